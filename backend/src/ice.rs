@@ -0,0 +1,99 @@
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use hmac::{Hmac, Mac};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use reqwest::Url;
+use serde::Serialize;
+use sha1::Sha1;
+
+use crate::EnvVars;
+
+/// An entry of `RTCConfiguration.iceServers`.
+#[derive(Serialize, Debug)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub credential: Option<String>,
+}
+
+/// Returns the STUN and TURN servers the user can connect through. The TURN
+/// servers are only listed when a shared secret is configured for them.
+pub fn ice_servers(env_vars: &EnvVars, user_id: ObjectId) -> Vec<IceServer> {
+    let mut servers = vec![];
+    let mut stun_urls = configured_urls(&env_vars.stun_urls);
+    if let Some(url) = embedded_stun_url(env_vars) {
+        stun_urls.insert(0, url);
+    }
+    let turn_urls = configured_urls(&env_vars.turn_urls);
+
+    if !stun_urls.is_empty() {
+        servers.push(IceServer { urls: stun_urls, username: None, credential: None });
+    }
+
+    let secret = env_vars.turn_secret.as_deref().filter(|secret| !secret.is_empty());
+    if let Some(secret) = secret.filter(|_| !turn_urls.is_empty()) {
+        let expires = DateTime::now().timestamp_millis() / 1000 + env_vars.turn_credential_ttl;
+        let (username, credential) = turn_credentials(secret, &user_id.to_hex(), expires);
+
+        servers.push(IceServer { urls: turn_urls, username: Some(username), credential: Some(credential) });
+    }
+
+    servers
+}
+
+/// Creates time-limited credentials of the TURN REST API, as checked by coturn
+/// with `use-auth-secret`: the username is the expiry time (in seconds) and the
+/// user id, the password is the HMAC-SHA1 of the username keyed with the secret.
+pub fn turn_credentials(secret: &str, user_id: &str, expires: i64) -> (String, String) {
+    let username = format!("{expires}:{user_id}");
+
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret.as_bytes()).unwrap();
+    mac.update(username.as_bytes());
+    let credential = BASE64.encode(mac.finalize().into_bytes());
+
+    (username, credential)
+}
+
+/// The url of the STUN server embedded in the backend, if it is running. It defaults
+/// to the host of the frontend, with the port the STUN server listens on.
+fn embedded_stun_url(env_vars: &EnvVars) -> Option<String> {
+    let address = env_vars.stun_server_address.as_deref().filter(|address| !address.is_empty())?;
+    if let Some(url) = env_vars.stun_server_url.as_deref().filter(|url| !url.is_empty()) {
+        return Some(url.to_string());
+    }
+
+    let (_, port) = address.rsplit_once(':')?;
+    let frontend_url = Url::parse(&env_vars.frontend_url).ok()?;
+
+    Some(format!("stun:{}:{port}", frontend_url.host_str()?))
+}
+
+// Empty variables in the .env file are read as a single empty url
+fn configured_urls(urls: &[String]) -> Vec<String> {
+    urls.iter()
+        .map(|url| url.trim().to_string())
+        .filter(|url| !url.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn credentials_match_the_turn_rest_api() {
+        // Computed the way coturn checks it: base64(HMAC-SHA1(secret, "expiry:user"))
+        let (username, credential) = turn_credentials("secret", "abc", 1700000000);
+
+        assert_eq!(username, "1700000000:abc");
+        assert_eq!(credential, "JHgegmW+/B84iarkT5SFAOC1Iqw=");
+    }
+
+    #[test]
+    fn empty_urls_are_skipped() {
+        let urls = vec![" stun:a.com:3478 ".to_string(), "".to_string()];
+
+        assert_eq!(configured_urls(&urls), vec!["stun:a.com:3478".to_string()]);
+    }
+}
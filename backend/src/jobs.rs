@@ -0,0 +1,114 @@
+use std::time::Duration;
+use actix_web::rt::time;
+use futures::TryStreamExt;
+use log::info;
+use mongodb::{Collection, bson::{doc, oid::ObjectId, DateTime}};
+
+use crate::{schemas::{Confirm, Reset, User}, sessions::SessionRegistry, utils::{self, MapAndLog}};
+
+const PURGE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Periodically removes the users who did not confirm their email within the
+/// grace period (in seconds) together with their confirmation tokens, so the
+/// email addresses can be registered again.
+pub async fn purge_unconfirmed_users(users_coll: Collection<User>, confirms_coll: Collection<Confirm>, grace_period: i64) {
+    let mut interval = time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - grace_period * 1000);
+        purge_users_before(&users_coll, &confirms_coll, cutoff).await.ok();
+    }
+}
+
+async fn purge_users_before(users_coll: &Collection<User>, confirms_coll: &Collection<Confirm>, cutoff: DateTime) -> Result<(), ()> {
+    let cutoff_id = utils::object_id_from_date(cutoff);
+
+    let filter = doc!{
+        "confirmed": false,
+        "_id": {"$lt": cutoff_id}
+    };
+    let ids: Vec<ObjectId> = users_coll.find(filter.clone(), None).await
+        .log_and_map(())?
+        .map_ok(|user| user._id)
+        .try_collect().await
+        .log_and_map(())?;
+
+    users_coll.delete_many(filter, None).await
+        .log_and_map(())?;
+
+    // Only the registrations are purged, the pending email changes of confirmed users are kept
+    let filter = doc!{
+        "$or": [
+            {"user": {"$in": &ids}},
+            {"_id": {"$lt": cutoff_id}, "email": null},
+        ]
+    };
+    confirms_coll.delete_many(filter, None).await
+        .log_and_map(())?;
+
+    if !ids.is_empty() {
+        info!("Purged {} unconfirmed users", ids.len());
+    }
+
+    Ok(())
+}
+
+/// Periodically removes the users who deactivated their account more than
+/// `retention_period` seconds ago, together with everything left of them.
+pub async fn purge_deleted_users(
+    users_coll: Collection<User>,
+    confirms_coll: Collection<Confirm>,
+    resets_coll: Collection<Reset>,
+    session_registry: SessionRegistry,
+    retention_period: i64,
+) {
+    let mut interval = time::interval(PURGE_INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let cutoff = DateTime::from_millis(DateTime::now().timestamp_millis() - retention_period * 1000);
+        purge_deleted_before(&users_coll, &confirms_coll, &resets_coll, &session_registry, cutoff).await.ok();
+    }
+}
+
+async fn purge_deleted_before(
+    users_coll: &Collection<User>,
+    confirms_coll: &Collection<Confirm>,
+    resets_coll: &Collection<Reset>,
+    session_registry: &SessionRegistry,
+    cutoff: DateTime,
+) -> Result<(), ()> {
+    let filter = doc!{
+        "deleted": true,
+        "deleted_at": {"$lt": cutoff}
+    };
+    let ids: Vec<ObjectId> = users_coll.find(filter.clone(), None).await
+        .log_and_map(())?
+        .map_ok(|user| user._id)
+        .try_collect().await
+        .log_and_map(())?;
+    if ids.is_empty() { return Ok(()) }
+
+    let filter = doc!{"user": {"$in": &ids}};
+    confirms_coll.delete_many(filter.clone(), None).await
+        .log_and_map(())?;
+    resets_coll.delete_many(filter, None).await
+        .log_and_map(())?;
+
+    for id in &ids {
+        session_registry.end_user_sessions(*id).await
+            .log_and_map(())?;
+    }
+
+    // The users go last, so a failed run is retried with the same users
+    let filter = doc!{"_id": {"$in": &ids}};
+    users_coll.delete_many(filter, None).await
+        .log_and_map(())?;
+
+    info!("Purged {} deleted users", ids.len());
+
+    Ok(())
+}
@@ -61,6 +61,106 @@ pub async fn send_confirmation(
     send_email(content, env_vars).await
 }
 
+#[cfg(debug_assertions)]
+pub async fn send_password_reset(
+    _username: &str,
+    _email: &str,
+    token: &str,
+    env_vars: &EnvVars,
+) -> Result<(), String> {
+    println!(
+        "RESET PASSWORD: \n {}/resetPassword?token={token}",
+        env_vars.frontend_url
+    );
+
+    Ok(())
+}
+
+#[cfg(not(debug_assertions))]
+pub async fn send_password_reset(
+    username: &str,
+    email: &str,
+    token: &str,
+    env_vars: &EnvVars,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut html = fs::read_to_string("emails/passwordReset.html")?;
+
+    html = html.replace(
+        "{{RESET_URL}}",
+        format!("{}/resetPassword?token={token}", env_vars.frontend_url).as_str(),
+    );
+    html = html.replace("{{USERNAME}}", &html_escape::encode_safe(username));
+
+    let content = json!({
+      "Messages":[
+        {
+          "From": {
+            "Name": "Speer",
+            "Email": &env_vars.noreply_email,
+          },
+          "To": [{
+            "Name": &username,
+            "Email": &email,
+          }],
+          "Subject": "Speer - Reset your password",
+          "HTMLPart": &html,
+        }
+      ]
+    });
+
+    send_email(content, env_vars).await
+}
+
+#[cfg(debug_assertions)]
+pub async fn send_email_change_confirmation(
+    _username: &str,
+    _email: &str,
+    token: &str,
+    env_vars: &EnvVars,
+) -> Result<(), String> {
+    println!(
+        "CONFIRM EMAIL CHANGE: \n {}/confirmEmail?token={token}",
+        env_vars.frontend_url
+    );
+
+    Ok(())
+}
+
+#[cfg(not(debug_assertions))]
+pub async fn send_email_change_confirmation(
+    username: &str,
+    email: &str,
+    token: &str,
+    env_vars: &EnvVars,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut html = fs::read_to_string("emails/emailChange.html")?;
+
+    html = html.replace(
+        "{{CONFIRM_URL}}",
+        format!("{}/confirmEmail?token={token}", env_vars.frontend_url).as_str(),
+    );
+    html = html.replace("{{USERNAME}}", &html_escape::encode_safe(username));
+
+    let content = json!({
+      "Messages":[
+        {
+          "From": {
+            "Name": "Speer",
+            "Email": &env_vars.noreply_email,
+          },
+          "To": [{
+            "Name": &username,
+            "Email": &email,
+          }],
+          "Subject": "Speer - Confirm your new email",
+          "HTMLPart": &html,
+        }
+      ]
+    });
+
+    send_email(content, env_vars).await
+}
+
 pub async fn send_feedback_notification(
     feedback: &Feedback,
     env_vars: &EnvVars,
@@ -1,23 +1,34 @@
 use cookie::time::Duration;
 use dotenv::dotenv;
-use actix::Actor;
+use actix::{Actor, AsyncContext};
 use actix_cors::Cors;
 use actix_web::{cookie::{Key, SameSite}, middleware::Logger, web::{self, Data}, App, HttpServer};
-use mongodb::{Client, options::ClientOptions};
+use mongodb::{bson::doc, Client, IndexModel, options::{ClientOptions, IndexOptions}};
 use serde::Deserialize;
 use serde_json::{Map, Value};
 use actix_identity::IdentityMiddleware;
-use actix_session::{storage::RedisSessionStore, SessionMiddleware, config::PersistentSession};
-use std::{env, fs};
+use actix_session::{SessionMiddleware, config::PersistentSession};
+use std::{env, fs, net::IpAddr, time};
+use sha2::{Digest, Sha256};
+use webauthn_rs::{WebauthnBuilder, fake::{FakePasskeyDistribution, WebauthnFakeCredentialGenerator}, prelude::Url};
 
 mod schemas;
 mod routes;
 mod utils;
 mod mail;
+mod jobs;
+mod rate_limit;
+mod sessions;
+mod totp;
+mod ice;
+mod stun;
 mod ws;
 
 const SECS_IN_DAY: i64 = 60 * 60 * 24;
 
+// Made up credential ids, offered for the emails that have no passkeys
+pub type FakePasskeys = WebauthnFakeCredentialGenerator<FakePasskeyDistribution>;
+
 pub struct CurrDir{
     path: String
 }
@@ -26,6 +37,7 @@ pub struct CurrDir{
 pub struct EnvVars {
     cookie_secret: String,
     confirm_secret: String,
+    totp_secret: String,
     mailjet_public: String,
     mailjet_secret: String,
     admin_email: String,
@@ -38,11 +50,39 @@ pub struct EnvVars {
     mongo_url: String,
     #[serde(default = "default_frontend_url")]
     frontend_url: String,
+    #[serde(default = "default_confirm_token_ttl")]
+    confirm_token_ttl: i64,
+    #[serde(default = "default_unconfirmed_grace_period")]
+    unconfirmed_grace_period: i64,
+    #[serde(default = "default_reset_token_ttl")]
+    reset_token_ttl: i64,
+    #[serde(default = "default_deleted_retention_period")]
+    deleted_retention_period: i64,
+    #[serde(default = "default_guest_link_ttl")]
+    guest_link_ttl: i64,
+    #[serde(default = "default_stun_urls")]
+    stun_urls: Vec<String>,
+    #[serde(default)]
+    turn_urls: Vec<String>,
+    // The static-auth-secret of coturn, TURN is not offered without it
+    turn_secret: Option<String>,
+    #[serde(default = "default_turn_credential_ttl")]
+    turn_credential_ttl: i64,
+    // The embedded STUN server is only started when its address is set
+    stun_server_address: Option<String>,
+    stun_server_url: Option<String>,
+    // The bytes each user can send through the file relay daily, the relay is off by default
+    #[serde(default)]
+    relay_quota: u64,
+    // The reverse proxies whose forwarding headers tell the address of the client
+    #[serde(default)]
+    trusted_proxies: Vec<IpAddr>,
+    webauthn_rp_id: Option<String>,
 }
 
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
-    if let Err(_) = dotenv() {
+    if dotenv().is_err() {
         println!("[Info] No '.env' file can be found in the current working directory, or it is formatted badly.\nYou can find information about the file in the documentation: https://github.com/horvbalint/speer#backendenv");
     }
 
@@ -50,13 +90,62 @@ async fn main() -> std::io::Result<()> {
 
     let env_vars = envy::prefixed("SPEER_").from_env::<EnvVars>().unwrap();
     let server_address = env_vars.server_address.clone();
+    let stun_server_address = env_vars.stun_server_address.clone().filter(|address| !address.is_empty());
 
     let client_options = ClientOptions::parse(&env_vars.mongo_url).await.unwrap();
     let client = Client::with_options(client_options).unwrap();
     let db = client.database("speer");
-    let ws_server = ws::Server::new(db.collection::<schemas::User>("users")).start();
 
-    let redis_store = RedisSessionStore::new(&env_vars.redis_url).await.unwrap();
+    // Reset tokens are removed by MongoDB once they expire
+    let expire_index = IndexModel::builder()
+        .keys(doc!{"expires": 1})
+        .options(IndexOptions::builder().expire_after(time::Duration::ZERO).build())
+        .build();
+    db.collection::<schemas::Reset>("resets").create_index(expire_index, None).await.unwrap();
+
+    // Two users registering or confirming the same address at once can not both succeed
+    let email_index = IndexModel::builder()
+        .keys(doc!{"email": 1})
+        .options(IndexOptions::builder().unique(true).build())
+        .build();
+    db.collection::<schemas::User>("users").create_index(email_index, None).await.unwrap();
+
+    tokio::spawn(jobs::purge_unconfirmed_users(
+        db.collection::<schemas::User>("users"),
+        db.collection::<schemas::Confirm>("confirms"),
+        env_vars.unconfirmed_grace_period,
+    ));
+
+    // The relying party id defaults to the domain the frontend is served from
+    let frontend_url = Url::parse(&env_vars.frontend_url).unwrap();
+    let rp_id = env_vars.webauthn_rp_id.clone()
+        .unwrap_or_else(|| frontend_url.host_str().unwrap().to_string());
+    let webauthn = Data::new(WebauthnBuilder::new(&rp_id, &frontend_url).unwrap().rp_name("Speer").build().unwrap());
+    // The key must stay the same, otherwise the made up ids would change between restarts
+    let fake_passkeys_key = Sha256::digest(format!("fake-passkeys:{}", env_vars.cookie_secret));
+    let fake_passkeys = Data::new(FakePasskeys::new(&fake_passkeys_key).unwrap());
+
+    let redis_client = redis::Client::open(env_vars.redis_url.as_str()).unwrap();
+    let redis_connection = redis::aio::ConnectionManager::new(redis_client).await.unwrap();
+    let session_registry = sessions::SessionRegistry::new(&env_vars.redis_url, redis_connection.clone()).await.unwrap();
+    let rate_limiter = rate_limit::RateLimiter::new(redis_connection.clone());
+
+    tokio::spawn(jobs::purge_deleted_users(
+        db.collection::<schemas::User>("users"),
+        db.collection::<schemas::Confirm>("confirms"),
+        db.collection::<schemas::Reset>("resets"),
+        session_registry.clone(),
+        env_vars.deleted_retention_period,
+    ));
+
+    let (cluster, cluster_messages) = ws::Cluster::new(&env_vars.redis_url, redis_connection).await.unwrap();
+    let users_coll = db.collection::<schemas::User>("users");
+    let ws_rate_limiter = rate_limiter.clone();
+    let relay_quota = env_vars.relay_quota;
+    let ws_server = ws::Server::create(move |ctx| {
+        ctx.add_stream(cluster_messages);
+        ws::Server::new(users_coll, cluster, ws_rate_limiter, relay_quota)
+    });
 
     let server = HttpServer::new(move || {
         let cors = Cors::default()
@@ -72,7 +161,7 @@ async fn main() -> std::io::Result<()> {
         let changelog = serde_json::from_str::<Map<String, Value>>(&json_string).unwrap();
 
         let session_middleware = SessionMiddleware::builder(
-          redis_store.clone(),
+          session_registry.clone(),
           Key::from(env_vars.cookie_secret.as_ref())
         )
             .cookie_same_site(SameSite::Strict)
@@ -87,6 +176,11 @@ async fn main() -> std::io::Result<()> {
             .app_data(Data::new(db.collection::<schemas::MinimalUser>("users")))
             .app_data(Data::new(db.collection::<schemas::User>("users")))
             .app_data(Data::new(db.collection::<schemas::Confirm>("confirms")))
+            .app_data(Data::new(db.collection::<schemas::Reset>("resets")))
+            .app_data(Data::new(session_registry.clone()))
+            .app_data(Data::new(rate_limiter.clone()))
+            .app_data(webauthn.clone())
+            .app_data(fake_passkeys.clone())
             .app_data(Data::new(curr_dir))
             .app_data(Data::new(ws_server.clone()))
             .app_data(Data::new(changelog))
@@ -95,22 +189,51 @@ async fn main() -> std::io::Result<()> {
             .wrap(Logger::default())
             .wrap(cors)
             .route("/ws/", web::get().to(ws::ws_route))
+            .route("/ws/schema", web::get().to(ws::schema_route))
+            .route("/ws/guest/{token}", web::get().to(ws::guest_ws_route))
+            .route("/guest/{token}/iceServers", web::get().to(ws::guest_ice_servers_route))
             .service(routes::register_handler)
             .service(routes::login_handler)
+            .service(routes::login_totp_handler)
             .service(routes::logout_handler)
+            .service(routes::sessions_handler)
+            .service(routes::remove_session_handler)
             .service(routes::confirm_handler)
             .service(routes::cancel_handler)
             .service(routes::resend_confirmation_handler)
+            .service(routes::forgot_password_handler)
+            .service(routes::reset_password_handler)
             .service(routes::avatar_handler)
+            .service(routes::change_password_handler)
+            .service(routes::hide_last_seen_handler)
+            .service(routes::guest_link_handler)
+            .service(routes::ice_servers_handler)
+            .service(routes::change_email_handler)
+            .service(routes::confirm_email_handler)
+            .service(routes::enroll_totp_handler)
+            .service(routes::verify_totp_handler)
+            .service(routes::disable_totp_handler)
+            .service(routes::start_passkey_registration_handler)
+            .service(routes::finish_passkey_registration_handler)
+            .service(routes::start_passkey_login_handler)
+            .service(routes::finish_passkey_login_handler)
+            .service(routes::passkeys_handler)
+            .service(routes::remove_passkey_handler)
             .service(routes::user_by_email_handler)
             .service(routes::me_handler)
+            .service(routes::delete_me_handler)
             .service(routes::onlines_handler)
             .service(routes::online_handler)
             .service(routes::friends_handler)
+            .service(routes::unfriend_id_handler)
             .service(routes::request_id_handler)
             .service(routes::request_handler)
+            .service(routes::outgoing_request_handler)
+            .service(routes::cancel_request_id_handler)
             .service(routes::accept_id_handler)
             .service(routes::decline_id_handler)
+            .service(routes::block_id_handler)
+            .service(routes::unblock_id_handler)
             .service(routes::add_device_handler)
             .service(routes::remove_device_handler)
             .service(routes::test_devices_handler)
@@ -126,6 +249,12 @@ async fn main() -> std::io::Result<()> {
     println!("The dark side of the 🌑 is ready!");
     println!("Listening at address: {}", server_address);
 
+    if let Some(stun_address) = stun_server_address {
+        let socket = actix_web::rt::net::UdpSocket::bind(&stun_address).await?;
+        println!("STUN server listening at address: {}", stun_address);
+        actix_web::rt::spawn(stun::run(socket));
+    }
+
     server.bind(server_address)?.run().await
 }
 
@@ -144,3 +273,31 @@ fn default_mongo_url() -> String {
 fn default_frontend_url() -> String {
     "http://localhost:9000".to_string()
 }
+
+fn default_confirm_token_ttl() -> i64 {
+    SECS_IN_DAY * 2
+}
+
+fn default_unconfirmed_grace_period() -> i64 {
+    SECS_IN_DAY * 7
+}
+
+fn default_reset_token_ttl() -> i64 {
+    60 * 60
+}
+
+fn default_deleted_retention_period() -> i64 {
+    SECS_IN_DAY * 30
+}
+
+fn default_guest_link_ttl() -> i64 {
+    SECS_IN_DAY
+}
+
+fn default_stun_urls() -> Vec<String> {
+    vec!["stun:stun.l.google.com:19302".to_string()]
+}
+
+fn default_turn_credential_ttl() -> i64 {
+    SECS_IN_DAY
+}
\ No newline at end of file
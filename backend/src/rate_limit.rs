@@ -0,0 +1,219 @@
+use std::rc::Rc;
+use actix_web::{
+    body::EitherBody,
+    dev::{forward_ready, Service, ServiceRequest, ServiceResponse, Transform},
+    error::InternalError,
+    http::header::RETRY_AFTER,
+    web::Data,
+    Error, HttpRequest, HttpResponse,
+};
+use futures::future::{ready, LocalBoxFuture, Ready};
+use redis::{aio::ConnectionManager, RedisError};
+
+use crate::{utils::MapAndLog, EnvVars};
+
+/// Fixed window counters stored in Redis. If Redis can not be reached, the
+/// requests are let through instead of locking everyone out. The budgets taken
+/// with `consume` are the exception, as they guard resources of the server.
+#[derive(Clone)]
+pub struct RateLimiter {
+    // Not set in the tests, which run without Redis
+    client: Option<ConnectionManager>,
+    #[cfg(test)]
+    unlimited: bool,
+}
+
+impl RateLimiter {
+    pub fn new(client: ConnectionManager) -> RateLimiter {
+        RateLimiter {
+            client: Some(client),
+            #[cfg(test)]
+            unlimited: false,
+        }
+    }
+
+    /// A limiter which acts as if Redis could not be reached.
+    #[cfg(test)]
+    pub fn detached() -> RateLimiter {
+        RateLimiter { client: None, unlimited: false }
+    }
+
+    /// A limiter without Redis, whose budgets are never used up.
+    #[cfg(test)]
+    pub fn unlimited() -> RateLimiter {
+        RateLimiter { client: None, unlimited: true }
+    }
+
+    /// Counts a hit in the bucket. Returns the seconds to wait, if the limit is exceeded.
+    pub async fn hit(&self, bucket: &str, limit: u64, window_secs: i64) -> Option<i64> {
+        let key = bucket_key(bucket);
+
+        let (count, ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .cmd("SET").arg(&key).arg(0).arg("EX").arg(window_secs).arg("NX").ignore()
+            .incr(&key, 1)
+            .ttl(&key)
+            .query_async(&mut self.connection().log_and_map(()).ok()?).await
+            .log_and_map(())
+            .ok()?;
+
+        (count > limit).then_some(ttl.max(1))
+    }
+
+    /// Takes the amount from the budget of the bucket. Returns the seconds to wait, if the
+    /// budget is not enough, in which case nothing is taken. The errors of Redis are
+    /// returned, so the caller can deny the request.
+    pub async fn consume(&self, bucket: &str, amount: u64, limit: u64, window_secs: i64) -> Result<Option<i64>, RedisError> {
+        // More than the whole budget is never allowed, and it might not even fit in a Redis integer
+        if amount > limit { return Ok(Some(window_secs)) }
+        #[cfg(test)]
+        if self.unlimited { return Ok(None) }
+
+        let key = bucket_key(bucket);
+
+        let (count, ttl): (u64, i64) = redis::pipe()
+            .atomic()
+            .cmd("SET").arg(&key).arg(0).arg("EX").arg(window_secs).arg("NX").ignore()
+            .incr(&key, amount)
+            .ttl(&key)
+            .query_async(&mut self.connection()?).await?;
+
+        if count <= limit { return Ok(None) }
+
+        redis::cmd("DECRBY").arg(&key).arg(amount)
+            .query_async::<_, ()>(&mut self.connection()?).await
+            .log_and_map(())
+            .ok();
+
+        Ok(Some(ttl.max(1)))
+    }
+
+    /// Returns the seconds to wait, if the bucket is over the limit, without counting a hit.
+    pub async fn check(&self, bucket: &str, limit: u64) -> Option<i64> {
+        let key = bucket_key(bucket);
+
+        let (count, ttl): (Option<u64>, i64) = redis::pipe()
+            .get(&key)
+            .ttl(&key)
+            .query_async(&mut self.connection().log_and_map(()).ok()?).await
+            .log_and_map(())
+            .ok()?;
+
+        (count.unwrap_or_default() >= limit).then_some(ttl.max(1))
+    }
+
+    pub async fn reset(&self, bucket: &str) {
+        let Ok(mut client) = self.connection().log_and_map(()) else { return };
+
+        redis::cmd("DEL").arg(bucket_key(bucket))
+            .query_async::<_, ()>(&mut client).await
+            .log_and_map(())
+            .ok();
+    }
+
+    fn connection(&self) -> Result<ConnectionManager, RedisError> {
+        self.client.clone().ok_or_else(|| RedisError::from((redis::ErrorKind::IoError, "Not connected to Redis")))
+    }
+}
+
+fn bucket_key(bucket: &str) -> String {
+    format!("speer:rate:{bucket}")
+}
+
+/// The IP address of the client. The forwarding headers can be set by anyone, so they
+/// are only used when the request comes from one of the trusted proxies.
+pub fn client_ip(request: &HttpRequest) -> Option<String> {
+    let peer = request.peer_addr()?.ip();
+    let trusted = request.app_data::<Data<EnvVars>>()
+        .is_some_and(|env_vars| env_vars.trusted_proxies.contains(&peer));
+
+    if trusted {
+        request.connection_info().realip_remote_addr().map(|ip| ip.to_string())
+    }
+    else {
+        Some(peer.to_string())
+    }
+}
+
+pub fn too_many_requests(retry_after: i64) -> Error {
+    let response = HttpResponse::TooManyRequests()
+        .insert_header((RETRY_AFTER, retry_after.to_string()))
+        .body("Too many requests");
+
+    InternalError::from_response("Too many requests", response).into()
+}
+
+/// Middleware limiting the number of requests a single IP address can make to
+/// the wrapped route within the given window.
+pub struct RateLimit {
+    scope: &'static str,
+    limit: u64,
+    window_secs: i64,
+}
+
+impl RateLimit {
+    pub fn per_ip(scope: &'static str, limit: u64, window_secs: i64) -> RateLimit {
+        RateLimit { scope, limit, window_secs }
+    }
+}
+
+impl<S, B> Transform<S, ServiceRequest> for RateLimit
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Transform = RateLimitMiddleware<S>;
+    type InitError = ();
+    type Future = Ready<Result<Self::Transform, Self::InitError>>;
+
+    fn new_transform(&self, service: S) -> Self::Future {
+        ready(Ok(RateLimitMiddleware {
+            service: Rc::new(service),
+            scope: self.scope,
+            limit: self.limit,
+            window_secs: self.window_secs,
+        }))
+    }
+}
+
+pub struct RateLimitMiddleware<S> {
+    service: Rc<S>,
+    scope: &'static str,
+    limit: u64,
+    window_secs: i64,
+}
+
+impl<S, B> Service<ServiceRequest> for RateLimitMiddleware<S>
+where
+    S: Service<ServiceRequest, Response = ServiceResponse<B>, Error = Error> + 'static,
+    B: 'static,
+{
+    type Response = ServiceResponse<EitherBody<B>>;
+    type Error = Error;
+    type Future = LocalBoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+    forward_ready!(service);
+
+    fn call(&self, req: ServiceRequest) -> Self::Future {
+        let service = self.service.clone();
+        let (scope, limit, window_secs) = (self.scope, self.limit, self.window_secs);
+
+        Box::pin(async move {
+            let rate_limiter = req.app_data::<Data<RateLimiter>>().cloned();
+            let ip = client_ip(req.request()).unwrap_or_else(|| "unknown".to_string());
+
+            if let Some(rate_limiter) = rate_limiter {
+                let bucket = format!("{scope}:ip:{ip}");
+
+                if let Some(retry_after) = rate_limiter.hit(&bucket, limit, window_secs).await {
+                    let response = too_many_requests(retry_after).error_response();
+                    return Ok(req.into_response(response).map_into_right_body());
+                }
+            }
+
+            service.call(req).await.map(ServiceResponse::map_into_left_body)
+        })
+    }
+}
@@ -1,8 +1,9 @@
 use actix::Addr;
 use actix_identity::Identity;
+use actix_session::Session;
 use actix_web::{Responder, error::*, get, post, web::{Path, Json, Data}, HttpRequest, delete, HttpMessage};
 use futures::TryStreamExt;
-use mongodb::{Collection, Database, bson::{doc, oid::ObjectId}};
+use mongodb::{Collection, Database, bson::{doc, oid::ObjectId, DateTime, Document}};
 use serde::Deserialize;
 use serde_json::{Map as SerdeMap, Value as SerdeValue};
 use jsonwebtoken::{encode, Header, EncodingKey};
@@ -11,11 +12,14 @@ use std::path::PathBuf;
 use actix_files::NamedFile;
 use image::imageops::FilterType;
 use unicode_segmentation::UnicodeSegmentation;
+use webauthn_rs::{Webauthn, prelude::{PasskeyAuthentication, PasskeyRegistration, PublicKeyCredential, RegisterPublicKeyCredential, RequestChallengeResponse, Uuid}};
 
-use crate::{schemas::{Device, Feedback}, utils::MapAndLog, ws::{Server, ConnectedIds, Dispatch}, CurrDir, EnvVars};
-use crate::schemas::{User, MinimalUser, MeUser};
+use crate::{schemas::{Device, Feedback}, rate_limit::{self, RateLimit, RateLimiter, too_many_requests}, sessions::{self, SessionRegistry}, utils::MapAndLog, ws::{Server, ConnectedIds, ConnectedSessions, Dispatch, FriendsChanged, Kick, Unfriend}, CurrDir, EnvVars, FakePasskeys};
+use crate::schemas::{User, MinimalUser, MeUser, Status, UserPresence};
 use crate::mail;
-use crate::schemas::Confirm;
+use crate::totp;
+use crate::ice;
+use crate::schemas::{Confirm, ConfirmClaims, ConfirmTokenError, GuestClaims, GUEST_AUDIENCE, MinimalPasskey, PasskeyCredential, Reset, Totp};
 use crate::utils;
 
 extern crate bcrypt;
@@ -23,6 +27,27 @@ use bcrypt::{verify, hash};
 
 extern crate image;
 
+const TOTP_USER_KEY: &str = "totp_user";
+const TOTP_STARTED_KEY: &str = "totp_started";
+const TOTP_LOGIN_TIMEOUT_MILLIS: i64 = 5 * 60 * 1000;
+const TOTP_MAX_ATTEMPTS: u64 = 5;
+// Accounts are locked for the window after this many failed login attempts from anywhere,
+// addresses after this many failed attempts on any accounts
+const LOGIN_FAILURE_LIMIT: u64 = 5;
+const LOGIN_IP_FAILURE_LIMIT: u64 = 20;
+const LOGIN_LOCKOUT_SECS: i64 = 15 * 60;
+// Unknown emails are checked against this, so they take as long as a wrong password
+const UNKNOWN_ACCOUNT_HASH: &str = "$2b$10$wML3orqzq50O87sRgnX2/O.KqwebHxiOEnEvpIMiCR9o0daKRw8kO";
+const EMAIL_LIMIT: u64 = 3;
+const EMAIL_WINDOW_SECS: i64 = 60 * 60;
+const PING_LIMIT: u64 = 3;
+const PING_WINDOW_SECS: i64 = 5 * 60;
+const PASSKEY_REGISTRATION_KEY: &str = "passkey_registration";
+const PASSKEY_AUTHENTICATION_KEY: &str = "passkey_authentication";
+// The second half of an unfriending is retried this many times, before the first one is undone
+const UNFRIEND_ATTEMPTS: u32 = 3;
+const UNFRIEND_RETRY_MILLIS: u64 = 100;
+
 #[derive(Deserialize)]
 pub struct LoginBody {
     email: String,
@@ -36,13 +61,61 @@ pub struct RegisterBody {
     password: String
 }
 
+#[derive(Deserialize)]
+pub struct TotpBody {
+    code: String
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyRegistrationBody {
+    name: String
+}
+
+#[derive(Deserialize)]
+pub struct PasskeyLoginBody {
+    email: String
+}
+
+#[derive(Deserialize)]
+pub struct PasswordBody {
+    password: String
+}
+
+#[derive(Deserialize)]
+pub struct ResetPasswordBody {
+    password: String
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ChangePasswordBody {
+    current_password: String,
+    new_password: String,
+}
+
+#[derive(Deserialize)]
+pub struct ChangeEmailBody {
+    email: String,
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct DeleteMeBody {
+    password: String,
+}
+
+#[derive(Deserialize)]
+pub struct HideLastSeenBody {
+    hide: bool,
+}
+
 #[derive(Deserialize)]
 pub struct PingBody {
     id: ObjectId,
     message: String,
 }
 
-#[post("/register")]
+#[post("/register", wrap = "RateLimit::per_ip(\"register\", 5, 60 * 60)")]
 pub async fn register_handler(
     body: Json<RegisterBody>,
     users_coll: Data<Collection<User>>,
@@ -64,17 +137,19 @@ pub async fn register_handler(
         password: password.to_string(),
         ..Default::default()
     };
-    let insert_result = users_coll.insert_one(&user, None).await
-        .log_and_map(ErrorInternalServerError("Failed to create user"))?;
+    let insert_result = match users_coll.insert_one(&user, None).await {
+        Err(err) if utils::is_duplicate_key(&err) => return Err(ErrorBadRequest("Email in use")),
+        result => result.log_and_map(ErrorInternalServerError("Failed to create user"))?,
+    };
 
-    let inserted_id = insert_result.inserted_id.as_object_id().unwrap().to_string();
-    let token = encode(&Header::default(), &inserted_id, &EncodingKey::from_secret(env_vars.confirm_secret.as_ref()))
-        .log_and_map(ErrorInternalServerError(""))?;
+    let inserted_id = insert_result.inserted_id.as_object_id().unwrap();
+    let token = create_confirm_token(inserted_id, &env_vars)?;
 
     let confirm = Confirm {
         _id: ObjectId::new(),
         user: user._id,
         token: token.clone(),
+        email: None,
     };
     confirms_coll.insert_one(confirm, None).await
         .log_and_map(ErrorInternalServerError("Failed to create user"))?;
@@ -85,27 +160,210 @@ pub async fn register_handler(
     Ok("")
 }
 
-#[post("/login")]
+#[post("/login", wrap = "RateLimit::per_ip(\"login\", 20, 60)")]
 pub async fn login_handler(
     request: HttpRequest,
     credentials: Json<LoginBody>,
+    session: Session,
     users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
 ) -> Result<impl Responder, Error> {
+    let failures = LoginFailures::new(&request, &credentials.email);
+    failures.check(&rate_limiter).await?;
+
     let filter = doc!{"email": &credentials.email};
 
     let user = users_coll.find_one(filter, None).await
-        .log_and_map(ErrorBadRequest("Incorrect credentials"))?
-        .ok_or_else(|| ErrorBadRequest("Incorrect credentials"))?;
+        .log_and_map(ErrorInternalServerError(""))?;
+    let hash = user.as_ref().map_or(UNKNOWN_ACCOUNT_HASH, |user| user.password.as_str());
 
-    let verified = verify(&credentials.password, user.password.as_str())
+    // Unknown emails and wrong passwords can not be told apart
+    let verified = verify(&credentials.password, hash)
         .log_and_map(ErrorInternalServerError("verification failed"))?;
+    let Some(user) = user.filter(|_| verified) else {
+        failures.hit(&rate_limiter).await;
+        return Err(ErrorUnauthorized("Incorrect credentials"));
+    };
+    failures.reset(&rate_limiter).await;
 
-    if !verified { return Err(ErrorUnauthorized("Password does not match")) }
     if user.deleted { return Err(ErrorUnauthorized("User deactivated")) }
     if !user.confirmed { return Err(ErrorUnauthorized("Email not confirmed")) }
 
+    // The user is only logged in after the second step at '/login/totp'
+    if user.totp_enabled() {
+        session.insert(TOTP_USER_KEY, user._id.to_hex())
+            .log_and_map(ErrorInternalServerError(""))?;
+        session.insert(TOTP_STARTED_KEY, DateTime::now().timestamp_millis())
+            .log_and_map(ErrorInternalServerError(""))?;
+
+        return Err(ErrorUnauthorized("TOTP required"));
+    }
+
+    Identity::login(&request.extensions(), user._id.to_hex())
+      .log_and_map(ErrorInternalServerError("identity failed"))?;
+    sessions::record_login(&request, &session)
+      .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok("")
+}
+
+#[post("/login/totp", wrap = "RateLimit::per_ip(\"login\", 20, 60)")]
+pub async fn login_totp_handler(
+    request: HttpRequest,
+    body: Json<TotpBody>,
+    session: Session,
+    users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
+    env_vars: Data<EnvVars>,
+) -> Result<impl Responder, Error> {
+    let user_id = session.get::<String>(TOTP_USER_KEY)
+        .log_and_map(ErrorInternalServerError(""))?
+        .and_then(|id| ObjectId::parse_str(id).ok())
+        .ok_or_else(|| ErrorUnauthorized("Log in first"))?;
+    let started = session.get::<i64>(TOTP_STARTED_KEY)
+        .log_and_map(ErrorInternalServerError(""))?
+        .unwrap_or_default();
+
+    if DateTime::now().timestamp_millis() - started > TOTP_LOGIN_TIMEOUT_MILLIS {
+        clear_totp_login(&session);
+        return Err(ErrorUnauthorized("Log in first"));
+    }
+
+    // Counted for the user rather than the session, logging in again does not give more tries
+    let attempts_bucket = format!("totp:user:{}", user_id.to_hex());
+    if let Some(retry_after) = rate_limiter.hit(&attempts_bucket, TOTP_MAX_ATTEMPTS, TOTP_LOGIN_TIMEOUT_MILLIS / 1000).await {
+        clear_totp_login(&session);
+        return Err(too_many_requests(retry_after));
+    }
+
+    let filter = doc!{"_id": user_id, "deleted": false};
+    let user = users_coll.find_one(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .ok_or_else(|| ErrorUnauthorized("Log in first"))?;
+    let user_totp = user.totp
+        .filter(|totp| totp.enabled)
+        .ok_or_else(|| ErrorUnauthorized("Log in first"))?;
+
+    // Wrong codes count as failed logins too
+    let failures = LoginFailures::new(&request, &user.email);
+    failures.check(&rate_limiter).await?;
+
+    let secret = totp::decrypt_secret(&user_totp.secret, &env_vars.totp_secret)
+        .ok_or_else(|| ErrorInternalServerError(""))?;
+
+    // The filters make sure a code is only accepted once, even if it is sent twice at the same time
+    let (filter, update) = if let Some(step) = totp::verify_code(&secret, &body.code, user_totp.last_step) {
+        (doc!{"_id": user._id, "totp.last_step": {"$lt": step}}, doc!{"$set": {"totp.last_step": step}})
+    } else {
+        let code_hash = totp::hash_recovery_code(&body.code);
+        (doc!{"_id": user._id, "totp.recovery_codes": &code_hash}, doc!{"$pull": {"totp.recovery_codes": &code_hash}})
+    };
+    let result = users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    if result.modified_count != 1 {
+        failures.hit(&rate_limiter).await;
+        return Err(ErrorUnauthorized("Invalid code"));
+    }
+
+    rate_limiter.reset(&attempts_bucket).await;
+    clear_totp_login(&session);
+    Identity::login(&request.extensions(), user._id.to_hex())
+      .log_and_map(ErrorInternalServerError("identity failed"))?;
+    sessions::record_login(&request, &session)
+      .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok("")
+}
+
+#[post("/passkey/login/start", wrap = "RateLimit::per_ip(\"login\", 20, 60)")]
+pub async fn start_passkey_login_handler(
+    request: HttpRequest,
+    body: Json<PasskeyLoginBody>,
+    session: Session,
+    users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
+    webauthn: Data<Webauthn>,
+    fake_passkeys: Data<FakePasskeys>,
+) -> Result<impl Responder, Error> {
+    LoginFailures::new(&request, &body.email).check(&rate_limiter).await?;
+
+    let filter = doc!{
+        "email": &body.email,
+        "confirmed": true,
+        "deleted": false
+    };
+    let user = users_coll.find_one(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .filter(|user| !user.passkeys.is_empty());
+
+    // Emails without passkeys get a challenge too, so it can not be told whether an account exists
+    let Some(user) = user else {
+        let (challenge, state) = decoy_passkey_challenge(&webauthn, &fake_passkeys, &body.email)
+            .log_and_map(ErrorInternalServerError(""))?;
+
+        session.insert(PASSKEY_AUTHENTICATION_KEY, (&body.email, None::<String>, state))
+            .log_and_map(ErrorInternalServerError(""))?;
+
+        return Ok(Json(challenge));
+    };
+
+    let passkeys: Vec<_> = user.passkeys.into_iter().map(|credential| credential.passkey).collect();
+    let (challenge, state) = webauthn.start_passkey_authentication(&passkeys)
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    session.insert(PASSKEY_AUTHENTICATION_KEY, (&body.email, Some(user._id.to_hex()), state))
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok(Json(challenge))
+}
+
+#[post("/passkey/login/finish", wrap = "RateLimit::per_ip(\"login\", 20, 60)")]
+pub async fn finish_passkey_login_handler(
+    request: HttpRequest,
+    credential: Json<PublicKeyCredential>,
+    session: Session,
+    users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
+    webauthn: Data<Webauthn>,
+) -> Result<impl Responder, Error> {
+    let (email, user_id, state) = session.remove_as::<(String, Option<String>, PasskeyAuthentication)>(PASSKEY_AUTHENTICATION_KEY)
+        .and_then(Result::ok)
+        .ok_or_else(|| ErrorBadRequest("No login in progress"))?;
+
+    let failures = LoginFailures::new(&request, &email);
+    failures.check(&rate_limiter).await?;
+
+    // Decoy challenges have no user and never succeed
+    let user_id = user_id.and_then(|user_id| ObjectId::parse_str(user_id).ok());
+    let result = webauthn.finish_passkey_authentication(&credential, &state).ok();
+    let Some((user_id, result)) = user_id.zip(result) else {
+        failures.hit(&rate_limiter).await;
+        return Err(ErrorUnauthorized("Incorrect credentials"));
+    };
+
+    let filter = doc!{"_id": user_id, "confirmed": true, "deleted": false};
+    let user = users_coll.find_one(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .ok_or_else(|| ErrorUnauthorized("Incorrect credentials"))?;
+
+    let mut used = user.passkeys.into_iter()
+        .find(|credential| credential.passkey.cred_id() == result.cred_id())
+        .ok_or_else(|| ErrorUnauthorized("Incorrect credentials"))?;
+
+    // Storing the new signature counter, so cloned authenticators can be detected
+    if used.passkey.update_credential(&result) == Some(true) {
+        let filter = doc!{"_id": user._id, "passkeys._id": used._id};
+        let update = doc!{"$set": {"passkeys.$": used}};
+        users_coll.update_one(filter, update, None).await
+            .log_and_map(ErrorInternalServerError(""))?;
+    }
+    failures.reset(&rate_limiter).await;
+
     Identity::login(&request.extensions(), user._id.to_hex())
       .log_and_map(ErrorInternalServerError("identity failed"))?;
+    sessions::record_login(&request, &session)
+      .log_and_map(ErrorInternalServerError(""))?;
 
     Ok("")
 }
@@ -117,15 +375,60 @@ pub async fn logout_handler(identity: Identity) -> Result<impl Responder, Error>
   Ok("")
 }
 
+#[get("/sessions")]
+pub async fn sessions_handler(
+    session: Session,
+    session_registry: Data<SessionRegistry>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let mut user_sessions = session_registry.user_sessions(user._id).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let current = session.get::<String>(sessions::SESSION_ID_KEY).ok().flatten();
+    let connected = ws_addr.send(ConnectedSessions { _id: user._id }).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    for user_session in &mut user_sessions {
+        user_session.current = current.as_ref() == Some(&user_session.id);
+        user_session.connected = connected.contains(&user_session.id);
+    }
+
+    Ok(Json(user_sessions))
+}
+
+#[delete("/sessions/{id}")]
+pub async fn remove_session_handler(
+    params: Path<String>,
+    session_registry: Data<SessionRegistry>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let session_id = params.into_inner();
+
+    let ended = session_registry.end_session(user._id, &session_id).await
+        .log_and_map(ErrorInternalServerError(""))?;
+    if !ended {
+        return Err(ErrorBadRequest("No such session"));
+    }
+
+    ws_addr.do_send(Kick { _id: user._id, session_id: Some(session_id) });
+
+    Ok("")
+}
+
 #[post("/confirm/{token}")]
 pub async fn confirm_handler(
     params: Path<String>,
     confirms_coll: Data<Collection<Confirm>>,
     users_coll: Data<Collection<User>>,
+    env_vars: Data<EnvVars>,
 ) -> Result<impl Responder, Error> {
     let token = params.into_inner();
 
-    let filter = doc!{"token": token};
+    verify_confirm_token(&token, &env_vars)?;
+
+    let filter = doc!{"token": token, "email": null};
     let confirm = confirms_coll.find_one(filter, None).await
         .log_and_map(ErrorInternalServerError("Invalid token"))?
         .ok_or_else(|| ErrorInternalServerError("Invalid token"))?;
@@ -169,14 +472,21 @@ pub async fn cancel_handler(
     Ok("ok")
 }
 
-#[post("/resendConfirmation/{email}")]
+#[post("/resendConfirmation/{email}", wrap = "RateLimit::per_ip(\"email\", 10, 60 * 60)")]
 pub async fn resend_confirmation_handler(
     params: Path<String>,
     confirms_coll: Data<Collection<Confirm>>,
     users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
     env_vars: Data<EnvVars>
 ) -> Result<impl Responder, Error> {
     let email = params.into_inner();
+
+    let bucket = format!("confirmation:account:{}", email.to_lowercase());
+    if let Some(retry_after) = rate_limiter.hit(&bucket, EMAIL_LIMIT, EMAIL_WINDOW_SECS).await {
+        return Err(too_many_requests(retry_after));
+    }
+
     let filter = doc!{
         "email": email,
         "confirmed": false,
@@ -187,17 +497,108 @@ pub async fn resend_confirmation_handler(
         .log_and_map(ErrorInternalServerError(""))?
         .ok_or_else(|| ErrorBadRequest("Invalid email"))?;
 
+    // The previous token might have expired already, so a new one is sent
+    let token = create_confirm_token(user._id, &env_vars)?;
+
     let filter = doc!{"user": user._id};
-    let confirm = confirms_coll.find_one(filter, None).await
+    let update = doc!{"$set": {"token": &token}};
+    confirms_coll.find_one_and_update(filter, update, None).await
         .log_and_map(ErrorInternalServerError(""))?
         .ok_or_else(|| ErrorBadRequest("Failed to resend email"))?;
 
-    mail::send_confirmation(&user.username, &user.email, &confirm.token, &env_vars).await
+    mail::send_confirmation(&user.username, &user.email, &token, &env_vars).await
         .log_and_map(ErrorInternalServerError("Failed to send confirmation email"))?;
 
     Ok("ok")
 }
 
+#[post("/forgotPassword/{email}", wrap = "RateLimit::per_ip(\"email\", 10, 60 * 60)")]
+pub async fn forgot_password_handler(
+    params: Path<String>,
+    resets_coll: Data<Collection<Reset>>,
+    users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
+    env_vars: Data<EnvVars>
+) -> Result<impl Responder, Error> {
+    let email = params.into_inner();
+
+    let bucket = format!("reset:account:{}", email.to_lowercase());
+    if let Some(retry_after) = rate_limiter.hit(&bucket, EMAIL_LIMIT, EMAIL_WINDOW_SECS).await {
+        return Err(too_many_requests(retry_after));
+    }
+
+    let filter = doc!{
+        "email": email,
+        "confirmed": true,
+        "deleted": false
+    };
+
+    // Not revealing whether the email is registered or not
+    let user = match users_coll.find_one(filter, None).await.log_and_map(ErrorInternalServerError(""))? {
+        Some(user) => user,
+        None => return Ok(""),
+    };
+
+    let filter = doc!{"user": user._id};
+    resets_coll.delete_many(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let token = utils::generate_random_string(64);
+    let expires = DateTime::now().timestamp_millis() + env_vars.reset_token_ttl * 1000;
+    let reset = Reset {
+        _id: ObjectId::new(),
+        user: user._id,
+        token_hash: utils::hash_token(&token),
+        expires: DateTime::from_millis(expires),
+    };
+    resets_coll.insert_one(&reset, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    mail::send_password_reset(&user.username, &user.email, &token, &env_vars).await
+        .log_and_map(ErrorInternalServerError("Failed to send password reset email"))?;
+
+    Ok("")
+}
+
+#[post("/resetPassword/{token}")]
+pub async fn reset_password_handler(
+    params: Path<String>,
+    body: Json<ResetPasswordBody>,
+    resets_coll: Data<Collection<Reset>>,
+    users_coll: Data<Collection<User>>,
+    session_registry: Data<SessionRegistry>,
+    ws_addr: Data<Addr<Server>>,
+) -> Result<impl Responder, Error> {
+    if body.password.is_empty() {
+        return Err(ErrorBadRequest("Empty password"));
+    }
+
+    let token = params.into_inner();
+
+    // Deleting the token right away, so it can not be used twice
+    let filter = doc!{
+        "token_hash": utils::hash_token(&token),
+        "expires": {"$gt": DateTime::now()}
+    };
+    let reset = resets_coll.find_one_and_delete(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .ok_or_else(|| ErrorBadRequest("Invalid token"))?;
+
+    let password = hash(&body.password, 10)
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc!{"_id": reset.user, "deleted": false};
+    let update = doc!{"$set": {"password": password}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    session_registry.end_user_sessions(reset.user).await
+        .log_and_map(ErrorInternalServerError(""))?;
+    ws_addr.do_send(Kick { _id: reset.user, session_id: None });
+
+    Ok("")
+}
+
 #[post("/avatar")]
 pub async fn avatar_handler(
     mut parts: awmp::Parts,
@@ -220,6 +621,7 @@ pub async fn avatar_handler(
         .log_and_map(ErrorInternalServerError("Failed to save image"))?;
 
     let tmp_path = save_res.to_str().unwrap();
+    #[allow(clippy::needless_borrows_for_generic_args)]
     image::open(&tmp_path)
         .log_and_map(ErrorInternalServerError("Failed to compress image"))?
         .resize_to_fill(200, 200, FilterType::Triangle)
@@ -227,17 +629,345 @@ pub async fn avatar_handler(
         .log_and_map(ErrorInternalServerError("Failed to compress image"))?;
 
     let filter = doc!{"_id": user._id};
-    let update = doc!{"$set": {"avatar": &full_file_name}};
+    let update = doc!{"$set": {"avatar": &full_file_name}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError("Could not update user profile"))?;
+
+    fs::remove_file(tmp_path).ok();
+    if user.avatar != "avatar.jpg" {
+        let path = PathBuf::from(format!("{}/{}", files_path, user.avatar));
+        fs::remove_file(path).ok();
+    }
+
+    Ok(full_file_name)
+}
+
+#[post("/changePassword")]
+pub async fn change_password_handler(
+    body: Json<ChangePasswordBody>,
+    session: Session,
+    users_coll: Data<Collection<User>>,
+    session_registry: Data<SessionRegistry>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let verified = verify(&body.current_password, user.password.as_str())
+        .log_and_map(ErrorInternalServerError("verification failed"))?;
+    if !verified { return Err(ErrorUnauthorized("Password does not match")) }
+
+    if body.new_password.is_empty() {
+        return Err(ErrorBadRequest("Empty password"));
+    }
+
+    let password = hash(&body.new_password, 10)
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$set": {"password": password}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    // Anyone who knew the old password is logged out, only this session stays
+    let current = session.get::<String>(sessions::SESSION_ID_KEY).ok().flatten();
+    let ended = session_registry.end_other_sessions(user._id, current.as_deref()).await
+        .log_and_map(ErrorInternalServerError(""))?;
+    for session_id in ended {
+        ws_addr.do_send(Kick { _id: user._id, session_id: Some(session_id) });
+    }
+
+    Ok("")
+}
+
+#[post("/hideLastSeen")]
+pub async fn hide_last_seen_handler(
+    body: Json<HideLastSeenBody>,
+    users_coll: Data<Collection<User>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$set": {"hide_last_seen": body.hide}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok("")
+}
+
+#[post("/guestLink")]
+pub async fn guest_link_handler(
+    env_vars: Data<EnvVars>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let claims = GuestClaims {
+        sub: ObjectId::new().to_hex(),
+        owner: user._id.to_hex(),
+        aud: GUEST_AUDIENCE.to_string(),
+        exp: DateTime::now().timestamp_millis() / 1000 + env_vars.guest_link_ttl,
+    };
+
+    let token = encode(&Header::default(), &claims, &EncodingKey::from_secret(env_vars.confirm_secret.as_ref()))
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok(Json(serde_json::json!({
+        "link": format!("{}/guest?token={token}", env_vars.frontend_url),
+        "expires": claims.exp,
+    })))
+}
+
+#[get("/iceServers")]
+pub async fn ice_servers_handler(
+    env_vars: Data<EnvVars>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    Ok(Json(ice::ice_servers(&env_vars, user._id)))
+}
+
+#[post("/changeEmail")]
+pub async fn change_email_handler(
+    body: Json<ChangeEmailBody>,
+    users_coll: Data<Collection<User>>,
+    confirms_coll: Data<Collection<Confirm>>,
+    env_vars: Data<EnvVars>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let verified = verify(&body.password, user.password.as_str())
+        .log_and_map(ErrorInternalServerError("verification failed"))?;
+    if !verified { return Err(ErrorUnauthorized("Password does not match")) }
+
+    let filter = doc!{"email": &body.email};
+    let email_taken = users_coll.find_one(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .is_some();
+    if email_taken {return Err(ErrorBadRequest("Email in use"));}
+
+    // Only the latest requested address can be confirmed
+    let filter = doc!{"user": user._id, "email": {"$ne": null}};
+    confirms_coll.delete_many(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let token = create_confirm_token(user._id, &env_vars)?;
+    let confirm = Confirm {
+        _id: ObjectId::new(),
+        user: user._id,
+        token: token.clone(),
+        email: Some(body.email.clone()),
+    };
+    confirms_coll.insert_one(confirm, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    mail::send_email_change_confirmation(&user.username, &body.email, &token, &env_vars).await
+        .log_and_map(ErrorInternalServerError("Failed to send confirmation email"))?;
+
+    Ok("")
+}
+
+#[post("/confirmEmail/{token}")]
+pub async fn confirm_email_handler(
+    params: Path<String>,
+    confirms_coll: Data<Collection<Confirm>>,
+    users_coll: Data<Collection<User>>,
+    env_vars: Data<EnvVars>,
+) -> Result<impl Responder, Error> {
+    let token = params.into_inner();
+
+    verify_confirm_token(&token, &env_vars)?;
+
+    let filter = doc!{"token": token, "email": {"$ne": null}};
+    let confirm = confirms_coll.find_one_and_delete(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .ok_or_else(|| ErrorBadRequest("Invalid token"))?;
+    let email = confirm.email.unwrap_or_default();
+
+    // The address might have been registered since the change was requested
+    let filter = doc!{"email": &email};
+    let email_taken = users_coll.find_one(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .is_some();
+    if email_taken {return Err(ErrorBadRequest("Email in use"));}
+
+    let filter = doc!{"_id": confirm.user, "deleted": false};
+    let update = doc!{"$set": {"email": email}};
+    match users_coll.update_one(filter, update, None).await {
+        Err(err) if utils::is_duplicate_key(&err) => return Err(ErrorBadRequest("Email in use")),
+        result => result.log_and_map(ErrorInternalServerError(""))?,
+    };
+
+    Ok("ok")
+}
+
+#[post("/totp/enroll")]
+pub async fn enroll_totp_handler(
+    body: Json<PasswordBody>,
+    users_coll: Data<Collection<User>>,
+    env_vars: Data<EnvVars>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let verified = verify(&body.password, user.password.as_str())
+        .log_and_map(ErrorInternalServerError("verification failed"))?;
+    if !verified { return Err(ErrorUnauthorized("Password does not match")) }
+
+    if user.totp_enabled() {
+        return Err(ErrorBadRequest("TOTP already enabled"));
+    }
+
+    // The secret is only put to use once a code is verified at '/totp/verify'
+    let secret = totp::generate_secret();
+    let user_totp = Totp {
+        secret: totp::encrypt_secret(&secret, &env_vars.totp_secret),
+        enabled: false,
+        last_step: 0,
+        recovery_codes: vec![],
+    };
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$set": {"totp": mongodb::bson::to_bson(&user_totp).unwrap()}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok(Json(serde_json::json!({
+        "secret": totp::encode_secret(&secret),
+        "uri": totp::provisioning_uri(&secret, &user.email),
+    })))
+}
+
+#[post("/totp/verify")]
+pub async fn verify_totp_handler(
+    body: Json<TotpBody>,
+    users_coll: Data<Collection<User>>,
+    env_vars: Data<EnvVars>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let user_totp = user.totp
+        .filter(|totp| !totp.enabled)
+        .ok_or_else(|| ErrorBadRequest("No TOTP enrollment in progress"))?;
+
+    let secret = totp::decrypt_secret(&user_totp.secret, &env_vars.totp_secret)
+        .ok_or_else(|| ErrorInternalServerError(""))?;
+    let step = totp::verify_code(&secret, &body.code, user_totp.last_step)
+        .ok_or_else(|| ErrorBadRequest("Invalid code"))?;
+
+    let (recovery_codes, recovery_hashes) = totp::generate_recovery_codes();
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$set": {
+        "totp.enabled": true,
+        "totp.last_step": step,
+        "totp.recovery_codes": recovery_hashes,
+    }};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok(Json(recovery_codes))
+}
+
+#[delete("/totp")]
+pub async fn disable_totp_handler(
+    body: Json<PasswordBody>,
+    users_coll: Data<Collection<User>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let verified = verify(&body.password, user.password.as_str())
+        .log_and_map(ErrorInternalServerError("verification failed"))?;
+    if !verified { return Err(ErrorUnauthorized("Password does not match")) }
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$set": {"totp": null}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok("")
+}
+
+#[post("/passkey/register/start")]
+pub async fn start_passkey_registration_handler(
+    body: Json<PasskeyRegistrationBody>,
+    session: Session,
+    webauthn: Data<Webauthn>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    if body.name.is_empty() {
+        return Err(ErrorBadRequest("Empty name"))
+    }
+
+    if user.passkeys.iter().any(|credential| credential.name == body.name) {
+        return Err(ErrorBadRequest("Name collision"))
+    }
+
+    let registered = user.passkeys.iter()
+        .map(|credential| credential.passkey.cred_id().clone())
+        .collect();
+    let (challenge, state) = webauthn.start_passkey_registration(user_uuid(user._id), &user.email, &user.username, Some(registered))
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    session.insert(PASSKEY_REGISTRATION_KEY, (&body.name, state))
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok(Json(challenge))
+}
+
+#[post("/passkey/register/finish")]
+pub async fn finish_passkey_registration_handler(
+    credential: Json<RegisterPublicKeyCredential>,
+    session: Session,
+    users_coll: Data<Collection<User>>,
+    webauthn: Data<Webauthn>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let (name, state) = session.remove_as::<(String, PasskeyRegistration)>(PASSKEY_REGISTRATION_KEY)
+        .and_then(Result::ok)
+        .ok_or_else(|| ErrorBadRequest("No registration in progress"))?;
+
+    let passkey = webauthn.finish_passkey_registration(&credential, &state)
+        .map_err(|_| ErrorBadRequest("Registration failed"))?;
+
+    let credential = PasskeyCredential {
+        _id: ObjectId::new(),
+        name,
+        created: DateTime::now(),
+        passkey,
+    };
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$push": {"passkeys": credential}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok("")
+}
+
+#[get("/passkeys")]
+pub async fn passkeys_handler(
+    user: User,
+) -> Result<impl Responder, Error> {
+    let passkeys: Vec<MinimalPasskey> = user.passkeys.into_iter()
+        .map(|credential| MinimalPasskey {
+            _id: credential._id,
+            name: credential.name,
+            created: credential.created,
+        })
+        .collect();
+
+    Ok(Json(passkeys))
+}
+
+#[delete("/passkey/{id}")]
+pub async fn remove_passkey_handler(
+    params: Path<String>,
+    users_coll: Data<Collection<User>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let id = ObjectId::parse_str(params.into_inner())
+        .map_err(|_| ErrorBadRequest("Not an id"))?;
+
+    if !user.passkeys.iter().any(|credential| credential._id == id) {
+        return Err(ErrorBadRequest("No such passkey"))
+    }
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$pull": {"passkeys": {"_id": id}}};
     users_coll.update_one(filter, update, None).await
-        .log_and_map(ErrorInternalServerError("Could not update user profile"))?;
-
-    fs::remove_file(tmp_path).ok();
-    if user.avatar != "avatar.jpg" {
-        let path = PathBuf::from(format!("{}/{}", files_path, user.avatar));
-        fs::remove_file(path).ok();
-    }
+        .log_and_map(ErrorInternalServerError(""))?;
 
-    Ok(full_file_name)
+    Ok("")
 }
 
 #[get("/me")]
@@ -254,8 +984,62 @@ pub async fn me_handler(
     Ok(Json(user))
 }
 
+#[delete("/me")]
+pub async fn delete_me_handler(
+    body: Json<DeleteMeBody>,
+    users_coll: Data<Collection<User>>,
+    db: Data<Database>,
+    curr_dir: Data<CurrDir>,
+    session_registry: Data<SessionRegistry>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let verified = verify(&body.password, user.password.as_str())
+        .log_and_map(ErrorInternalServerError("verification failed"))?;
+    if !verified { return Err(ErrorUnauthorized("Password does not match")) }
+
+    let filter = doc!{"_id": user._id};
+    let update = doc!{"$set": {
+        "deleted": true,
+        "deleted_at": DateTime::now(),
+        "avatar": "avatar.jpg",
+        "friends": [],
+        "requests": [],
+        "sent_requests": [],
+        "devices": [],
+        "passkeys": [],
+        "totp": null,
+    }};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc!{"user": user._id};
+    db.collection::<Confirm>("confirms").delete_many(filter.clone(), None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+    db.collection::<Reset>("resets").delete_many(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc!{"$or": [{"friends": user._id}, {"requests": user._id}, {"sent_requests": user._id}]};
+    let update = doc!{"$pull": {"friends": user._id, "requests": user._id, "sent_requests": user._id}};
+    users_coll.update_many(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    if user.avatar != "avatar.jpg" {
+        let path = PathBuf::from(format!("{}/files/{}", curr_dir.path, user.avatar));
+        fs::remove_file(path).ok();
+    }
+
+    session_registry.end_user_sessions(user._id).await
+        .log_and_map(ErrorInternalServerError(""))?;
+    ws_addr.do_send(Kick { _id: user._id, session_id: None });
+    ws_addr.do_send(FriendsChanged { ids: user.friends });
+
+    Ok("")
+}
+
 #[get("/onlines")]
 pub async fn onlines_handler(
+    users_coll: Data<Collection<User>>,
     ws_addr: Data<Addr<Server>>,
     user: User,
 ) -> Result<impl Responder, Error> {
@@ -263,17 +1047,27 @@ pub async fn onlines_handler(
         .log_and_map(ErrorInternalServerError(""))?
         .ok_or_else(|| ErrorInternalServerError(""))?;
 
-    let friend_onlines: Vec<String> = onlines.into_iter()
-        .filter(|id| user.friends.contains(id))
-        .map(|id| id.to_hex())
+    let friends = utils::visible_friends(&users_coll, &user).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let friend_onlines: Vec<ObjectId> = onlines.into_iter()
+        .filter(|id| friends.contains(id))
         .collect();
 
+    let filter = doc!{"_id": {"$in": friend_onlines}};
+    let friend_onlines: Vec<UserPresence> = users_coll.find(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .map_ok(|friend| UserPresence { _id: friend._id, presence: friend.presence })
+        .try_collect().await
+        .log_and_map(ErrorInternalServerError(""))?;
+
     Ok(Json(friend_onlines))
 }
 
 #[get("/online/{id}")]
 pub async fn online_handler(
     params: Path<String>,
+    users_coll: Data<Collection<User>>,
     ws_addr: Data<Addr<Server>>,
     user: User,
 ) -> Result<impl Responder, Error> {
@@ -288,7 +1082,10 @@ pub async fn online_handler(
         .log_and_map(ErrorInternalServerError(""))?
         .ok_or_else(|| ErrorInternalServerError(""))?;
 
-    let is_online = onlines.contains(&id);
+    let friends = utils::visible_friends(&users_coll, &user).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let is_online = friends.contains(&id) && onlines.contains(&id);
     Ok(Json(is_online))
 }
 
@@ -303,14 +1100,17 @@ pub async fn user_by_email_handler(
         "email": email,
         "deleted": false,
         "confirmed": true,
+        "blocked": {"$ne": user._id},
         "$and": [
             {"_id": {"$nin": user.friends}},
+            {"_id": {"$nin": user.blocked}},
             {"_id": {"$ne": user._id}},
         ]
     };
 
     let user = minimal_users_coll.find_one(filter, None).await
-        .log_and_map(ErrorInternalServerError(""))?;
+        .log_and_map(ErrorInternalServerError(""))?
+        .map(MinimalUser::without_last_seen);
 
     Ok(Json(user))
 }
@@ -318,22 +1118,92 @@ pub async fn user_by_email_handler(
 #[get("/friends")]
 pub async fn friends_handler(
     minimal_users_coll: Data<Collection<MinimalUser>>,
+    users_coll: Data<Collection<User>>,
     user: User,
 ) -> Result<impl Responder, Error> {
+    // The friends blocked in either direction are listed, but their last-seen time is not shown
+    let visible = utils::visible_friends(&users_coll, &user).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
     let filter = doc!{
         "deleted": false,
         "confirmed": true,
-        "_id": {"$in": user.friends}
+        "_id": {"$in": &user.friends}
     };
 
+    // Hiding the own last-seen time hides the last-seen time of the friends as well
+    let hide_all = user.hide_last_seen;
     let users: Vec<MinimalUser> = minimal_users_coll.find(filter, None).await
         .log_and_map(ErrorInternalServerError(""))?
+        .map_ok(|friend| {
+            if hide_all || friend.hide_last_seen || !visible.contains(&friend._id) { friend.without_last_seen() } else { friend }
+        })
         .try_collect().await
         .log_and_map(ErrorInternalServerError(""))?;
 
     Ok(Json(users))
 }
 
+#[delete("/friend/{id}")]
+pub async fn unfriend_id_handler(
+    params: Path<String>,
+    users_coll: Data<Collection<User>>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let id = params.into_inner();
+    let id = ObjectId::from_str(&id)
+        .map_err(|_| ErrorBadRequest("Not an id"))?;
+
+    if !user.friends.contains(&id) {
+        return Err(ErrorBadRequest("Not friend"));
+    }
+
+    // Mongo runs standalone, so there are no transactions. If the friend can not be updated,
+    // the user is made their friend again, and neither of them is left half friends
+    let filter = doc! {"_id": user._id};
+    let update = doc! {"$pull": {"friends": id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc! {"_id": id};
+    let update = doc! {"$pull": {"friends": user._id}};
+    let result = update_one_with_retry(&users_coll, filter, update).await;
+    if result.is_err() {
+        let filter = doc! {"_id": user._id};
+        let update = doc! {"$addToSet": {"friends": id}};
+        update_one_with_retry(&users_coll, filter, update).await
+            .log_and_map(())
+            .ok();
+    }
+    result.log_and_map(ErrorInternalServerError(""))?;
+
+    ws_addr.do_send(FriendsChanged { ids: vec![user._id, id] });
+    ws_addr.do_send(Unfriend { _id: user._id, remote_id: id });
+    ws_addr.do_send(Dispatch {
+        event: "unfriend".to_string(),
+        payload: doc!{"_id": user._id.to_hex()},
+        filter: vec![id]
+    });
+
+    Ok("")
+}
+
+async fn update_one_with_retry(users_coll: &Collection<User>, filter: Document, update: Document) -> mongodb::error::Result<()> {
+    let mut attempt = 1;
+
+    loop {
+        match users_coll.update_one(filter.clone(), update.clone(), None).await {
+            Ok(_) => return Ok(()),
+            Err(err) if attempt == UNFRIEND_ATTEMPTS => return Err(err),
+            Err(_) => {
+                tokio::time::sleep(std::time::Duration::from_millis(UNFRIEND_RETRY_MILLIS * attempt as u64)).await;
+                attempt += 1;
+            },
+        }
+    }
+}
+
 #[post("/request/{id}")]
 pub async fn request_id_handler(
     params: Path<String>,
@@ -349,9 +1219,15 @@ pub async fn request_id_handler(
         return Err(ErrorBadRequest("Make peace with yourself"))
     }
 
+    if user.blocked.contains(&id) {
+        return Err(ErrorBadRequest("User blocked"))
+    }
+
+    // Users who blocked the requester are not revealed
     let filter = doc! {
         "deleted": false,
         "confirmed": true,
+        "blocked": {"$ne": user._id},
         "_id": id
     };
     let req_user = users_coll.find_one(filter, None).await
@@ -367,6 +1243,11 @@ pub async fn request_id_handler(
     users_coll.update_one(filter, update, None).await
         .log_and_map(ErrorInternalServerError(""))?;
 
+    let filter = doc!{"_id": &user._id};
+    let update = doc!{"$addToSet": {"sent_requests": req_user._id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
     tokio::spawn(async move {
         let event = Dispatch {
             event: "request".to_string(),
@@ -402,12 +1283,67 @@ pub async fn request_handler(
 
     let req_users: Vec<MinimalUser> = minimal_users_coll.find(filter, None).await
         .log_and_map(ErrorInternalServerError(""))?
+        .map_ok(MinimalUser::without_last_seen)
+        .try_collect().await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok(Json(req_users))
+}
+
+#[get("/request/outgoing")]
+pub async fn outgoing_request_handler(
+    minimal_users_coll: Data<Collection<MinimalUser>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let filter = doc! {
+        "deleted": false,
+        "confirmed": true,
+        "_id": {"$in": user.sent_requests}
+    };
+
+    let req_users: Vec<MinimalUser> = minimal_users_coll.find(filter, None).await
+        .log_and_map(ErrorInternalServerError(""))?
+        .map_ok(MinimalUser::without_last_seen)
         .try_collect().await
         .log_and_map(ErrorInternalServerError(""))?;
 
     Ok(Json(req_users))
 }
 
+#[delete("/request/{id}")]
+pub async fn cancel_request_id_handler(
+    params: Path<String>,
+    users_coll: Data<Collection<User>>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let id = params.into_inner();
+    let id = ObjectId::from_str(&id)
+        .map_err(|_| ErrorBadRequest("Not an id"))?;
+
+    if !user.sent_requests.contains(&id) {
+        return Err(ErrorBadRequest("Not in sent requests"));
+    }
+
+    let filter = doc! {"_id": user._id};
+    let update = doc! {"$pull": {"sent_requests": id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc! {"_id": id};
+    let update = doc! {"$pull": {"requests": user._id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    ws_addr.do_send(Dispatch {
+        event: "cancelRequest".to_string(),
+        payload: doc!{"_id": user._id.to_hex()},
+        filter: vec![id]
+    });
+
+    Ok("")
+}
+
 #[post("/accept/{id}")]
 pub async fn accept_id_handler(
     params: Path<String>,
@@ -432,10 +1368,15 @@ pub async fn accept_id_handler(
         .log_and_map(ErrorInternalServerError(""))?;
 
     let filter = doc! {"_id": id};
-    let update = doc! {"$addToSet": {"friends": user._id}};
+    let update = doc! {
+        "$pull": {"sent_requests": user._id},
+        "$addToSet": {"friends": user._id}
+    };
     users_coll.update_one(filter, update, None).await
         .log_and_map(ErrorInternalServerError(""))?;
 
+    ws_addr.do_send(FriendsChanged { ids: vec![user._id, id] });
+
     tokio::spawn(async move {
         let event = Dispatch {
             event: "friend".to_string(),
@@ -480,6 +1421,70 @@ pub async fn decline_id_handler(
     users_coll.update_one(filter, update, None).await
         .log_and_map(ErrorInternalServerError(""))?;
 
+    let filter = doc! {"_id": id};
+    let update = doc! {"$pull": {"sent_requests": user._id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    Ok("")
+}
+
+#[post("/block/{id}")]
+pub async fn block_id_handler(
+    params: Path<String>,
+    users_coll: Data<Collection<User>>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let id = params.into_inner();
+    let id = ObjectId::from_str(&id)
+        .map_err(|_| ErrorBadRequest("Not an id"))?;
+
+    if user._id == id {
+        return Err(ErrorBadRequest("Make peace with yourself"))
+    }
+
+    // Pending requests from the blocked user are dropped as well
+    let filter = doc! {"_id": user._id};
+    let update = doc! {
+        "$addToSet": {"blocked": id},
+        "$pull": {"requests": id}
+    };
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    let filter = doc! {"_id": id};
+    let update = doc! {"$pull": {"sent_requests": user._id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    ws_addr.do_send(FriendsChanged { ids: vec![user._id, id] });
+
+    Ok("")
+}
+
+#[delete("/block/{id}")]
+pub async fn unblock_id_handler(
+    params: Path<String>,
+    users_coll: Data<Collection<User>>,
+    ws_addr: Data<Addr<Server>>,
+    user: User,
+) -> Result<impl Responder, Error> {
+    let id = params.into_inner();
+    let id = ObjectId::from_str(&id)
+        .map_err(|_| ErrorBadRequest("Not an id"))?;
+
+    if !user.blocked.contains(&id) {
+        return Err(ErrorBadRequest("Not blocked"));
+    }
+
+    let filter = doc! {"_id": user._id};
+    let update = doc! {"$pull": {"blocked": id}};
+    users_coll.update_one(filter, update, None).await
+        .log_and_map(ErrorInternalServerError(""))?;
+
+    ws_addr.do_send(FriendsChanged { ids: vec![user._id, id] });
+
     Ok("")
 }
 
@@ -544,21 +1549,40 @@ pub async fn test_devices_handler(
     Ok(Json(remaining_devices))
 }
 
-#[post("/ping")]
+#[post("/ping", wrap = "RateLimit::per_ip(\"ping\", 30, 60)")]
 pub async fn ping_handler(
     ping: Json<PingBody>,
     users_coll: Data<Collection<User>>,
+    rate_limiter: Data<RateLimiter>,
     user: User,
 ) -> Result<impl Responder, Error> {
     if !user.friends.contains(&ping.id) {
         return Err(ErrorBadRequest("Not friend"))
     }
 
+    let bucket = format!("ping:account:{}:{}", user._id.to_hex(), ping.id.to_hex());
+    if let Some(retry_after) = rate_limiter.hit(&bucket, PING_LIMIT, PING_WINDOW_SECS).await {
+        return Err(too_many_requests(retry_after));
+    }
+
+    if user.blocked.contains(&ping.id) {
+        return Err(ErrorBadRequest("User blocked"))
+    }
+
     let filter = doc!{"_id": ping.id};
-    let friend_devices = users_coll.find_one(filter, None).await
+    let friend = users_coll.find_one(filter, None).await
         .log_and_map(ErrorInternalServerError(""))?
-        .ok_or_else(|| ErrorInternalServerError(""))?
-        .devices;
+        .ok_or_else(|| ErrorInternalServerError(""))?;
+
+    // Acting as if the friend had no devices, so the block is not revealed
+    if friend.blocked.contains(&user._id) {
+        return Ok(Json(false));
+    }
+
+    if friend.presence.status == Status::DoNotDisturb {
+        return Ok(Json(false));
+    }
+    let friend_devices = friend.devices;
 
     let title = format!("'{}' pinged you!", user.username);
     let body = if !ping.message.is_empty() {
@@ -686,3 +1710,174 @@ pub async fn files_handler(
 
     Ok(res)
 }
+
+fn create_confirm_token(user_id: ObjectId, env_vars: &EnvVars) -> Result<String, Error> {
+    let claims = ConfirmClaims {
+        sub: user_id.to_hex(),
+        exp: DateTime::now().timestamp_millis() / 1000 + env_vars.confirm_token_ttl,
+    };
+
+    encode(&Header::default(), &claims, &EncodingKey::from_secret(env_vars.confirm_secret.as_ref()))
+        .log_and_map(ErrorInternalServerError(""))
+}
+
+fn verify_confirm_token(token: &str, env_vars: &EnvVars) -> Result<(), Error> {
+    ConfirmClaims::verify(token, env_vars.confirm_secret.as_ref())
+        .map_err(|err| match err {
+            ConfirmTokenError::Expired => ErrorBadRequest("Expired token"),
+            ConfirmTokenError::Invalid => ErrorBadRequest("Invalid token"),
+        })
+}
+
+fn clear_totp_login(session: &Session) {
+    session.remove(TOTP_USER_KEY);
+    session.remove(TOTP_STARTED_KEY);
+}
+
+// WebAuthn identifies users by a UUID, the ObjectId of the user is padded to get one
+fn user_uuid(user_id: ObjectId) -> Uuid {
+    let mut bytes = [0; 16];
+    bytes[..12].copy_from_slice(&user_id.bytes());
+
+    Uuid::from_bytes(bytes)
+}
+
+// Looks like a challenge for an account with passkeys, the same email always gets the same credential ids
+fn decoy_passkey_challenge(webauthn: &Webauthn, fake_passkeys: &FakePasskeys, email: &str) -> Result<(RequestChallengeResponse, PasskeyAuthentication), anyhow::Error> {
+    let (mut challenge, state) = webauthn.start_passkey_authentication(&[])?;
+
+    let allow_credentials: Vec<_> = fake_passkeys.generate(email.to_lowercase().as_bytes())?
+        .into_iter()
+        .map(|id| serde_json::json!({"type": "public-key", "id": id}))
+        .collect();
+    challenge.public_key.allow_credentials = serde_json::from_value(allow_credentials.into())?;
+
+    Ok((challenge, state))
+}
+
+/// The failed logins counted for the account, so rotating addresses does not give more tries,
+/// and for the address, so it can not try many accounts either.
+struct LoginFailures {
+    account: String,
+    address: String,
+}
+
+impl LoginFailures {
+    fn new(request: &HttpRequest, email: &str) -> LoginFailures {
+        let ip = rate_limit::client_ip(request).unwrap_or_else(|| "unknown".to_string());
+
+        LoginFailures {
+            account: format!("login:account:{}", email.to_lowercase()),
+            address: format!("login:ip:{ip}"),
+        }
+    }
+
+    /// Checked before the credentials, so a locked account does not even reach bcrypt.
+    async fn check(&self, rate_limiter: &RateLimiter) -> Result<(), Error> {
+        let account = rate_limiter.check(&self.account, LOGIN_FAILURE_LIMIT).await;
+        let address = rate_limiter.check(&self.address, LOGIN_IP_FAILURE_LIMIT).await;
+
+        match account.max(address) {
+            Some(retry_after) => Err(too_many_requests(retry_after)),
+            None => Ok(()),
+        }
+    }
+
+    async fn hit(&self, rate_limiter: &RateLimiter) {
+        rate_limiter.hit(&self.account, LOGIN_FAILURE_LIMIT, LOGIN_LOCKOUT_SECS).await;
+        rate_limiter.hit(&self.address, LOGIN_IP_FAILURE_LIMIT, LOGIN_LOCKOUT_SECS).await;
+    }
+
+    // The failures of the address stay, so logging in to an own account does not reset them
+    async fn reset(&self, rate_limiter: &RateLimiter) {
+        rate_limiter.reset(&self.account).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mongodb::bson;
+    use webauthn_authenticator_rs::{WebauthnAuthenticator, softpasskey::SoftPasskey};
+    use webauthn_rs::{WebauthnBuilder, prelude::{Passkey, Url}};
+
+    fn webauthn() -> (Webauthn, Url) {
+        let origin = Url::parse("https://localhost:8080").unwrap();
+        let webauthn = WebauthnBuilder::new("localhost", &origin).unwrap().rp_name("Speer").build().unwrap();
+
+        (webauthn, origin)
+    }
+
+    fn register(webauthn: &Webauthn, origin: &Url, authenticator: &mut WebauthnAuthenticator<SoftPasskey>) -> Passkey {
+        let (challenge, state) = webauthn.start_passkey_registration(user_uuid(ObjectId::new()), "user@example.com", "user", None).unwrap();
+        let credential = authenticator.do_registration(origin.clone(), challenge).unwrap();
+
+        webauthn.finish_passkey_registration(&credential, &state).unwrap()
+    }
+
+    #[test]
+    fn unknown_accounts_cost_as_much_as_known_ones() {
+        assert!(!verify("password", UNKNOWN_ACCOUNT_HASH).unwrap());
+
+        let cost = UNKNOWN_ACCOUNT_HASH.split('$').nth(2).unwrap().parse::<u32>().unwrap();
+        assert_eq!(cost, 10);
+    }
+
+    #[test]
+    fn stored_passkey_can_log_in() {
+        let (webauthn, origin) = webauthn();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&webauthn, &origin, &mut authenticator);
+
+        // The passkey has to survive the round trip through the user document
+        let stored = PasskeyCredential { _id: ObjectId::new(), name: "laptop".to_string(), created: DateTime::now(), passkey };
+        let stored: PasskeyCredential = bson::from_bson(stored.into()).unwrap();
+
+        let (challenge, state) = webauthn.start_passkey_authentication(std::slice::from_ref(&stored.passkey)).unwrap();
+        let credential = authenticator.do_authentication(origin, challenge).unwrap();
+        let result = webauthn.finish_passkey_authentication(&credential, &state).unwrap();
+
+        assert_eq!(result.cred_id(), stored.passkey.cred_id());
+    }
+
+    #[test]
+    fn decoy_challenge_is_stable_per_email() {
+        let (webauthn, _) = webauthn();
+        let fake_passkeys = FakePasskeys::new(b"key").unwrap();
+        let allowed = |email| {
+            let (challenge, _) = decoy_passkey_challenge(&webauthn, &fake_passkeys, email).unwrap();
+            serde_json::to_value(challenge.public_key.allow_credentials).unwrap()
+        };
+
+        assert_eq!(allowed("nobody@example.com"), allowed("Nobody@example.com"));
+        assert_eq!(allowed("nobody@example.com")[0]["type"], "public-key");
+    }
+
+    #[test]
+    fn decoy_challenge_rejects_real_passkeys() {
+        let (webauthn, origin) = webauthn();
+        let fake_passkeys = FakePasskeys::new(b"key").unwrap();
+        let mut authenticator = WebauthnAuthenticator::new(SoftPasskey::new(true));
+        let passkey = register(&webauthn, &origin, &mut authenticator);
+
+        let (challenge, _) = webauthn.start_passkey_authentication(&[passkey]).unwrap();
+        let credential = authenticator.do_authentication(origin, challenge).unwrap();
+        let (_, decoy_state) = decoy_passkey_challenge(&webauthn, &fake_passkeys, "user@example.com").unwrap();
+
+        assert!(webauthn.finish_passkey_authentication(&credential, &decoy_state).is_err());
+    }
+
+    #[actix_web::test]
+    async fn unfriend_updates_give_up_after_their_attempts() {
+        // Nothing listens there, so every attempt fails
+        let options = mongodb::options::ClientOptions::parse("mongodb://127.0.0.1:9/?serverSelectionTimeoutMS=50").await.unwrap();
+        let users_coll = mongodb::Client::with_options(options).unwrap().database("speer").collection::<User>("users");
+
+        let started = std::time::Instant::now();
+        let result = update_one_with_retry(&users_coll, doc! {"_id": ObjectId::new()}, doc! {"$pull": {"friends": ObjectId::new()}}).await;
+
+        assert!(result.is_err());
+        // The waits between the attempts
+        assert!(started.elapsed() >= std::time::Duration::from_millis(UNFRIEND_RETRY_MILLIS * 3));
+    }
+}
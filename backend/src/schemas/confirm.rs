@@ -1,3 +1,5 @@
+use base64::{engine::general_purpose::URL_SAFE_NO_PAD as BASE64_URL, Engine};
+use jsonwebtoken::{crypto, decode, errors::ErrorKind, Algorithm, DecodingKey, Validation};
 use mongodb::bson::oid::ObjectId;
 use serde::{Serialize, Deserialize};
 
@@ -6,4 +8,78 @@ pub struct Confirm {
     pub _id: ObjectId,
     pub user: ObjectId,
     pub token: String,
-}
\ No newline at end of file
+    // Set when the token confirms an email change instead of a registration
+    #[serde(default)]
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ConfirmClaims {
+    pub sub: String,
+    pub exp: i64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfirmTokenError {
+    Expired,
+    Invalid,
+}
+
+impl ConfirmClaims {
+    /// Checks the signature and the expiry of a confirmation token. The tokens issued
+    /// before they expired only hold the id of the user, these are still accepted, as
+    /// the registrations not confirmed within the grace period are purged anyway.
+    pub fn verify(token: &str, secret: &[u8]) -> Result<(), ConfirmTokenError> {
+        let key = DecodingKey::from_secret(secret);
+
+        match decode::<ConfirmClaims>(token, &key, &Validation::default()) {
+            Ok(_) => Ok(()),
+            Err(err) if *err.kind() == ErrorKind::ExpiredSignature => Err(ConfirmTokenError::Expired),
+            Err(_) if is_legacy_token(token, &key) => Ok(()),
+            Err(_) => Err(ConfirmTokenError::Invalid),
+        }
+    }
+}
+
+// The old tokens were signed with the default header and hold the id as a JSON string
+fn is_legacy_token(token: &str, key: &DecodingKey) -> bool {
+    let Some((message, signature)) = token.rsplit_once('.') else { return false };
+    let Some((_, payload)) = message.split_once('.') else { return false };
+
+    let signed = crypto::verify(signature, message.as_bytes(), key, Algorithm::HS256).unwrap_or(false);
+
+    signed && BASE64_URL.decode(payload).ok()
+        .and_then(|payload| serde_json::from_slice::<String>(&payload).ok())
+        .is_some_and(|id| ObjectId::parse_str(id).is_ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use super::*;
+
+    fn token<T: Serialize>(claims: &T, secret: &[u8]) -> String {
+        encode(&Header::default(), claims, &EncodingKey::from_secret(secret)).unwrap()
+    }
+
+    #[test]
+    fn expired_and_forged_tokens_are_told_apart() {
+        let now = get_current_timestamp() as i64;
+        let claims = |exp| ConfirmClaims { sub: ObjectId::new().to_hex(), exp };
+
+        assert_eq!(ConfirmClaims::verify(&token(&claims(now + 60), b"secret"), b"secret"), Ok(()));
+        assert_eq!(ConfirmClaims::verify(&token(&claims(now - 120), b"secret"), b"secret"), Err(ConfirmTokenError::Expired));
+        assert_eq!(ConfirmClaims::verify(&token(&claims(now + 60), b"other"), b"secret"), Err(ConfirmTokenError::Invalid));
+        assert_eq!(ConfirmClaims::verify(&token(&claims(now - 120), b"other"), b"secret"), Err(ConfirmTokenError::Invalid));
+        assert_eq!(ConfirmClaims::verify("not.a.token", b"secret"), Err(ConfirmTokenError::Invalid));
+    }
+
+    #[test]
+    fn tokens_without_expiry_are_accepted() {
+        let id = ObjectId::new().to_hex();
+
+        assert_eq!(ConfirmClaims::verify(&token(&id, b"secret"), b"secret"), Ok(()));
+        assert_eq!(ConfirmClaims::verify(&token(&id, b"other"), b"secret"), Err(ConfirmTokenError::Invalid));
+        assert_eq!(ConfirmClaims::verify(&token(&"not an id", b"secret"), b"secret"), Err(ConfirmTokenError::Invalid));
+    }
+}
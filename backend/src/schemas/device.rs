@@ -21,6 +21,7 @@ pub struct Device {
     pub subscription: WebPushSubscription,
 }
 
+#[allow(clippy::from_over_into)]
 impl Into<bson::Bson> for Device {
     fn into(self) -> bson::Bson {
         bson::to_bson(&self).unwrap()
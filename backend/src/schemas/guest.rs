@@ -0,0 +1,53 @@
+use jsonwebtoken::Validation;
+use serde::{Serialize, Deserialize};
+
+// Guest tokens are signed with the same secret as the confirmation tokens, the audience tells them apart
+pub const GUEST_AUDIENCE: &str = "guest";
+
+/// The claims of a guest call link, `sub` is the id of the link. Every connection
+/// made with it gets its own guest id, and can only signal the owner of the link.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct GuestClaims {
+    pub sub: String,
+    pub owner: String,
+    pub aud: String,
+    pub exp: i64,
+}
+
+impl GuestClaims {
+    /// Only accepts tokens meant for guests.
+    pub fn validation() -> Validation {
+        let mut validation = Validation::default();
+        validation.set_audience(&[GUEST_AUDIENCE]);
+        validation.set_required_spec_claims(&["exp", "aud"]);
+
+        validation
+    }
+}
+
+/// The guest joining, as sent to the owner of the link.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct GuestLogin {
+    pub _id: String,
+    pub username: String,
+}
+
+#[cfg(test)]
+mod tests {
+    use jsonwebtoken::{decode, encode, DecodingKey, EncodingKey, Header};
+    use super::*;
+    use crate::schemas::ConfirmClaims;
+
+    #[test]
+    fn only_guest_tokens_are_accepted() {
+        let exp = jsonwebtoken::get_current_timestamp() as i64 + 60;
+        let key = EncodingKey::from_secret(b"secret");
+        let guest = GuestClaims { sub: "guest".to_string(), owner: "owner".to_string(), aud: GUEST_AUDIENCE.to_string(), exp };
+        let guest_token = encode(&Header::default(), &guest, &key).unwrap();
+        let confirm_token = encode(&Header::default(), &ConfirmClaims { sub: "user".to_string(), exp }, &key).unwrap();
+
+        let decode_guest = |token: &str| decode::<GuestClaims>(token, &DecodingKey::from_secret(b"secret"), &GuestClaims::validation());
+        assert!(decode_guest(&guest_token).is_ok());
+        assert!(decode_guest(&confirm_token).is_err());
+    }
+}
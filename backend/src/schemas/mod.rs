@@ -1,7 +1,12 @@
 mod user;
 mod device;
 mod confirm;
+mod reset;
 mod feedback;
+mod totp;
+mod passkey;
+mod presence;
+mod guest;
 
 pub use device::Device;
 pub use device::MinimalDevice;
@@ -9,4 +14,16 @@ pub use user::User;
 pub use user::MinimalUser;
 pub use user::MeUser;
 pub use confirm::Confirm;
-pub use feedback::Feedback;
\ No newline at end of file
+pub use confirm::{ConfirmClaims, ConfirmTokenError};
+pub use reset::Reset;
+pub use feedback::Feedback;
+pub use totp::Totp;
+pub use totp::MinimalTotp;
+pub use passkey::PasskeyCredential;
+pub use passkey::MinimalPasskey;
+pub use presence::Status;
+pub use presence::Presence;
+pub use presence::UserPresence;
+pub use guest::GuestClaims;
+pub use guest::GUEST_AUDIENCE;
+pub use guest::GuestLogin;
\ No newline at end of file
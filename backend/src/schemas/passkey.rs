@@ -0,0 +1,26 @@
+use mongodb::bson::{self, oid::ObjectId, DateTime, serde_helpers::{serialize_object_id_as_hex_string, serialize_bson_datetime_as_rfc3339_string}};
+use serde::{Serialize, Deserialize};
+use webauthn_rs::prelude::Passkey;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct PasskeyCredential {
+    pub _id: ObjectId,
+    pub name: String,
+    pub created: DateTime,
+    pub passkey: Passkey,
+}
+
+impl From<PasskeyCredential> for bson::Bson {
+    fn from(credential: PasskeyCredential) -> Self {
+        bson::to_bson(&credential).unwrap()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MinimalPasskey {
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub _id: ObjectId,
+    pub name: String,
+    #[serde(serialize_with = "serialize_bson_datetime_as_rfc3339_string")]
+    pub created: DateTime,
+}
@@ -0,0 +1,35 @@
+use mongodb::bson::{oid::ObjectId, serde_helpers::serialize_object_id_as_hex_string, Bson};
+use schemars::JsonSchema;
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Status {
+    #[default]
+    Online,
+    Away,
+    Busy,
+    // Pings are not delivered as push notifications
+    DoNotDisturb,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Default)]
+pub struct Presence {
+    pub status: Status,
+    pub text: Option<String>,
+}
+
+impl From<Presence> for Bson {
+    fn from(presence: Presence) -> Self {
+        mongodb::bson::to_bson(&presence).unwrap()
+    }
+}
+
+/// The presence of a friend, as sent to the clients.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct UserPresence {
+    #[serde(serialize_with = "serialize_object_id_as_hex_string")]
+    pub _id: ObjectId,
+    #[serde(flatten)]
+    pub presence: Presence,
+}
@@ -0,0 +1,11 @@
+use mongodb::bson::{oid::ObjectId, DateTime};
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Reset {
+    pub _id: ObjectId,
+    pub user: ObjectId,
+    // The token itself is only sent in the email
+    pub token_hash: String,
+    pub expires: DateTime,
+}
@@ -0,0 +1,15 @@
+use serde::{Serialize, Deserialize};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Totp {
+    // Encrypted with the `totp_secret` from the environment
+    pub secret: String,
+    pub enabled: bool,
+    pub last_step: i64,
+    pub recovery_codes: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MinimalTotp {
+    pub enabled: bool,
+}
@@ -1,11 +1,11 @@
 use std::pin::Pin;
 use actix_web::{Error, FromRequest, HttpRequest, dev, error::{ErrorUnauthorized, ErrorInternalServerError}, web::Data};
 use futures::Future;
-use mongodb::{Collection, Database, bson::{doc, oid::ObjectId, serde_helpers::serialize_object_id_as_hex_string}};
+use mongodb::{Collection, Database, bson::{doc, oid::ObjectId, DateTime, serde_helpers::serialize_object_id_as_hex_string}};
 use actix_identity::Identity;
-use serde::{Serialize, Deserialize};
+use serde::{Serialize, Deserialize, Serializer};
 
-use crate::{schemas::{Device, MinimalDevice}, utils::MapAndLog};
+use crate::{schemas::{Device, MinimalDevice, MinimalPasskey, MinimalTotp, PasskeyCredential, Presence, Totp}, utils::MapAndLog};
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct User {
@@ -15,10 +15,26 @@ pub struct User {
     pub username: String,
     pub avatar: String,
     pub requests: Vec<ObjectId>,
+    #[serde(default)]
+    pub sent_requests: Vec<ObjectId>,
     pub friends: Vec<ObjectId>,
+    #[serde(default)]
+    pub blocked: Vec<ObjectId>,
     pub devices: Vec<Device>,
+    #[serde(default)]
+    pub totp: Option<Totp>,
+    #[serde(default)]
+    pub passkeys: Vec<PasskeyCredential>,
+    #[serde(default)]
+    pub presence: Presence,
+    #[serde(default)]
+    pub last_seen: Option<DateTime>,
+    #[serde(default)]
+    pub hide_last_seen: bool,
     pub confirmed: bool,
     pub deleted: bool,
+    #[serde(default)]
+    pub deleted_at: Option<DateTime>,
     pub admin: bool,
 }
 
@@ -38,6 +54,12 @@ impl FromRequest for User {
     }
 }
 
+impl User {
+    pub fn totp_enabled(&self) -> bool {
+        self.totp.as_ref().is_some_and(|totp| totp.enabled)
+    }
+}
+
 impl Default for User {
     fn default() -> Self {
         User {
@@ -47,10 +69,18 @@ impl Default for User {
             username: "".to_string(),
             avatar: "avatar.jpg".to_string(),
             requests: vec![],
+            sent_requests: vec![],
             friends: vec![],
+            blocked: vec![],
             devices: vec![],
+            totp: None,
+            passkeys: vec![],
+            presence: Presence::default(),
+            last_seen: None,
+            hide_last_seen: false,
             confirmed: false,
             deleted: false,
+            deleted_at: None,
             admin: false,
         }
     }
@@ -81,6 +111,17 @@ pub struct MinimalUser {
     pub email: String,
     pub username: String,
     pub avatar: String,
+    // Only shown to friends, who did not hide their own last-seen time
+    #[serde(default, serialize_with = "serialize_optional_date")]
+    pub last_seen: Option<DateTime>,
+    #[serde(default, skip_serializing)]
+    pub hide_last_seen: bool,
+}
+
+impl MinimalUser {
+    pub fn without_last_seen(self) -> MinimalUser {
+        MinimalUser { last_seen: None, ..self }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -91,8 +132,25 @@ pub struct MeUser {
     pub username: String,
     pub avatar: String,
     pub requests: Vec<ObjectId>,
+    #[serde(default)]
+    pub sent_requests: Vec<ObjectId>,
     pub friends: Vec<ObjectId>,
+    #[serde(default)]
+    pub blocked: Vec<ObjectId>,
     pub devices: Vec<MinimalDevice>,
+    #[serde(default)]
+    pub totp: Option<MinimalTotp>,
+    #[serde(default)]
+    pub passkeys: Vec<MinimalPasskey>,
+    #[serde(default)]
+    pub presence: Presence,
+    #[serde(default)]
+    pub hide_last_seen: bool,
     pub confirmed: bool,
     pub deleted: bool,
 }
+
+fn serialize_optional_date<S: Serializer>(date: &Option<DateTime>, serializer: S) -> Result<S::Ok, S::Error> {
+    date.and_then(|date| date.try_to_rfc3339_string().ok())
+        .serialize(serializer)
+}
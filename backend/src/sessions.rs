@@ -0,0 +1,198 @@
+use std::collections::HashMap;
+use actix_session::{Session, storage::{LoadError, RedisSessionStore, SaveError, SessionKey, SessionStore, UpdateError}};
+use actix_web::{cookie::time::Duration, http::header::USER_AGENT, HttpRequest};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use redis::{aio::ConnectionManager, AsyncCommands, RedisError};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::{rate_limit, utils};
+
+// The key under which actix-identity stores the id of the logged in user
+const IDENTITY_KEY: &str = "actix_identity.user_id";
+
+pub const SESSION_ID_KEY: &str = "session_id";
+const CREATED_KEY: &str = "session_created";
+const USER_AGENT_KEY: &str = "session_user_agent";
+const IP_KEY: &str = "session_ip";
+
+#[derive(Serialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct SessionInfo {
+    pub id: String,
+    pub created: Option<i64>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub current: bool,
+    pub connected: bool,
+}
+
+/// A wrapper around the `RedisSessionStore` which remembers the session keys
+/// belonging to each user, so that their sessions can be listed and ended from
+/// the server.
+#[derive(Clone)]
+pub struct SessionRegistry {
+    store: RedisSessionStore,
+    client: ConnectionManager,
+}
+
+impl SessionRegistry {
+    pub async fn new(redis_url: &str, client: ConnectionManager) -> Result<SessionRegistry, anyhow::Error> {
+        let store = RedisSessionStore::new(redis_url).await?;
+
+        Ok(SessionRegistry { store, client })
+    }
+
+    /// Returns the sessions of the user, `current` and `connected` are left for the caller to fill in.
+    pub async fn user_sessions(&self, user_id: ObjectId) -> Result<Vec<SessionInfo>, anyhow::Error> {
+        let mut client = self.client.clone();
+        let key = user_sessions_key(user_id);
+
+        let session_keys: HashMap<String, String> = client.hgetall(&key).await?;
+        let mut sessions = vec![];
+
+        for (id, session_key) in session_keys {
+            let state = self.store.load(&SessionKey::try_from(session_key)?).await?;
+
+            // Sessions which expired or were logged out are forgotten lazily
+            let Some(state) = state else {
+                client.hdel::<_, _, ()>(&key, &id).await?;
+                continue;
+            };
+
+            sessions.push(SessionInfo {
+                id,
+                created: state_value(&state, CREATED_KEY),
+                user_agent: state_value(&state, USER_AGENT_KEY),
+                ip: state_value(&state, IP_KEY),
+                current: false,
+                connected: false,
+            });
+        }
+
+        Ok(sessions)
+    }
+
+    /// Deletes a single session of the user from Redis. Returns false if there was no such session.
+    pub async fn end_session(&self, user_id: ObjectId, session_id: &str) -> Result<bool, RedisError> {
+        let mut client = self.client.clone();
+        let key = user_sessions_key(user_id);
+
+        let session_key: Option<String> = client.hget(&key, session_id).await?;
+        let Some(session_key) = session_key else { return Ok(false) };
+
+        client.del::<_, ()>(session_key).await?;
+        client.hdel::<_, _, ()>(&key, session_id).await?;
+
+        Ok(true)
+    }
+
+    /// Deletes every session of the user from Redis, logging them out everywhere.
+    pub async fn end_user_sessions(&self, user_id: ObjectId) -> Result<(), RedisError> {
+        let mut client = self.client.clone();
+        let key = user_sessions_key(user_id);
+
+        let session_keys: Vec<String> = client.hvals(&key).await?;
+        if !session_keys.is_empty() {
+            client.del::<_, ()>(session_keys).await?;
+        }
+
+        client.del(key).await
+    }
+
+    /// Deletes the sessions of the user except the current one. Returns the ids of the ended sessions.
+    pub async fn end_other_sessions(&self, user_id: ObjectId, current: Option<&str>) -> Result<Vec<String>, RedisError> {
+        let mut client = self.client.clone();
+        let key = user_sessions_key(user_id);
+
+        let user_sessions: HashMap<String, String> = client.hgetall(&key).await?;
+        let (ids, session_keys): (Vec<String>, Vec<String>) = user_sessions.into_iter()
+            .filter(|(id, _)| Some(id.as_str()) != current)
+            .unzip();
+
+        if !ids.is_empty() {
+            client.del::<_, ()>(session_keys).await?;
+            client.hdel::<_, _, ()>(&key, &ids).await?;
+        }
+
+        Ok(ids)
+    }
+
+    async fn track(&self, session_key: &SessionKey, session_state: &HashMap<String, String>, ttl: &Duration) {
+        let user_id = state_value::<String>(session_state, IDENTITY_KEY)
+            .and_then(|id| ObjectId::parse_str(id).ok());
+
+        if let Some(user_id) = user_id {
+            let mut client = self.client.clone();
+            let key = user_sessions_key(user_id);
+            let session_id = state_value(session_state, SESSION_ID_KEY)
+                .unwrap_or_else(|| legacy_session_id(session_key));
+
+            client.hset::<_, _, _, ()>(&key, session_id, session_key.as_ref()).await.ok();
+            client.expire::<_, ()>(&key, ttl.whole_seconds()).await.ok();
+        }
+    }
+}
+
+impl SessionStore for SessionRegistry {
+    async fn load(&self, session_key: &SessionKey) -> Result<Option<HashMap<String, String>>, LoadError> {
+        self.store.load(session_key).await
+    }
+
+    async fn save(&self, session_state: HashMap<String, String>, ttl: &Duration) -> Result<SessionKey, SaveError> {
+        let session_key = self.store.save(session_state.clone(), ttl).await?;
+        self.track(&session_key, &session_state, ttl).await;
+
+        Ok(session_key)
+    }
+
+    async fn update(
+        &self,
+        session_key: SessionKey,
+        session_state: HashMap<String, String>,
+        ttl: &Duration,
+    ) -> Result<SessionKey, UpdateError> {
+        let session_key = self.store.update(session_key, session_state.clone(), ttl).await?;
+        self.track(&session_key, &session_state, ttl).await;
+
+        Ok(session_key)
+    }
+
+    async fn update_ttl(&self, session_key: &SessionKey, ttl: &Duration) -> Result<(), anyhow::Error> {
+        self.store.update_ttl(session_key, ttl).await
+    }
+
+    async fn delete(&self, session_key: &SessionKey) -> Result<(), anyhow::Error> {
+        self.store.delete(session_key).await
+    }
+}
+
+/// Stores the metadata shown in the session list into a freshly logged in session.
+pub fn record_login(request: &HttpRequest, session: &Session) -> Result<(), anyhow::Error> {
+    let user_agent = request.headers().get(USER_AGENT)
+        .and_then(|user_agent| user_agent.to_str().ok())
+        .map(|user_agent| user_agent.to_string());
+    let ip = rate_limit::client_ip(request);
+
+    session.insert(SESSION_ID_KEY, utils::generate_random_string(16))?;
+    session.insert(CREATED_KEY, DateTime::now().timestamp_millis())?;
+    session.insert(USER_AGENT_KEY, user_agent)?;
+    session.insert(IP_KEY, ip)?;
+
+    Ok(())
+}
+
+fn state_value<T: serde::de::DeserializeOwned>(session_state: &HashMap<String, String>, key: &str) -> Option<T> {
+    session_state.get(key)
+        .and_then(|value| serde_json::from_str(value).ok())
+}
+
+// Sessions created before the metadata was recorded are identified by their hashed key
+fn legacy_session_id(session_key: &SessionKey) -> String {
+    let hash = Sha256::digest(session_key.as_ref().as_bytes());
+    hash.iter().take(8).map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn user_sessions_key(user_id: ObjectId) -> String {
+    format!("speer:sessions:{}", user_id.to_hex())
+}
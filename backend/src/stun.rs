@@ -0,0 +1,146 @@
+use std::net::{IpAddr, SocketAddr};
+use actix_web::rt::net::UdpSocket;
+
+use crate::utils::MapAndLog;
+
+// The message types and the attribute used from RFC 5389
+const BINDING_REQUEST: u16 = 0x0001;
+const BINDING_RESPONSE: u16 = 0x0101;
+const XOR_MAPPED_ADDRESS: u16 = 0x0020;
+const MAGIC_COOKIE: u32 = 0x2112_A442;
+const HEADER_LEN: usize = 20;
+// Large enough for any request a browser sends
+const BUFFER_LEN: usize = 1500;
+
+/// Answers the STUN binding requests received on the socket, so the clients can
+/// find out their public address. Every other message is ignored, there is no TURN.
+pub async fn run(socket: UdpSocket) {
+    let mut buffer = [0; BUFFER_LEN];
+
+    loop {
+        let Ok((len, peer)) = socket.recv_from(&mut buffer).await.log_and_map(()) else { continue };
+
+        if let Some(response) = binding_response(&buffer[..len], peer) {
+            socket.send_to(&response, peer).await.log_and_map(()).ok();
+        }
+    }
+}
+
+/// Creates the success response for a binding request, containing the address
+/// of the peer as a XOR-MAPPED-ADDRESS.
+fn binding_response(request: &[u8], peer: SocketAddr) -> Option<Vec<u8>> {
+    if request.len() < HEADER_LEN { return None }
+
+    let message_type = u16::from_be_bytes([request[0], request[1]]);
+    let length = u16::from_be_bytes([request[2], request[3]]) as usize;
+    let cookie = u32::from_be_bytes([request[4], request[5], request[6], request[7]]);
+    let transaction_id = &request[8..HEADER_LEN];
+
+    // The attributes are padded to 4 bytes, so the length has to be a multiple of it
+    let aligned = length.is_multiple_of(4);
+
+    if message_type != BINDING_REQUEST || cookie != MAGIC_COOKIE || !aligned || request.len() != HEADER_LEN + length {
+        return None;
+    }
+
+    // The port and the address are XOR-ed with the cookie (and the transaction id for IPv6)
+    let port = peer.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let (family, address) = match peer.ip().to_canonical() {
+        IpAddr::V4(ip) => (0x01u8, (u32::from(ip) ^ MAGIC_COOKIE).to_be_bytes().to_vec()),
+        IpAddr::V6(ip) => {
+            let mask = [&MAGIC_COOKIE.to_be_bytes()[..], transaction_id].concat();
+            (0x02, ip.octets().iter().zip(mask).map(|(byte, mask)| byte ^ mask).collect())
+        },
+    };
+
+    let mut attribute = vec![0, family];
+    attribute.extend_from_slice(&port.to_be_bytes());
+    attribute.extend_from_slice(&address);
+
+    let mut response = Vec::with_capacity(HEADER_LEN + 4 + attribute.len());
+    response.extend_from_slice(&BINDING_RESPONSE.to_be_bytes());
+    response.extend_from_slice(&(4 + attribute.len() as u16).to_be_bytes());
+    response.extend_from_slice(&MAGIC_COOKIE.to_be_bytes());
+    response.extend_from_slice(transaction_id);
+    response.extend_from_slice(&XOR_MAPPED_ADDRESS.to_be_bytes());
+    response.extend_from_slice(&(attribute.len() as u16).to_be_bytes());
+    response.extend_from_slice(&attribute);
+
+    Some(response)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The sample request of RFC 5769 (2.1), with its transaction id
+    const SAMPLE_REQUEST: &str = "
+        000100582112a442b7e7a701bc34d686fa87dfae
+        802200105354554e207465737420636c69656e74
+        002400046e0001ff
+        80290008932ff9b151263b36
+        000600096576746a3a68367659202020
+        000800149aeaa70cbfd8cb56781ef2b5b2d3f249c1b571a2
+        80280004e57a3bcf";
+    const SAMPLE_HEADER: &str = "2112a442b7e7a701bc34d686fa87dfae";
+
+    fn bytes(hex: &str) -> Vec<u8> {
+        let hex: String = hex.split_whitespace().collect();
+
+        (0..hex.len()).step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn answers_with_the_ipv4_address() {
+        let peer = "192.0.2.1:32853".parse().unwrap();
+        let response = binding_response(&bytes(SAMPLE_REQUEST), peer).unwrap();
+
+        // The XOR-MAPPED-ADDRESS of the sample IPv4 response of RFC 5769 (2.2)
+        let expected = bytes(&format!("0101000c {SAMPLE_HEADER} 00200008 0001a147 e112a643"));
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn answers_with_the_ipv6_address() {
+        let peer = "[2001:db8:1234:5678:11:2233:4455:6677]:32853".parse().unwrap();
+        let response = binding_response(&bytes(SAMPLE_REQUEST), peer).unwrap();
+
+        // The XOR-MAPPED-ADDRESS of the sample IPv6 response of RFC 5769 (2.3)
+        let expected = bytes(&format!("01010018 {SAMPLE_HEADER} 00200014 0002a147 0113a9faa5d3f179bc25f4b5bed2b9d9"));
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn answers_mapped_ipv4_addresses_as_ipv4() {
+        let peer = "[::ffff:192.0.2.1]:32853".parse().unwrap();
+        let request = bytes(&format!("00010000 {SAMPLE_HEADER}"));
+
+        let expected = bytes(&format!("0101000c {SAMPLE_HEADER} 00200008 0001a147 e112a643"));
+        assert_eq!(binding_response(&request, peer).unwrap(), expected);
+    }
+
+    #[test]
+    fn ignores_other_messages() {
+        let peer = "192.0.2.1:32853".parse().unwrap();
+        let request = bytes(SAMPLE_REQUEST);
+
+        // Too short
+        assert!(binding_response(&request[..HEADER_LEN - 1], peer).is_none());
+        // Truncated attributes
+        assert!(binding_response(&request[..request.len() - 4], peer).is_none());
+        // A binding response instead of a request
+        let mut response = request.clone();
+        response[1] = 0x01;
+        response[0] = 0x01;
+        assert!(binding_response(&response, peer).is_none());
+        // A wrong magic cookie (RFC 3489 style request)
+        let mut legacy = request.clone();
+        legacy[4] = 0;
+        assert!(binding_response(&legacy, peer).is_none());
+        // A length which is not a multiple of 4
+        let unaligned = bytes(&format!("00010002 {SAMPLE_HEADER} 0000"));
+        assert!(binding_response(&unaligned, peer).is_none());
+    }
+}
@@ -0,0 +1,109 @@
+use aes_gcm::{aead::{Aead, KeyInit}, Aes256Gcm, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use mongodb::bson::DateTime;
+use rand::RngCore;
+use reqwest::Url;
+use sha1::Sha1;
+use sha2::{Digest, Sha256};
+
+use crate::utils;
+
+// The parameters below are the defaults of every authenticator app (RFC 6238)
+const STEP_SECS: i64 = 30;
+const DIGITS: u32 = 6;
+const SECRET_LEN: usize = 20;
+const NONCE_LEN: usize = 12;
+const RECOVERY_CODE_COUNT: usize = 10;
+const RECOVERY_CODE_LEN: usize = 10;
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LEN];
+    rand::thread_rng().fill_bytes(&mut secret);
+
+    secret
+}
+
+pub fn encode_secret(secret: &[u8]) -> String {
+    BASE32_NOPAD.encode(secret)
+}
+
+/// Creates the `otpauth://` URI, which authenticator apps can import (usually from a QR code).
+pub fn provisioning_uri(secret: &[u8], email: &str) -> String {
+    let mut uri = Url::parse("otpauth://totp").unwrap();
+    uri.set_path(&format!("Speer:{email}"));
+    uri.query_pairs_mut()
+        .append_pair("secret", &encode_secret(secret))
+        .append_pair("issuer", "Speer")
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECS.to_string());
+
+    uri.to_string()
+}
+
+/// Checks the code against the current, the previous and the next time step.
+/// Returns the matching step, which has to be stored as `last_step`, so a
+/// code can not be used twice.
+pub fn verify_code(secret: &[u8], code: &str, last_step: i64) -> Option<i64> {
+    let code = code.trim();
+    let current_step = DateTime::now().timestamp_millis() / 1000 / STEP_SECS;
+
+    (current_step - 1..=current_step + 1)
+        .filter(|step| *step > last_step)
+        .find(|step| generate_code(secret, *step as u64) == code)
+}
+
+fn generate_code(secret: &[u8], counter: u64) -> String {
+    let mut mac = <Hmac<Sha1> as Mac>::new_from_slice(secret).unwrap();
+    mac.update(&counter.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let binary = u32::from_be_bytes([hash[offset], hash[offset + 1], hash[offset + 2], hash[offset + 3]]) & 0x7fff_ffff;
+
+    format!("{:0width$}", binary % 10_u32.pow(DIGITS), width = DIGITS as usize)
+}
+
+/// Encrypts the secret with AES-256-GCM, the result contains the nonce as well.
+pub fn encrypt_secret(secret: &[u8], key: &str) -> String {
+    let mut nonce = [0; NONCE_LEN];
+    rand::thread_rng().fill_bytes(&mut nonce);
+
+    let mut encrypted = nonce.to_vec();
+    encrypted.extend(cipher(key).encrypt(Nonce::from_slice(&nonce), secret).unwrap());
+
+    BASE64.encode(encrypted)
+}
+
+pub fn decrypt_secret(encrypted: &str, key: &str) -> Option<Vec<u8>> {
+    let encrypted = BASE64.decode(encrypted).ok()?;
+    if encrypted.len() < NONCE_LEN { return None }
+
+    let (nonce, ciphertext) = encrypted.split_at(NONCE_LEN);
+    cipher(key).decrypt(Nonce::from_slice(nonce), ciphertext).ok()
+}
+
+fn cipher(key: &str) -> Aes256Gcm {
+    let key = Sha256::digest(key.as_bytes());
+    Aes256Gcm::new(&key)
+}
+
+/// Returns the recovery codes to show to the user and their hashes to store.
+pub fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| utils::generate_random_string(RECOVERY_CODE_LEN))
+        .collect();
+    let hashes = codes.iter().map(|code| hash_recovery_code(code)).collect();
+
+    (codes, hashes)
+}
+
+/// The recovery codes are random enough, so a fast hash is sufficient for them.
+pub fn hash_recovery_code(code: &str) -> String {
+    let code = code.trim().to_lowercase();
+    let hash = Sha256::digest(code.as_bytes());
+
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -1,11 +1,13 @@
-use futures::future;
+use futures::{future, TryStreamExt};
 use log::error;
 use mongodb::{
-    bson::{doc, oid::ObjectId},
+    bson::{doc, oid::ObjectId, DateTime},
+    error::{ErrorKind, WriteFailure},
     Collection,
 };
 use rand::{distributions::Alphanumeric, Rng};
 use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::fs::File;
 use web_push::*;
 
@@ -20,6 +22,35 @@ pub fn generate_random_string(len: usize) -> String {
         .to_lowercase()
 }
 
+/// Tokens sent in emails are only stored hashed, a leaked database does not let anyone use them.
+pub fn hash_token(token: &str) -> String {
+    let hash = Sha256::digest(token.as_bytes());
+
+    hash.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+// The code MongoDB answers with, when a write violates a unique index
+const DUPLICATE_KEY_CODE: i32 = 11000;
+
+pub fn is_duplicate_key(err: &mongodb::error::Error) -> bool {
+    matches!(
+        err.kind.as_ref(),
+        ErrorKind::Write(WriteFailure::WriteError(write_error)) if write_error.code == DUPLICATE_KEY_CODE
+    )
+}
+
+/// Creates the smallest ObjectId generated at the given date, useful for
+/// filtering documents by their creation time.
+pub fn object_id_from_date(date: DateTime) -> ObjectId {
+    let secs = (date.timestamp_millis() / 1000) as u32;
+
+    let mut bytes = [0; 12];
+    bytes[..4].copy_from_slice(&secs.to_be_bytes());
+
+    ObjectId::from_bytes(bytes)
+}
+
+#[allow(clippy::needless_return)]
 pub fn get_file_extension(file: &awmp::File, fallback_str: &str) -> String {
     return file
         .original_file_name()
@@ -28,16 +59,52 @@ pub fn get_file_extension(file: &awmp::File, fallback_str: &str) -> String {
         .to_string();
 }
 
+/// Returns the friends of the user who can see their presence, leaving out the
+/// ones blocked by the user and the ones who blocked the user.
+pub async fn visible_friends(
+    users_coll: &Collection<User>,
+    user: &User,
+) -> Result<Vec<ObjectId>, mongodb::error::Error> {
+    let filter = doc! {"_id": {"$in": &user.friends}, "blocked": user._id};
+    let blocking: Vec<ObjectId> = users_coll
+        .find(filter, None)
+        .await?
+        .map_ok(|friend| friend._id)
+        .try_collect()
+        .await?;
+
+    let visible = user
+        .friends
+        .iter()
+        .filter(|id| !user.blocked.contains(id) && !blocking.contains(id))
+        .copied()
+        .collect();
+
+    Ok(visible)
+}
+
 pub async fn send_push_notifications(
     users_coll: &Collection<User>,
     user_id: ObjectId,
     devices: Vec<Device>,
     title: String,
     body: String,
+) -> bool {
+    send_push_notifications_with_url(users_coll, user_id, devices, title, body, None).await
+}
+
+/// Sends the notifications, which open the given path of the app when clicked.
+pub async fn send_push_notifications_with_url(
+    users_coll: &Collection<User>,
+    user_id: ObjectId,
+    devices: Vec<Device>,
+    title: String,
+    body: String,
+    url: Option<String>,
 ) -> bool {
     let futures = devices
         .iter()
-        .map(|device| send_push_notification(device.clone(), title.clone(), body.clone()));
+        .map(|device| send_push_notification(device.clone(), title.clone(), body.clone(), url.clone()));
     let results = future::join_all(futures).await;
 
     let devices_len = devices.len();
@@ -67,6 +134,7 @@ async fn send_push_notification(
     device: Device,
     title: String,
     body: String,
+    url: Option<String>,
 ) -> Result<(), WebPushError> {
     let file = File::open("vapid.pem")
         .log_and_map(WebPushError::Unspecified)?;
@@ -79,7 +147,7 @@ async fn send_push_notification(
 
     let sig_builder = VapidSignatureBuilder::from_pem(file, &subscription_info)?.build()?;
 
-    let content = json!({"title": title, "body": body}).to_string();
+    let content = json!({"title": title, "body": body, "url": url}).to_string();
     let content = content.as_bytes();
 
     let mut message_builder = WebPushMessageBuilder::new(&subscription_info);
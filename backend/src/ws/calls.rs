@@ -0,0 +1,266 @@
+use std::collections::HashMap;
+use mongodb::bson::oid::ObjectId;
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+/// What a signal means for the call between the two users. Plain `signal`s
+/// carry the WebRTC data, the others drive the state of the call.
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum SignalAction {
+    Signal,
+    Ring,
+    Accept,
+    Decline,
+    Hangup,
+}
+
+#[derive(Serialize, Deserialize, JsonSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+pub enum CallState {
+    Ringing,
+    Accepted,
+    InCall,
+    Ended,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Call {
+    pub id: String,
+    pub caller: ObjectId,
+    pub callee: ObjectId,
+    // The devices in the call, the callee's is known once it accepts
+    pub caller_device: String,
+    pub callee_device: Option<String>,
+    pub state: CallState,
+}
+
+impl Call {
+    pub fn other(&self, user: ObjectId) -> ObjectId {
+        if self.caller == user { self.callee } else { self.caller }
+    }
+
+    /// Whether the device of the user is the one in the call.
+    pub fn has_device(&self, user: ObjectId, device: &str) -> bool {
+        (self.caller == user && self.caller_device == device)
+            || (self.callee == user && self.callee_device.as_deref() == Some(device))
+    }
+
+    fn between(&self, user: ObjectId, remote: ObjectId) -> bool {
+        (self.caller == user && self.callee == remote) || (self.caller == remote && self.callee == user)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CallError {
+    /// One of the users is in another call already.
+    Busy,
+    /// There is no call between the users, which the action could apply to.
+    NoCall,
+}
+
+/// The calls in progress. Every instance keeps the whole table, the changes are
+/// shared through the cluster, so a user can be found busy on any instance.
+#[derive(Default)]
+pub struct Calls {
+    calls: HashMap<String, Call>,
+    by_user: HashMap<ObjectId, String>,
+}
+
+impl Calls {
+    pub fn of_user(&self, user: &ObjectId) -> Option<&Call> {
+        self.by_user.get(user).and_then(|id| self.calls.get(id))
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Call> {
+        self.calls.get(id)
+    }
+
+    pub fn all(&self) -> impl Iterator<Item = &Call> {
+        self.calls.values()
+    }
+
+    /// Stores the new state of the call, ended calls are forgotten.
+    pub fn update(&mut self, call: Call) {
+        if call.state == CallState::Ended {
+            if self.calls.remove(&call.id).is_some() {
+                self.by_user.remove(&call.caller);
+                self.by_user.remove(&call.callee);
+            }
+            return;
+        }
+
+        self.by_user.insert(call.caller, call.id.clone());
+        self.by_user.insert(call.callee, call.id.clone());
+        self.calls.insert(call.id.clone(), call);
+    }
+
+    /// Returns the call changed by the action the `device` of `user` sent to `remote`, without
+    /// storing it. `Ok(None)` means the action does not change any call.
+    pub fn transition(&self, user: ObjectId, device: &str, remote: ObjectId, action: SignalAction) -> Result<Option<Call>, CallError> {
+        let current = self.of_user(&user).filter(|call| call.between(user, remote));
+
+        match action {
+            SignalAction::Ring => {
+                if current.is_some_and(|call| call.caller == user && call.state == CallState::Ringing) {
+                    return Ok(None);
+                }
+                if self.of_user(&user).is_some() || self.of_user(&remote).is_some() {
+                    return Err(CallError::Busy);
+                }
+
+                Ok(Some(Call {
+                    id: utils::generate_random_string(16),
+                    caller: user,
+                    callee: remote,
+                    caller_device: device.to_string(),
+                    callee_device: None,
+                    state: CallState::Ringing,
+                }))
+            },
+            SignalAction::Accept => {
+                let call = current
+                    .filter(|call| call.callee == user && call.state == CallState::Ringing)
+                    .ok_or(CallError::NoCall)?;
+
+                Ok(Some(Call { callee_device: Some(device.to_string()), state: CallState::Accepted, ..call.clone() }))
+            },
+            SignalAction::Decline | SignalAction::Hangup => {
+                let call = current.ok_or(CallError::NoCall)?;
+
+                Ok(Some(Call { state: CallState::Ended, ..call.clone() }))
+            },
+            // The first signal of the devices in the call after accepting means the peers are connecting
+            SignalAction::Signal => match current {
+                Some(call) if call.state == CallState::Accepted && call.has_device(user, device) => {
+                    Ok(Some(Call { state: CallState::InCall, ..call.clone() }))
+                },
+                _ => Ok(None),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ring(calls: &mut Calls, caller: ObjectId, callee: ObjectId) -> Call {
+        let call = calls.transition(caller, "desk", callee, SignalAction::Ring).unwrap().unwrap();
+        calls.update(call.clone());
+        call
+    }
+
+    #[test]
+    fn ringing_starts_a_call() {
+        let (caller, callee) = (ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+
+        let call = ring(&mut calls, caller, callee);
+
+        assert_eq!(call.state, CallState::Ringing);
+        assert_eq!((call.caller, call.callee), (caller, callee));
+        assert_eq!(calls.of_user(&callee).map(|call| &call.id), Some(&call.id));
+        // Ringing again does not start another call
+        assert!(calls.transition(caller, "desk", callee, SignalAction::Ring).unwrap().is_none());
+    }
+
+    #[test]
+    fn users_in_a_call_are_busy() {
+        let (caller, callee, other) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+        ring(&mut calls, caller, callee);
+
+        assert_eq!(calls.transition(other, "tablet", callee, SignalAction::Ring).unwrap_err(), CallError::Busy);
+        assert_eq!(calls.transition(other, "tablet", caller, SignalAction::Ring).unwrap_err(), CallError::Busy);
+        assert_eq!(calls.transition(callee, "phone", caller, SignalAction::Ring).unwrap_err(), CallError::Busy);
+    }
+
+    #[test]
+    fn only_the_callee_can_accept() {
+        let (caller, callee) = (ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+        ring(&mut calls, caller, callee);
+
+        assert_eq!(calls.transition(caller, "desk", callee, SignalAction::Accept).unwrap_err(), CallError::NoCall);
+
+        let call = calls.transition(callee, "phone", caller, SignalAction::Accept).unwrap().unwrap();
+        assert_eq!(call.state, CallState::Accepted);
+        calls.update(call);
+
+        assert_eq!(calls.transition(callee, "phone", caller, SignalAction::Accept).unwrap_err(), CallError::NoCall);
+    }
+
+    #[test]
+    fn signals_only_change_accepted_calls() {
+        let (caller, callee) = (ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+
+        assert!(calls.transition(caller, "desk", callee, SignalAction::Signal).unwrap().is_none());
+
+        ring(&mut calls, caller, callee);
+        assert!(calls.transition(caller, "desk", callee, SignalAction::Signal).unwrap().is_none());
+
+        calls.update(calls.transition(callee, "phone", caller, SignalAction::Accept).unwrap().unwrap());
+        let call = calls.transition(caller, "desk", callee, SignalAction::Signal).unwrap().unwrap();
+        assert_eq!(call.state, CallState::InCall);
+        calls.update(call);
+
+        assert!(calls.transition(callee, "phone", caller, SignalAction::Signal).unwrap().is_none());
+    }
+
+    #[test]
+    fn declining_and_hanging_up_end_the_call() {
+        let (caller, callee) = (ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+
+        ring(&mut calls, caller, callee);
+        let call = calls.transition(callee, "phone", caller, SignalAction::Decline).unwrap().unwrap();
+        assert_eq!(call.state, CallState::Ended);
+        calls.update(call);
+        assert!(calls.of_user(&caller).is_none());
+        assert!(calls.of_user(&callee).is_none());
+
+        let call = ring(&mut calls, caller, callee);
+        calls.update(calls.transition(callee, "phone", caller, SignalAction::Accept).unwrap().unwrap());
+        calls.update(calls.transition(caller, "desk", callee, SignalAction::Hangup).unwrap().unwrap());
+        assert!(calls.get(&call.id).is_none());
+
+        assert_eq!(calls.transition(caller, "desk", callee, SignalAction::Hangup).unwrap_err(), CallError::NoCall);
+        assert_eq!(calls.transition(callee, "phone", caller, SignalAction::Decline).unwrap_err(), CallError::NoCall);
+    }
+
+    #[test]
+    fn actions_only_apply_to_the_call_between_the_users() {
+        let (caller, callee, other) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+        ring(&mut calls, caller, callee);
+
+        assert_eq!(calls.transition(callee, "phone", other, SignalAction::Accept).unwrap_err(), CallError::NoCall);
+        assert_eq!(calls.transition(other, "tablet", caller, SignalAction::Hangup).unwrap_err(), CallError::NoCall);
+        assert!(calls.of_user(&caller).is_some());
+    }
+
+    #[test]
+    fn the_call_knows_its_devices() {
+        let (caller, callee) = (ObjectId::new(), ObjectId::new());
+        let mut calls = Calls::default();
+
+        let call = ring(&mut calls, caller, callee);
+        assert!(call.has_device(caller, "desk"));
+        assert!(!call.has_device(caller, "laptop"));
+        assert!(!call.has_device(callee, "phone"));
+
+        let call = calls.transition(callee, "phone", caller, SignalAction::Accept).unwrap().unwrap();
+        assert!(call.has_device(callee, "phone"));
+        assert!(!call.has_device(callee, "laptop"));
+        calls.update(call);
+
+        // The other devices of the users do not connect the call
+        assert!(calls.transition(callee, "laptop", caller, SignalAction::Signal).unwrap().is_none());
+        assert!(calls.transition(caller, "laptop", callee, SignalAction::Signal).unwrap().is_none());
+        assert_eq!(calls.transition(callee, "phone", caller, SignalAction::Signal).unwrap().unwrap().state, CallState::InCall);
+    }
+}
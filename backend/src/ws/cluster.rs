@@ -0,0 +1,332 @@
+use std::{collections::{HashMap, HashSet}, future::Future, time::{Duration, Instant}};
+use futures::{channel::mpsc, stream::BoxStream, StreamExt};
+use log::{error, warn};
+use mongodb::bson::{oid::ObjectId, DateTime};
+use redis::{aio::ConnectionManager, AsyncCommands, Msg, RedisError};
+use serde::{Deserialize, Serialize};
+
+use crate::utils::{self, MapAndLog};
+use super::{Call, RelayRequest, Room};
+
+const CHANNEL: &str = "speer:ws";
+const INSTANCES_KEY: &str = "speer:instances";
+// An instance, which did not refresh its online users within this time, is considered dead
+pub const INSTANCE_TTL_SECS: i64 = 30;
+
+/// A message, which has to reach the connections held by other instances.
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Broadcast {
+    /// Sends a raw message to one device of the user, or to all of them.
+    Deliver { user: ObjectId, device: Option<String>, message: String },
+    /// Emits a pusher event to the users subscribed to it.
+    Event { event: String, data: serde_json::Value, ids: Vec<ObjectId> },
+    Kick { user: ObjectId, session_id: Option<String> },
+    FriendsChanged { ids: Vec<ObjectId> },
+    /// The new state of a call, so every instance knows who is busy.
+    Call { call: Call },
+    /// The new state of a group call, empty rooms are removed.
+    Room { room: Room },
+    /// A guest connected with the link of the owner, or left if `owner` is not set.
+    Guest { guest: ObjectId, owner: Option<ObjectId> },
+    /// A relay action, which has to reach the instance keeping the relay.
+    Relay { user: ObjectId, device: String, request: RelayRequest },
+    /// A device left, the relays it takes part in are closed by the instances keeping them.
+    Disconnected { user: ObjectId, device: String },
+    /// The friendship ended, the relays between the users are closed.
+    Unfriend { user: ObjectId, remote: ObjectId },
+    /// Sent by every instance periodically with the devices connected to it.
+    Heartbeat { devices: Vec<(ObjectId, String)> },
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Envelope {
+    instance: String,
+    broadcast: Broadcast,
+}
+
+/// Connects the signaling servers of all the backend instances through Redis.
+/// Broadcasts are fanned out with pub/sub, while each instance keeps the set of
+/// its online users in Redis, so the presence can be answered for the whole cluster.
+/// Every instance also listens on its own channel, for the messages meant only for it.
+pub struct Cluster {
+    instance: String,
+    // Not set in the tests, which run without Redis
+    client: Option<ConnectionManager>,
+    redis_url: Option<String>,
+    // Commands are run one after another, so the signals keep their order
+    commands: mpsc::UnboundedSender<redis::Pipeline>,
+}
+
+impl Cluster {
+    pub async fn new(redis_url: &str, client: ConnectionManager) -> Result<(Cluster, BoxStream<'static, Msg>), RedisError> {
+        let (commands, mut receiver) = mpsc::unbounded::<redis::Pipeline>();
+        let mut connection = client.clone();
+
+        actix_web::rt::spawn(async move {
+            while let Some(pipeline) = receiver.next().await {
+                pipeline.query_async::<_, ()>(&mut connection).await
+                    .log_and_map(())
+                    .ok();
+            }
+        });
+
+        let cluster = Cluster {
+            instance: utils::generate_random_string(16),
+            client: Some(client),
+            redis_url: Some(redis_url.to_string()),
+            commands,
+        };
+        let messages = cluster.subscribe().await?;
+
+        Ok((cluster, messages))
+    }
+
+    /// Opens a new pub/sub connection listening on the channels of this instance.
+    pub fn subscribe(&self) -> impl Future<Output = Result<BoxStream<'static, Msg>, RedisError>> {
+        let redis_url = self.redis_url.clone();
+        let channels = [CHANNEL.to_string(), instance_channel(&self.instance)];
+
+        async move {
+            let redis_url = redis_url.ok_or_else(|| RedisError::from((redis::ErrorKind::IoError, "Not connected to Redis")))?;
+            let mut pubsub = redis::Client::open(redis_url)?
+                .get_async_connection().await?
+                .into_pubsub();
+            pubsub.subscribe(&channels).await?;
+
+            Ok(pubsub.into_on_message().boxed())
+        }
+    }
+
+    /// An instance, which only knows about its own connections.
+    #[cfg(test)]
+    pub fn detached() -> Cluster {
+        let (commands, _) = mpsc::unbounded();
+
+        Cluster { instance: utils::generate_random_string(16), client: None, redis_url: None, commands }
+    }
+
+    pub fn instance(&self) -> &str {
+        &self.instance
+    }
+
+    pub fn publish(&self, broadcast: Broadcast) {
+        self.publish_on(CHANNEL, broadcast);
+    }
+
+    /// Publishes the broadcast to a single instance.
+    pub fn publish_to(&self, instance: &str, broadcast: Broadcast) {
+        self.publish_on(&instance_channel(instance), broadcast);
+    }
+
+    /// Parses a message received from the channels together with the instance which sent it.
+    /// The messages published by this instance are skipped.
+    pub fn receive(&self, msg: Msg) -> Option<(String, Broadcast)> {
+        let envelope: Envelope = msg.get_payload::<String>().ok()
+            .and_then(|payload| serde_json::from_str(&payload).ok())
+            .or_else(|| {
+                warn!("Malformed message on the {CHANNEL} channel");
+                None
+            })?;
+
+        (envelope.instance != self.instance).then_some((envelope.instance, envelope.broadcast))
+    }
+
+    pub fn set_online(&self, user: ObjectId, online: bool) {
+        let key = self.online_key();
+        let mut pipeline = redis::pipe();
+
+        if online {
+            pipeline.sadd(&key, user.to_hex()).ignore()
+                .expire(&key, INSTANCE_TTL_SECS).ignore();
+        }
+        else {
+            pipeline.srem(&key, user.to_hex()).ignore();
+        }
+
+        self.run(pipeline);
+    }
+
+    /// Replaces the online users of this instance and marks the instance alive.
+    pub fn refresh(&self, users: Vec<ObjectId>) {
+        let key = self.online_key();
+        let now = DateTime::now().timestamp_millis();
+        let mut pipeline = redis::pipe();
+
+        pipeline.atomic()
+            .del(&key).ignore()
+            .zadd(INSTANCES_KEY, &self.instance, now).ignore()
+            .zrembyscore(INSTANCES_KEY, "-inf", now - INSTANCE_TTL_SECS * 1000).ignore();
+
+        if !users.is_empty() {
+            let ids: Vec<String> = users.iter().map(|id| id.to_hex()).collect();
+            pipeline.sadd(&key, ids).ignore()
+                .expire(&key, INSTANCE_TTL_SECS).ignore();
+        }
+
+        self.run(pipeline);
+    }
+
+    /// Returns the users online on any of the instances.
+    pub fn online_users(&self) -> impl Future<Output = Result<Vec<ObjectId>, RedisError>> {
+        let client = self.connection();
+
+        async move {
+            let mut client = client?;
+            let keys = live_instances(&mut client).await?.iter()
+                .map(|instance| online_key(instance))
+                .collect::<Vec<String>>();
+            if keys.is_empty() { return Ok(vec![]) }
+
+            let ids: Vec<String> = client.sunion(keys).await?;
+
+            Ok(ids.iter().filter_map(|id| ObjectId::parse_str(id).ok()).collect())
+        }
+    }
+
+    /// Checks whether the user has a connection held by another instance.
+    pub fn online_elsewhere(&self, user: ObjectId) -> impl Future<Output = Result<bool, RedisError>> {
+        let client = self.connection();
+        let this_instance = self.instance.clone();
+
+        async move {
+            let mut client = client?;
+            for instance in live_instances(&mut client).await? {
+                if instance != this_instance && client.sismember(online_key(&instance), user.to_hex()).await? {
+                    return Ok(true);
+                }
+            }
+
+            Ok(false)
+        }
+    }
+
+    fn connection(&self) -> Result<ConnectionManager, RedisError> {
+        self.client.clone().ok_or_else(|| RedisError::from((redis::ErrorKind::IoError, "Not connected to Redis")))
+    }
+
+    fn online_key(&self) -> String {
+        online_key(&self.instance)
+    }
+
+    fn publish_on(&self, channel: &str, broadcast: Broadcast) {
+        let envelope = Envelope { instance: self.instance.clone(), broadcast };
+
+        match serde_json::to_string(&envelope) {
+            Ok(payload) => self.run(redis::pipe().publish(channel, payload).ignore().to_owned()),
+            Err(err) => error!("{err}"),
+        }
+    }
+
+    fn run(&self, pipeline: redis::Pipeline) {
+        self.commands.unbounded_send(pipeline).ok();
+    }
+}
+
+async fn live_instances(client: &mut ConnectionManager) -> Result<Vec<String>, RedisError> {
+    let cutoff = DateTime::now().timestamp_millis() - INSTANCE_TTL_SECS * 1000;
+
+    client.zrangebyscore(INSTANCES_KEY, cutoff, "+inf").await
+}
+
+fn online_key(instance: &str) -> String {
+    format!("speer:online:{instance}")
+}
+
+fn instance_channel(instance: &str) -> String {
+    format!("{CHANNEL}:{instance}")
+}
+
+struct Peer {
+    last_heartbeat: Instant,
+    devices: HashSet<(ObjectId, String)>,
+}
+
+/// The other instances and the devices connected to them, as told by their heartbeats.
+/// The calls, rooms and guests are kept by every instance, so each of them cleans up
+/// after an instance which stopped sending heartbeats (e.g. because it crashed).
+#[derive(Default)]
+pub struct Instances {
+    peers: HashMap<String, Peer>,
+}
+
+impl Instances {
+    pub fn heartbeat(&mut self, instance: String, devices: Vec<(ObjectId, String)>) {
+        let peer = Peer { last_heartbeat: Instant::now(), devices: devices.into_iter().collect() };
+        self.peers.insert(instance, peer);
+    }
+
+    /// Returns the instance the device is connected to, if it is known.
+    pub fn locate(&self, user: ObjectId, device: &str) -> Option<&str> {
+        let key = (user, device.to_string());
+
+        self.peers.iter()
+            .find(|(_, peer)| peer.devices.contains(&key))
+            .map(|(instance, _)| instance.as_str())
+    }
+
+    pub fn is_online(&self, user: &ObjectId) -> bool {
+        self.peers.values().any(|peer| peer.devices.iter().any(|(id, _)| id == user))
+    }
+
+    /// Forgets the instances without a heartbeat within the timeout. Returns their
+    /// devices, which are not connected to any of the remaining instances.
+    pub fn remove_dead(&mut self, timeout: Duration) -> Vec<(ObjectId, String)> {
+        let dead: Vec<String> = self.peers.iter()
+            .filter(|(_, peer)| peer.last_heartbeat.elapsed() > timeout)
+            .map(|(instance, _)| instance.clone())
+            .collect();
+
+        let devices: HashSet<(ObjectId, String)> = dead.iter()
+            .filter_map(|instance| self.peers.remove(instance))
+            .flat_map(|peer| peer.devices)
+            .collect();
+
+        devices.into_iter()
+            .filter(|device| !self.peers.values().any(|peer| peer.devices.contains(device)))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn device(user: ObjectId, device: &str) -> (ObjectId, String) {
+        (user, device.to_string())
+    }
+
+    #[test]
+    fn devices_are_located_by_the_last_heartbeat() {
+        let user = ObjectId::new();
+        let mut instances = Instances::default();
+
+        instances.heartbeat("a".to_string(), vec![device(user, "phone")]);
+        instances.heartbeat("b".to_string(), vec![device(user, "laptop")]);
+        assert_eq!(instances.locate(user, "phone"), Some("a"));
+        assert_eq!(instances.locate(user, "laptop"), Some("b"));
+        assert_eq!(instances.locate(user, "tablet"), None);
+
+        instances.heartbeat("a".to_string(), vec![]);
+        assert_eq!(instances.locate(user, "phone"), None);
+        assert!(instances.is_online(&user));
+    }
+
+    #[test]
+    fn only_silent_instances_are_removed() {
+        let (user, moved) = (ObjectId::new(), ObjectId::new());
+        let mut instances = Instances::default();
+
+        instances.heartbeat("a".to_string(), vec![device(user, "phone"), device(moved, "laptop")]);
+        assert!(instances.remove_dead(Duration::from_secs(30)).is_empty());
+
+        instances.peers.get_mut("a").unwrap().last_heartbeat -= Duration::from_secs(31);
+        // The heartbeat of the other instance can list a device, before the dead one is removed
+        instances.heartbeat("b".to_string(), vec![device(moved, "laptop")]);
+
+        assert_eq!(instances.remove_dead(Duration::from_secs(30)), vec![device(user, "phone")]);
+        assert_eq!(instances.locate(user, "phone"), None);
+        assert!(!instances.is_online(&user));
+        assert!(instances.is_online(&moved));
+    }
+}
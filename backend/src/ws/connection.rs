@@ -2,44 +2,28 @@ use std::{time::{Instant, Duration}};
 use actix::{Actor, StreamHandler, Running, Addr, AsyncContext, ActorContext, Handler};
 use actix_web_actors::ws;
 use mongodb::bson::oid::ObjectId;
-use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
 
-use crate::schemas::User;
+use crate::{schemas::{Presence, Status, User}, utils};
 use crate::ws::message;
 use crate::ws::server;
 
-use super::Signal;
+use super::{ClientMessage, ClientSignal, ErrorCode, PusherAction, RelayRequest, RoomRequest, ServerMessage, Signal, PROTOCOL_VERSION};
 
 
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(5);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
-
-#[derive(Deserialize)]
-#[serde(rename_all = "camelCase")]
-struct SingalMessage {
-    action: String,
-    peer_data: String,
-    remote_id: ObjectId,
-    r#type: String,
-    data: Option<String>
-}
-
-#[derive(Deserialize)]
-struct PusherMessage {
-    action: String,
-    event: String,
-}
-
-#[derive(Deserialize)]
-#[serde(untagged)]
-enum Message {
-    Signal(SingalMessage),
-    Pusher(PusherMessage),
-}
+const PRESENCE_TEXT_LENGTH: usize = 80;
 
 #[derive(Debug)]
 pub struct Connection {
     user: User,
+    device: String,
+    session_id: Option<String>,
+    // The owner of the guest link, guests can only signal them
+    guest_of: Option<ObjectId>,
+    // The protocol version agreed on in the handshake
+    version: Option<u32>,
     hb: Instant,
     server: Addr<server::Server>
 }
@@ -53,12 +37,17 @@ impl Actor for Connection {
         self.server.do_send(message::Connect {
             addr: ctx.address(),
             user: self.user.clone(),
+            device: self.device.clone(),
+            session_id: self.session_id.clone(),
+            guest_of: self.guest_of,
         })
     }
 
     fn stopping(&mut self, ctx: &mut Self::Context) -> Running {
         self.server.do_send(message::Disconnect {
+            addr: ctx.address(),
             _id: self.user._id,
+            device: self.device.clone(),
         });
 
         ctx.close(None);
@@ -78,13 +67,30 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for Connection {
             Ok(ws::Message::Pong(_)) => self.hb = Instant::now(),
             Ok(ws::Message::Text(text)) => {
                 match serde_json::from_str(&text) {
-                    Ok(Message::Signal(msg)) => self.handle_signal_msg(msg),
-                    Ok(Message::Pusher(msg)) => self.handle_pusher_msg(msg),
-                    _ => {}
+                    Ok(ClientMessage::Hello { version }) => self.handle_hello(version, ctx),
+                    Ok(_) if self.version.is_none() => {
+                        self.send_error(ErrorCode::HandshakeRequired, "The first message has to be a hello", ctx)
+                    },
+                    Ok(ClientMessage::Signal(msg)) => self.handle_signal_msg(msg),
+                    Ok(_) if self.guest_of.is_some() => {
+                        self.send_error(ErrorCode::GuestNotAllowed, "Guests can only signal the owner of the link", ctx)
+                    },
+                    Ok(ClientMessage::Pusher { action, event }) => self.handle_pusher_msg(action, event),
+                    Ok(ClientMessage::Presence { status, text }) => self.handle_presence_msg(status, text),
+                    Ok(ClientMessage::Room(request)) => self.handle_room_msg(request),
+                    Ok(ClientMessage::Relay(request)) => self.handle_relay_msg(request),
+                    Err(err) => self.send_error(ErrorCode::InvalidMessage, err.to_string(), ctx),
                 }
             },
+            Ok(ws::Message::Binary(_) | ws::Message::Continuation(_)) => {
+                self.send_error(ErrorCode::Protocol, "Only text frames are supported", ctx)
+            },
             Ok(ws::Message::Close(_)) => ctx.stop(),
-            _ => (),
+            Ok(ws::Message::Nop) => (),
+            Err(err) => {
+                self.send_error(ErrorCode::Protocol, err.to_string(), ctx);
+                ctx.stop();
+            },
         }
     }
 }
@@ -105,15 +111,41 @@ impl Handler<message::Terminate> for Connection {
     }
 }
 
+impl Handler<message::Kick> for Connection {
+    type Result = ();
+
+    fn handle(&mut self, msg: message::Kick, ctx: &mut Self::Context) {
+        if msg.session_id.is_none() || msg.session_id == self.session_id {
+            ctx.terminate();
+        }
+    }
+}
+
 impl Connection {
-    pub fn new(user: User, server: Addr<server::Server>) -> Connection {
+    pub fn new(user: User, session_id: Option<String>, server: Addr<server::Server>) -> Connection {
         Connection {
             hb: Instant::now(),
             user,
+            device: utils::generate_random_string(16),
+            session_id,
+            guest_of: None,
+            version: None,
             server
         }
     }
 
+    pub fn guest(user: User, owner: ObjectId, server: Addr<server::Server>) -> Connection {
+        Connection {
+            guest_of: Some(owner),
+            ..Connection::new(user, None, server)
+        }
+    }
+
+    #[cfg(test)]
+    pub fn device(&self) -> &str {
+        &self.device
+    }
+
     fn hb(&self, ctx: &mut ws::WebsocketContext<Self>) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
@@ -126,32 +158,80 @@ impl Connection {
         });
     }
 
-    fn handle_pusher_msg(&self, msg: PusherMessage) {
-        match msg.action.as_str() {
-            "subscribe" => {
+    fn send_error(&self, code: ErrorCode, message: impl Into<String>, ctx: &mut ws::WebsocketContext<Self>) {
+        ctx.text(ServerMessage::error(code, message).to_text());
+    }
+
+    fn handle_hello(&mut self, version: u32, ctx: &mut ws::WebsocketContext<Self>) {
+        if version != PROTOCOL_VERSION {
+            let message = format!("The server speaks version {PROTOCOL_VERSION}");
+            self.send_error(ErrorCode::UnsupportedVersion, message, ctx);
+            ctx.close(None);
+            return ctx.stop();
+        }
+
+        self.version = Some(version);
+
+        let welcome = ServerMessage::Welcome { version, device: self.device.clone() };
+        ctx.text(welcome.to_text());
+    }
+
+    fn handle_pusher_msg(&self, action: PusherAction, event: String) {
+        match action {
+            PusherAction::Subscribe => {
                 self.server.do_send(message::Subscribe{
-                    event: msg.event,
-                    _id: self.user._id
+                    event,
+                    _id: self.user._id,
+                    device: self.device.clone(),
                 })
             },
-            "unsubscribe" => {
+            PusherAction::Unsubscribe => {
                 self.server.do_send(message::Unsubscribe{
-                    event: msg.event,
-                    _id: self.user._id
+                    event,
+                    _id: self.user._id,
+                    device: self.device.clone(),
                 })
             },
-            _ => {}
         }
     }
 
-    fn handle_signal_msg(&self, msg: SingalMessage) {
+    fn handle_presence_msg(&self, status: Status, text: Option<String>) {
+        let text = text
+            .map(|text| text.trim().graphemes(true).take(PRESENCE_TEXT_LENGTH).collect::<String>())
+            .filter(|text| !text.is_empty());
+
+        self.server.do_send(message::SetPresence {
+            _id: self.user._id,
+            presence: Presence { status, text },
+        });
+    }
+
+    fn handle_signal_msg(&self, msg: ClientSignal) {
         self.server.do_send(Signal {
             _id: self.user._id,
+            device: self.device.clone(),
             action: msg.action,
             peer_data: msg.peer_data,
             remote_id: msg.remote_id,
+            remote_device: msg.remote_device,
             r#type: msg.r#type,
             data: msg.data,
         });
     }
+
+    fn handle_room_msg(&self, request: RoomRequest) {
+        self.server.do_send(message::RoomAction {
+            _id: self.user._id,
+            device: self.device.clone(),
+            request,
+        });
+    }
+
+    fn handle_relay_msg(&self, request: RelayRequest) {
+        self.server.do_send(message::RelayAction {
+            _id: self.user._id,
+            device: self.device.clone(),
+            request,
+        });
+    }
 }
\ No newline at end of file
@@ -0,0 +1,155 @@
+use std::collections::{HashMap, HashSet};
+use mongodb::bson::oid::ObjectId;
+
+enum Entry {
+    // The number of the load, so a load invalidated meanwhile is not stored
+    Loading(u64),
+    Loaded(HashSet<ObjectId>),
+}
+
+/// The users each connected user is allowed to signal, which are the friends not blocked
+/// in either direction. The friends are loaded from the database when the user connects,
+/// and again whenever they change. Until a load is stored, the callers ask the database.
+#[derive(Default)]
+pub struct Friends {
+    entries: HashMap<ObjectId, Entry>,
+    loads: u64,
+}
+
+impl Friends {
+    /// Starts a new load of the friends of the user, the friends cached so far are dropped.
+    /// Returns the number of the load, the result has to be stored with it.
+    pub fn start_load(&mut self, user: ObjectId) -> u64 {
+        self.loads += 1;
+        self.entries.insert(user, Entry::Loading(self.loads));
+
+        self.loads
+    }
+
+    /// Stores the loaded friends, unless another load was started or the user left meanwhile.
+    /// A failed load is dropped, so the friends are not cached until the next one.
+    pub fn finish_load(&mut self, user: ObjectId, load: u64, friends: Option<&[ObjectId]>) {
+        let current = matches!(self.entries.get(&user), Some(Entry::Loading(current)) if *current == load);
+        if !current { return }
+
+        match friends {
+            Some(friends) => self.entries.insert(user, Entry::Loaded(friends.iter().copied().collect())),
+            None => self.entries.remove(&user),
+        };
+    }
+
+    /// Whether the friends of the user are kept, either loaded or being loaded.
+    pub fn is_kept(&self, user: &ObjectId) -> bool {
+        self.entries.contains_key(user)
+    }
+
+    /// The friends of the user, if they are loaded.
+    pub fn get(&self, user: &ObjectId) -> Option<&HashSet<ObjectId>> {
+        match self.entries.get(user) {
+            Some(Entry::Loaded(friends)) => Some(friends),
+            _ => None,
+        }
+    }
+
+    /// Whether the remote user is a friend of the user, if the friends are loaded.
+    pub fn is_friend(&self, user: &ObjectId, remote: &ObjectId) -> Option<bool> {
+        self.get(user).map(|friends| friends.contains(remote))
+    }
+
+    /// Forgets the friends of the user, who left. Returns them, if they were loaded.
+    pub fn remove(&mut self, user: &ObjectId) -> Option<HashSet<ObjectId>> {
+        match self.entries.remove(user) {
+            Some(Entry::Loaded(friends)) => Some(friends),
+            _ => None,
+        }
+    }
+
+    /// Stands in for a load, which can not be done without the database.
+    #[cfg(test)]
+    pub fn set(&mut self, user: ObjectId, friends: Vec<ObjectId>) {
+        let load = self.start_load(user);
+        self.finish_load(user, load, Some(&friends));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loaded_friends_are_kept() {
+        let mut friends = Friends::default();
+        let (user, friend) = (ObjectId::new(), ObjectId::new());
+
+        let load = friends.start_load(user);
+        assert!(friends.is_kept(&user));
+        assert_eq!(friends.is_friend(&user, &friend), None);
+
+        friends.finish_load(user, load, Some(&[friend]));
+        assert_eq!(friends.is_friend(&user, &friend), Some(true));
+        assert_eq!(friends.is_friend(&user, &ObjectId::new()), Some(false));
+    }
+
+    #[test]
+    fn a_stale_load_is_discarded() {
+        let mut friends = Friends::default();
+        let (user, old_friend, new_friend) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+
+        let stale = friends.start_load(user);
+        let load = friends.start_load(user);
+
+        // The first load finishes last, it read the friends before they changed
+        friends.finish_load(user, load, Some(&[new_friend]));
+        friends.finish_load(user, stale, Some(&[old_friend]));
+
+        assert_eq!(friends.is_friend(&user, &new_friend), Some(true));
+        assert_eq!(friends.is_friend(&user, &old_friend), Some(false));
+    }
+
+    #[test]
+    fn a_stale_load_does_not_fill_the_new_one() {
+        let mut friends = Friends::default();
+        let (user, friend) = (ObjectId::new(), ObjectId::new());
+
+        let stale = friends.start_load(user);
+        friends.start_load(user);
+        friends.finish_load(user, stale, Some(&[friend]));
+
+        assert_eq!(friends.is_friend(&user, &friend), None);
+    }
+
+    #[test]
+    fn a_new_load_invalidates_the_friends() {
+        let mut friends = Friends::default();
+        let (user, friend) = (ObjectId::new(), ObjectId::new());
+        friends.set(user, vec![friend]);
+
+        // Friending, unfriending and blocking all start a new load
+        friends.start_load(user);
+
+        assert_eq!(friends.is_friend(&user, &friend), None);
+    }
+
+    #[test]
+    fn a_failed_load_is_not_cached() {
+        let mut friends = Friends::default();
+        let user = ObjectId::new();
+
+        let load = friends.start_load(user);
+        friends.finish_load(user, load, None);
+
+        assert!(!friends.is_kept(&user));
+    }
+
+    #[test]
+    fn a_load_finishing_after_the_user_left_is_discarded() {
+        let mut friends = Friends::default();
+        let (user, friend) = (ObjectId::new(), ObjectId::new());
+
+        let load = friends.start_load(user);
+        assert_eq!(friends.remove(&user), None);
+        friends.finish_load(user, load, Some(&[friend]));
+
+        assert!(!friends.is_kept(&user));
+    }
+}
@@ -1,29 +1,44 @@
 use actix::{prelude::Message, Addr};
 use mongodb::bson::{Document, oid::ObjectId};
-use crate::schemas::User;
-use super::Connection;
+use crate::schemas::{Presence, User};
+use super::{Connection, RelayRequest, RoomRequest, SignalAction};
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Connect {
     pub addr: Addr<Connection>,
     pub user: User,
+    pub device: String,
+    pub session_id: Option<String>,
+    // Set for guests, to the owner of their link
+    pub guest_of: Option<ObjectId>,
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Disconnect {
+    pub addr: Addr<Connection>,
     pub _id: ObjectId,
+    pub device: String,
 }
 
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Terminate;
 
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Kick {
+    pub _id: ObjectId,
+    // Only the connection of this session is closed when set
+    pub session_id: Option<String>,
+}
+
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Subscribe {
     pub _id: ObjectId,
+    pub device: String,
     pub event: String,
 }
 
@@ -31,6 +46,7 @@ pub struct Subscribe {
 #[rtype(result = "()")]
 pub struct Unsubscribe {
     pub _id: ObjectId,
+    pub device: String,
     pub event: String,
 }
 
@@ -38,13 +54,53 @@ pub struct Unsubscribe {
 #[rtype(result = "()")]
 pub struct Signal {
     pub _id: ObjectId,
-    pub action: String,
+    pub device: String,
+    pub action: SignalAction,
     pub peer_data: String,
     pub remote_id: ObjectId,
+    // The signal is sent to every device of the remote user when not set
+    pub remote_device: Option<String>,
     pub r#type: String,
     pub data: Option<String>,
 }
 
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct RoomAction {
+    pub _id: ObjectId,
+    pub device: String,
+    pub request: RoomRequest,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct RelayAction {
+    pub _id: ObjectId,
+    pub device: String,
+    pub request: RelayRequest,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct Unfriend {
+    pub _id: ObjectId,
+    pub remote_id: ObjectId,
+}
+
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct SetPresence {
+    pub _id: ObjectId,
+    pub presence: Presence,
+}
+
+/// Tells the server to reload the cached friends of the users.
+#[derive(Message, Debug)]
+#[rtype(result = "()")]
+pub struct FriendsChanged {
+    pub ids: Vec<ObjectId>,
+}
+
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Send(pub String);
@@ -53,6 +109,12 @@ pub struct Send(pub String);
 #[rtype(result = "Option<Vec<ObjectId>>")]
 pub struct ConnectedIds;
 
+#[derive(Message, Debug)]
+#[rtype(result = "Vec<String>")]
+pub struct ConnectedSessions {
+    pub _id: ObjectId,
+}
+
 #[derive(Message, Debug)]
 #[rtype(result = "()")]
 pub struct Dispatch {
@@ -1,9 +1,23 @@
+mod calls;
+mod cluster;
 mod connection;
+mod friends;
 mod message;
+mod protocol;
+mod registry;
+mod relays;
+mod rooms;
 mod server;
 mod route;
 
+pub use calls::*;
+pub use cluster::*;
 pub use connection::*;
+pub use friends::*;
 pub use message::*;
+pub use protocol::*;
+pub use registry::*;
+pub use relays::*;
+pub use rooms::*;
 pub use server::*;
 pub use route::*;
\ No newline at end of file
@@ -0,0 +1,316 @@
+use mongodb::bson::oid::ObjectId;
+use schemars::{schema::RootSchema, schema_for, JsonSchema};
+use serde::{Deserialize, Serialize};
+
+use crate::schemas::Status;
+use super::SignalAction;
+
+/// The version of the protocol spoken by the server. Clients announce the
+/// version they speak in their `hello` message, right after connecting.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// The messages clients can send over the WebSocket.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "msgType", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ClientMessage {
+    /// Has to be the first message of the connection.
+    Hello { version: u32 },
+    Signal(ClientSignal),
+    Pusher { action: PusherAction, event: String },
+    /// Sets the status shown to the friends, the text is optional.
+    Presence { status: Status, #[serde(default)] text: Option<String> },
+    Room(RoomRequest),
+    Relay(RelayRequest),
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct ClientSignal {
+    pub action: SignalAction,
+    pub peer_data: String,
+    #[schemars(with = "String")]
+    pub remote_id: ObjectId,
+    /// The device of the remote user to signal, every device is signaled if not set.
+    #[serde(default)]
+    pub remote_device: Option<String>,
+    pub r#type: String,
+    #[serde(default)]
+    pub data: Option<String>,
+}
+
+/// The actions on group calls. Only the host can invite, kick and lock.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "action", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum RoomRequest {
+    Create,
+    /// Invites a friend of the host, who can join the room afterwards.
+    Invite { room_id: String, #[schemars(with = "String")] user_id: ObjectId },
+    Join { room_id: String },
+    Leave { room_id: String },
+    Kick { room_id: String, #[schemars(with = "String")] user_id: ObjectId },
+    /// Nobody can join a locked room, not even the invited users.
+    Lock { room_id: String, locked: bool },
+    /// Relays WebRTC data to another member of the room.
+    Signal { room_id: String, #[schemars(with = "String")] remote_id: ObjectId, peer_data: String },
+}
+
+/// The file transfers relayed through the server, when the peers can not connect directly.
+/// The data of the chunks is base64, it is passed on as it is and should be encrypted by the clients.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "action", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum RelayRequest {
+    /// Offers a transfer to a friend. The size is the total number of bytes in the
+    /// chunks, it is counted against the quota of the sender.
+    Open { #[schemars(with = "String")] remote_id: ObjectId, size: u64 },
+    Accept { relay_id: String },
+    Chunk { relay_id: String, data: String },
+    /// Sent by the receiver for every chunk, the sender can only have a few chunks unacknowledged.
+    Ack { relay_id: String },
+    Close { relay_id: String },
+}
+
+impl RelayRequest {
+    pub fn relay_id(&self) -> Option<&str> {
+        match self {
+            RelayRequest::Open { .. } => None,
+            RelayRequest::Accept { relay_id }
+            | RelayRequest::Chunk { relay_id, .. }
+            | RelayRequest::Ack { relay_id }
+            | RelayRequest::Close { relay_id } => Some(relay_id),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub enum PusherAction {
+    Subscribe,
+    Unsubscribe,
+}
+
+/// The messages the server sends over the WebSocket.
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "msgType", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum ServerMessage {
+    /// The answer to the `hello` of the client.
+    Welcome { version: u32, device: String },
+    Signal(ServerSignal),
+    Pusher { event: String, data: serde_json::Value },
+    /// A change of the call with the remote user, which was not caused by a signal of the remote user.
+    Call { event: CallEvent, call_id: Option<String>, remote_id: String },
+    Room(RoomEvent),
+    Relay(RelayEvent),
+    Error { code: ErrorCode, message: String },
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum CallEvent {
+    /// The remote user is in another call, the ring was rejected.
+    Busy,
+    /// The remote user did not answer in time.
+    Timeout,
+    /// The call of the remote user was not answered in time.
+    Missed,
+    /// The remote user left, the call is over.
+    Ended,
+    /// The remote user is offline, they were notified about the missed call.
+    Offline,
+    /// The call of the remote user was accepted or declined on one of the devices of the user,
+    /// the others stop ringing.
+    Answered,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "event", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum RoomEvent {
+    /// The host invited the user to join the room.
+    Invite { room_id: String, host_id: String },
+    /// The members of the room, sent to all of them whenever the room changes.
+    /// Every newly joined member connects to each of the members listed before.
+    Roster { room_id: String, host_id: String, members: Vec<RoomMember>, locked: bool },
+    Signal { room_id: String, remote_id: String, remote_device: String, peer_data: String },
+    /// The host removed the user from the room.
+    Kicked { room_id: String },
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(tag = "event", rename_all = "camelCase", rename_all_fields = "camelCase")]
+pub enum RelayEvent {
+    /// The transfer was offered to the remote user, sent to the sender.
+    Opened { relay_id: String, remote_id: String },
+    /// A friend offers a transfer, sent to every device of the receiver.
+    Request { relay_id: String, remote_id: String, size: u64 },
+    Accepted { relay_id: String },
+    Chunk { relay_id: String, data: String },
+    Ack { relay_id: String },
+    /// The other party closed the transfer, left or it was idle for too long.
+    Closed { relay_id: String },
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct RoomMember {
+    pub user_id: String,
+    pub device: String,
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug)]
+#[serde(untagged, rename_all_fields = "camelCase")]
+pub enum ServerSignal {
+    Data {
+        action: SignalAction,
+        peer_data: String,
+        remote_id: String,
+        remote_device: String,
+        r#type: String,
+        data: Option<String>,
+    },
+    /// The signal could not be delivered to the remote user.
+    Error {
+        error: String,
+        remote_id: String,
+    },
+}
+
+#[derive(Deserialize, Serialize, JsonSchema, Debug, Clone, Copy)]
+#[serde(rename_all = "camelCase")]
+pub enum ErrorCode {
+    /// The frame is not a valid client message.
+    InvalidMessage,
+    /// The frame is not a text frame, the messages are JSON text only.
+    Protocol,
+    /// A message was sent before the `hello`.
+    HandshakeRequired,
+    /// The server does not speak the version of the client, the connection is closed.
+    UnsupportedVersion,
+    /// The call action does not apply to any call with the remote user.
+    NoCall,
+    /// The room action was rejected, the message tells why.
+    Room,
+    /// Guests can only send signals.
+    GuestNotAllowed,
+    /// The relay action was rejected, the message tells why.
+    Relay,
+}
+
+impl ServerMessage {
+    pub fn error(code: ErrorCode, message: impl Into<String>) -> ServerMessage {
+        ServerMessage::Error { code, message: message.into() }
+    }
+
+    pub fn to_text(&self) -> String {
+        // Serializing the messages can not fail, they contain string keys only
+        serde_json::to_string(self).unwrap()
+    }
+}
+
+#[derive(Serialize)]
+pub struct ProtocolSchema {
+    version: u32,
+    client: RootSchema,
+    server: RootSchema,
+}
+
+/// The JSON schema of the protocol, for generating the bindings of other clients.
+pub fn schema() -> ProtocolSchema {
+    ProtocolSchema {
+        version: PROTOCOL_VERSION,
+        client: schema_for!(ClientMessage),
+        server: schema_for!(ServerMessage),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+    use super::*;
+
+    // The ids are written as hex strings by the clients, and as extended JSON by serde
+    fn round_trip<T: Serialize + for<'de> Deserialize<'de>>(message: Value) -> Value {
+        let first = serde_json::to_value(serde_json::from_value::<T>(message).unwrap()).unwrap();
+        let second = serde_json::to_value(serde_json::from_value::<T>(first.clone()).unwrap()).unwrap();
+
+        assert_eq!(first, second);
+        first
+    }
+
+    #[test]
+    fn client_messages_survive_a_round_trip() {
+        let id = ObjectId::new().to_hex();
+        let messages = [
+            json!({"msgType": "hello", "version": 1}),
+            json!({"msgType": "signal", "action": "ring", "peerData": "offer", "remoteId": id, "remoteDevice": "phone", "type": "call", "data": null}),
+            json!({"msgType": "pusher", "action": "subscribe", "event": "login"}),
+            json!({"msgType": "presence", "status": "doNotDisturb", "text": "Busy"}),
+            json!({"msgType": "room", "action": "create"}),
+            json!({"msgType": "room", "action": "invite", "roomId": "room", "userId": id}),
+            json!({"msgType": "room", "action": "join", "roomId": "room"}),
+            json!({"msgType": "room", "action": "leave", "roomId": "room"}),
+            json!({"msgType": "room", "action": "kick", "roomId": "room", "userId": id}),
+            json!({"msgType": "room", "action": "lock", "roomId": "room", "locked": true}),
+            json!({"msgType": "room", "action": "signal", "roomId": "room", "remoteId": id, "peerData": "offer"}),
+            json!({"msgType": "relay", "action": "open", "remoteId": id, "size": 10}),
+            json!({"msgType": "relay", "action": "accept", "relayId": "relay"}),
+            json!({"msgType": "relay", "action": "chunk", "relayId": "relay", "data": "AAAA"}),
+            json!({"msgType": "relay", "action": "ack", "relayId": "relay"}),
+            json!({"msgType": "relay", "action": "close", "relayId": "relay"}),
+        ];
+
+        for message in messages {
+            let result = round_trip::<ClientMessage>(message.clone());
+
+            assert_eq!(result["msgType"], message["msgType"]);
+            assert_eq!(result["action"], message["action"]);
+        }
+    }
+
+    #[test]
+    fn optional_fields_of_client_messages_can_be_left_out() {
+        let message = json!({"msgType": "signal", "action": "signal", "peerData": "offer", "remoteId": ObjectId::new().to_hex(), "type": "data"});
+        let Ok(ClientMessage::Signal(signal)) = serde_json::from_value(message) else { panic!("Not a signal") };
+        assert_eq!((signal.remote_device, signal.data), (None, None));
+
+        let message = json!({"msgType": "presence", "status": "away"});
+        let Ok(ClientMessage::Presence { text, .. }) = serde_json::from_value(message) else { panic!("Not a presence") };
+        assert_eq!(text, None);
+    }
+
+    #[test]
+    fn server_messages_survive_a_round_trip() {
+        let messages = [
+            json!({"msgType": "welcome", "version": 1, "device": "phone"}),
+            json!({"msgType": "signal", "action": "signal", "peerData": "offer", "remoteId": "user", "remoteDevice": "phone", "type": "data", "data": null}),
+            json!({"msgType": "signal", "error": "Not friend", "remoteId": "user"}),
+            json!({"msgType": "pusher", "event": "login", "data": {"_id": "user"}}),
+            json!({"msgType": "call", "event": "answered", "callId": "call", "remoteId": "user"}),
+            json!({"msgType": "room", "event": "invite", "roomId": "room", "hostId": "user"}),
+            json!({"msgType": "room", "event": "roster", "roomId": "room", "hostId": "user", "members": [{"userId": "user", "device": "phone"}], "locked": false}),
+            json!({"msgType": "room", "event": "signal", "roomId": "room", "remoteId": "user", "remoteDevice": "phone", "peerData": "offer"}),
+            json!({"msgType": "room", "event": "kicked", "roomId": "room"}),
+            json!({"msgType": "relay", "event": "opened", "relayId": "relay", "remoteId": "user"}),
+            json!({"msgType": "relay", "event": "request", "relayId": "relay", "remoteId": "user", "size": 10}),
+            json!({"msgType": "relay", "event": "accepted", "relayId": "relay"}),
+            json!({"msgType": "relay", "event": "chunk", "relayId": "relay", "data": "AAAA"}),
+            json!({"msgType": "relay", "event": "ack", "relayId": "relay"}),
+            json!({"msgType": "relay", "event": "closed", "relayId": "relay"}),
+            json!({"msgType": "error", "code": "protocol", "message": "Only text frames are supported"}),
+        ];
+
+        // The server messages hold no ids, so they are written exactly as the clients expect them
+        for message in messages {
+            assert_eq!(round_trip::<ServerMessage>(message.clone()), message);
+        }
+    }
+
+    #[test]
+    fn errors_are_written_with_their_code() {
+        let text = ServerMessage::error(ErrorCode::UnsupportedVersion, "The server speaks version 1").to_text();
+
+        assert_eq!(serde_json::from_str::<Value>(&text).unwrap(), json!({
+            "msgType": "error",
+            "code": "unsupportedVersion",
+            "message": "The server speaks version 1",
+        }));
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::{HashMap, HashSet};
+use actix::Addr;
+use mongodb::bson::oid::ObjectId;
+
+use super::Connection;
+
+struct Device {
+    addr: Addr<Connection>,
+    session_id: Option<String>,
+}
+
+/// The connections held by this instance and their event subscriptions.
+/// It is owned by the `Server` actor and only changed through `&mut self`,
+/// so every update is applied, even while futures of the server are running.
+#[derive(Default)]
+pub struct Registry {
+    // The devices of each user, keyed by the device id of the connection
+    connections: HashMap<ObjectId, HashMap<String, Device>>,
+    // The subscribed devices of each user per event
+    events: HashMap<String, HashMap<ObjectId, HashSet<String>>>,
+}
+
+impl Registry {
+    /// Adds the connection of the device. Returns true if it is the first device of the user.
+    pub fn connect(&mut self, user: ObjectId, device: String, addr: Addr<Connection>, session_id: Option<String>) -> bool {
+        let devices = self.connections.entry(user).or_default();
+        let first = devices.is_empty();
+
+        devices.insert(device, Device { addr, session_id });
+
+        first
+    }
+
+    /// Removes the device together with its subscriptions. Returns true if it was the last device of the user.
+    pub fn disconnect(&mut self, user: ObjectId, device: &str) -> bool {
+        for subscribed in self.events.values_mut() {
+            remove_device(subscribed, user, device);
+        }
+
+        let Some(devices) = self.connections.get_mut(&user) else { return false };
+        if devices.remove(device).is_none() {
+            return false;
+        }
+
+        let last = devices.is_empty();
+        if last {
+            self.connections.remove(&user);
+        }
+
+        last
+    }
+
+    /// Subscribes a connected device to the event. Returns false if the device is not connected.
+    pub fn subscribe(&mut self, user: ObjectId, device: String, event: String) -> bool {
+        let connected = self.connections.get(&user)
+            .is_some_and(|devices| devices.contains_key(&device));
+
+        if connected {
+            self.events.entry(event).or_default()
+                .entry(user).or_default()
+                .insert(device);
+        }
+
+        connected
+    }
+
+    pub fn unsubscribe(&mut self, user: ObjectId, device: &str, event: &str) {
+        if let Some(subscribed) = self.events.get_mut(event) {
+            remove_device(subscribed, user, device);
+
+            if subscribed.is_empty() {
+                self.events.remove(event);
+            }
+        }
+    }
+
+    pub fn is_online(&self, user: &ObjectId) -> bool {
+        self.connections.contains_key(user)
+    }
+
+    pub fn users(&self) -> Vec<ObjectId> {
+        self.connections.keys().copied().collect()
+    }
+
+    /// Returns every connected device with its user.
+    pub fn all_devices(&self) -> Vec<(ObjectId, String)> {
+        self.connections.iter()
+            .flat_map(|(user, devices)| devices.keys().map(|device| (*user, device.clone())))
+            .collect()
+    }
+
+    /// Returns a single device of the user, or all of them if `device` is `None`.
+    pub fn devices<'a>(&'a self, user: &ObjectId, device: Option<&'a str>) -> impl Iterator<Item = &'a Addr<Connection>> + 'a {
+        self.connections.get(user)
+            .into_iter()
+            .flat_map(move |devices| {
+                devices.iter()
+                    .filter(move |(id, _)| match device {
+                        Some(device) => device == id.as_str(),
+                        None => true,
+                    })
+                    .map(|(_, device)| &device.addr)
+            })
+    }
+
+    /// Checks whether the device is connected through the given connection. A device
+    /// reconnecting replaces its connection, and the old one has to be left alone.
+    pub fn is_connection(&self, user: &ObjectId, device: &str, addr: &Addr<Connection>) -> bool {
+        self.connections.get(user)
+            .and_then(|devices| devices.get(device))
+            .is_some_and(|connected| &connected.addr == addr)
+    }
+
+    pub fn has_device(&self, user: &ObjectId, device: &str) -> bool {
+        self.connections.get(user)
+            .is_some_and(|devices| devices.contains_key(device))
+    }
+
+    /// Returns the devices of the given users, which are subscribed to the event.
+    pub fn subscribers<'a>(&'a self, event: &str, users: &'a [ObjectId]) -> impl Iterator<Item = &'a Addr<Connection>> + 'a {
+        let subscribed = self.events.get(event);
+
+        users.iter()
+            .filter_map(move |user| Some((user, subscribed?.get(user)?)))
+            .flat_map(move |(user, devices)| {
+                devices.iter().filter_map(move |device| Some(&self.connections.get(user)?.get(device)?.addr))
+            })
+    }
+
+    pub fn session_ids(&self, user: &ObjectId) -> Vec<String> {
+        self.connections.get(user)
+            .into_iter()
+            .flat_map(|devices| devices.values())
+            .filter_map(|device| device.session_id.clone())
+            .collect()
+    }
+}
+
+fn remove_device(users: &mut HashMap<ObjectId, HashSet<String>>, user: ObjectId, device: &str) {
+    if let Some(devices) = users.get_mut(&user) {
+        devices.remove(device);
+
+        if devices.is_empty() {
+            users.remove(&user);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::dev::channel::channel;
+    use super::*;
+
+    fn addr() -> Addr<Connection> {
+        Addr::new(channel(16).0)
+    }
+
+    #[test]
+    fn first_and_last_device() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+
+        assert!(registry.connect(user, "phone".to_string(), addr(), None));
+        assert!(!registry.connect(user, "laptop".to_string(), addr(), None));
+
+        assert!(!registry.disconnect(user, "phone"));
+        assert!(registry.is_online(&user));
+        assert!(registry.disconnect(user, "laptop"));
+        assert!(!registry.is_online(&user));
+    }
+
+    #[test]
+    fn unknown_device_does_not_disconnect_the_user() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+        registry.connect(user, "phone".to_string(), addr(), None);
+
+        assert!(!registry.disconnect(user, "laptop"));
+        assert!(!registry.disconnect(ObjectId::new(), "phone"));
+        assert!(registry.is_online(&user));
+    }
+
+    #[test]
+    fn signals_reach_the_chosen_device() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+        let (phone, laptop) = (addr(), addr());
+        registry.connect(user, "phone".to_string(), phone.clone(), None);
+        registry.connect(user, "laptop".to_string(), laptop.clone(), None);
+
+        let targets: Vec<_> = registry.devices(&user, Some("laptop")).collect();
+        assert_eq!(targets, vec![&laptop]);
+        assert_eq!(registry.devices(&user, None).count(), 2);
+
+        registry.disconnect(user, "laptop");
+        assert_eq!(registry.devices(&user, Some("laptop")).count(), 0);
+        assert_eq!(registry.devices(&user, None).collect::<Vec<_>>(), vec![&phone]);
+    }
+
+    #[test]
+    fn reconnecting_device_replaces_the_old_connection() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+        let (old, new) = (addr(), addr());
+        registry.connect(user, "phone".to_string(), old, None);
+
+        assert!(!registry.connect(user, "phone".to_string(), new.clone(), None));
+        assert_eq!(registry.devices(&user, Some("phone")).collect::<Vec<_>>(), vec![&new]);
+        assert!(registry.disconnect(user, "phone"));
+    }
+
+    #[test]
+    fn only_the_current_connection_of_a_device_matches() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+        let (old, new) = (addr(), addr());
+        registry.connect(user, "phone".to_string(), old.clone(), None);
+        registry.connect(user, "phone".to_string(), new.clone(), None);
+
+        assert!(registry.is_connection(&user, "phone", &new));
+        assert!(!registry.is_connection(&user, "phone", &old));
+        assert!(!registry.is_connection(&user, "laptop", &new));
+    }
+
+    #[test]
+    fn subscriptions_end_with_the_connection() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+        let users = [user];
+
+        assert!(!registry.subscribe(user, "phone".to_string(), "presence".to_string()));
+
+        registry.connect(user, "phone".to_string(), addr(), None);
+        registry.connect(user, "laptop".to_string(), addr(), None);
+        assert!(registry.subscribe(user, "phone".to_string(), "presence".to_string()));
+        assert_eq!(registry.subscribers("presence", &users).count(), 1);
+
+        registry.disconnect(user, "phone");
+        registry.connect(user, "phone".to_string(), addr(), None);
+        assert_eq!(registry.subscribers("presence", &users).count(), 0);
+    }
+
+    #[test]
+    fn session_ids_of_every_device() {
+        let mut registry = Registry::default();
+        let user = ObjectId::new();
+        registry.connect(user, "phone".to_string(), addr(), Some("a".to_string()));
+        registry.connect(user, "laptop".to_string(), addr(), None);
+
+        assert_eq!(registry.session_ids(&user), vec!["a".to_string()]);
+    }
+}
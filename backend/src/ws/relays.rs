@@ -0,0 +1,300 @@
+use std::{collections::HashMap, fmt, time::{Duration, Instant}};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use mongodb::bson::oid::ObjectId;
+
+use crate::utils;
+
+// The chunks a sender can have on the way, before an ack is needed
+pub const RELAY_WINDOW: usize = 16;
+// The bytes of a chunk, leaving room in the 64 KiB WebSocket frames for base64 and the rest of the message
+pub const MAX_CHUNK_LEN: usize = 32 * 1024;
+
+/// A file transfer relayed through the server, for friends who can not connect
+/// directly. The chunks are end-to-end encrypted by the clients and only passed on,
+/// nothing is stored. The relay is kept by the instance of the sender, which is named by its id.
+pub struct Relay {
+    pub id: String,
+    pub sender: ObjectId,
+    pub sender_device: String,
+    pub receiver: ObjectId,
+    // Set when the receiver accepts the transfer on one of their devices
+    pub receiver_device: Option<String>,
+    // The declared length of the data, which was counted against the quota of the sender
+    size: u64,
+    sent: u64,
+    in_flight: usize,
+    last_activity: Instant,
+}
+
+impl Relay {
+    pub fn new(instance: &str, sender: ObjectId, sender_device: String, receiver: ObjectId, size: u64) -> Relay {
+        Relay {
+            id: format!("{instance}:{}", utils::generate_random_string(16)),
+            sender,
+            sender_device,
+            receiver,
+            receiver_device: None,
+            size,
+            sent: 0,
+            in_flight: 0,
+            last_activity: Instant::now(),
+        }
+    }
+
+    pub fn accept(&mut self, user: ObjectId, device: &str) -> Result<(), RelayError> {
+        if user != self.receiver { return Err(RelayError::NotAllowed) }
+        if self.receiver_device.is_some() { return Err(RelayError::AlreadyAccepted) }
+
+        self.receiver_device = Some(device.to_string());
+        self.last_activity = Instant::now();
+
+        Ok(())
+    }
+
+    /// Counts the chunk sent, if the receiver is ready for it. The length is that of the decoded data.
+    pub fn send_chunk(&mut self, user: ObjectId, device: &str, len: usize) -> Result<(), RelayError> {
+        if user != self.sender || device != self.sender_device { return Err(RelayError::NotAllowed) }
+        if self.receiver_device.is_none() { return Err(RelayError::NotAccepted) }
+        if len > MAX_CHUNK_LEN { return Err(RelayError::ChunkTooLarge) }
+        if self.sent + len as u64 > self.size { return Err(RelayError::SizeExceeded) }
+        if self.in_flight >= RELAY_WINDOW { return Err(RelayError::WindowFull) }
+
+        self.sent += len as u64;
+        self.in_flight += 1;
+        self.last_activity = Instant::now();
+
+        Ok(())
+    }
+
+    pub fn ack(&mut self, user: ObjectId, device: &str) -> Result<(), RelayError> {
+        if !self.is_receiver(user, device) { return Err(RelayError::NotAllowed) }
+
+        self.in_flight = self.in_flight.saturating_sub(1);
+        self.last_activity = Instant::now();
+
+        Ok(())
+    }
+
+    pub fn is_party(&self, user: ObjectId, device: &str) -> bool {
+        (user == self.sender && device == self.sender_device) || self.is_receiver(user, device)
+    }
+
+    /// Returns the other party of the transfer, the receiver might not have chosen a device yet.
+    pub fn other(&self, user: ObjectId) -> (ObjectId, Option<String>) {
+        if user == self.sender {
+            (self.receiver, self.receiver_device.clone())
+        }
+        else {
+            (self.sender, Some(self.sender_device.clone()))
+        }
+    }
+
+    /// Returns the instance keeping the relay with the id.
+    pub fn instance_of(id: &str) -> Option<&str> {
+        id.split_once(':').map(|(instance, _)| instance)
+    }
+
+    fn is_receiver(&self, user: ObjectId, device: &str) -> bool {
+        user == self.receiver && self.receiver_device.as_deref() == Some(device)
+    }
+}
+
+/// Returns the number of bytes in the base64 data of a chunk, which is what the quota is measured in.
+pub fn chunk_len(data: &str) -> Result<usize, RelayError> {
+    // Not even decoded, if it can only be too large
+    if data.len() > MAX_CHUNK_LEN.div_ceil(3) * 4 { return Err(RelayError::ChunkTooLarge) }
+
+    STANDARD.decode(data).map(|bytes| bytes.len()).map_err(|_| RelayError::InvalidChunk)
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RelayError {
+    Disabled,
+    NotFriend,
+    /// The quota can not be checked, so the relay is not opened.
+    Unavailable,
+    /// The transfer is larger than the whole quota.
+    TooLarge,
+    /// The quota of the sender is used up, it is renewed after the given seconds.
+    QuotaExceeded(i64),
+    NotAllowed,
+    NotAccepted,
+    AlreadyAccepted,
+    ChunkTooLarge,
+    /// The data of the chunk is not base64.
+    InvalidChunk,
+    SizeExceeded,
+    WindowFull,
+}
+
+impl fmt::Display for RelayError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RelayError::Disabled => f.write_str("The relay is disabled on this server"),
+            RelayError::NotFriend => f.write_str("Not friend"),
+            RelayError::Unavailable => f.write_str("The relay is not available right now, try again later"),
+            RelayError::TooLarge => f.write_str("The transfer is larger than the relay quota"),
+            RelayError::QuotaExceeded(retry_after) => write!(f, "The relay quota is used up, try again in {retry_after} seconds"),
+            RelayError::NotAllowed => f.write_str("Not a party of the transfer"),
+            RelayError::NotAccepted => f.write_str("The transfer was not accepted yet"),
+            RelayError::AlreadyAccepted => f.write_str("The transfer was accepted already"),
+            RelayError::ChunkTooLarge => write!(f, "The chunks can be at most {MAX_CHUNK_LEN} bytes long"),
+            RelayError::InvalidChunk => f.write_str("The data of the chunks must be base64"),
+            RelayError::SizeExceeded => f.write_str("More data was sent than declared"),
+            RelayError::WindowFull => f.write_str("Too many chunks are not acknowledged yet"),
+        }
+    }
+}
+
+/// The relays kept by this instance.
+#[derive(Default)]
+pub struct Relays {
+    relays: HashMap<String, Relay>,
+}
+
+impl Relays {
+    pub fn insert(&mut self, relay: Relay) {
+        self.relays.insert(relay.id.clone(), relay);
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Option<&mut Relay> {
+        self.relays.get_mut(id)
+    }
+
+    pub fn remove(&mut self, id: &str) -> Option<Relay> {
+        self.relays.remove(id)
+    }
+
+    /// Removes the relays the device takes part in.
+    pub fn remove_of_device(&mut self, user: ObjectId, device: &str) -> Vec<Relay> {
+        self.remove_where(|relay| relay.is_party(user, device))
+    }
+
+    pub fn remove_between(&mut self, user: ObjectId, remote: ObjectId) -> Vec<Relay> {
+        self.remove_where(|relay| {
+            (relay.sender == user && relay.receiver == remote) || (relay.sender == remote && relay.receiver == user)
+        })
+    }
+
+    pub fn remove_idle(&mut self, timeout: Duration) -> Vec<Relay> {
+        self.remove_where(|relay| relay.last_activity.elapsed() > timeout)
+    }
+
+    fn remove_where(&mut self, predicate: impl Fn(&Relay) -> bool) -> Vec<Relay> {
+        let ids: Vec<String> = self.relays.values()
+            .filter(|relay| predicate(relay))
+            .map(|relay| relay.id.clone())
+            .collect();
+
+        ids.iter().filter_map(|id| self.relays.remove(id)).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn accepted_relay(size: u64) -> Relay {
+        let mut relay = Relay::new("instance", ObjectId::new(), "phone".to_string(), ObjectId::new(), size);
+        relay.accept(relay.receiver, "laptop").unwrap();
+        relay
+    }
+
+    #[test]
+    fn the_id_names_the_instance() {
+        let relay = Relay::new("instance", ObjectId::new(), "phone".to_string(), ObjectId::new(), 10);
+
+        assert_eq!(Relay::instance_of(&relay.id), Some("instance"));
+        assert_eq!(Relay::instance_of("no-instance"), None);
+    }
+
+    #[test]
+    fn only_the_receiver_accepts_once() {
+        let mut relay = Relay::new("instance", ObjectId::new(), "phone".to_string(), ObjectId::new(), 10);
+        let sender = relay.sender;
+
+        assert_eq!(relay.send_chunk(sender, "phone", 1), Err(RelayError::NotAccepted));
+        assert_eq!(relay.accept(sender, "phone"), Err(RelayError::NotAllowed));
+        assert_eq!(relay.accept(relay.receiver, "laptop"), Ok(()));
+        assert_eq!(relay.accept(relay.receiver, "tablet"), Err(RelayError::AlreadyAccepted));
+        assert_eq!(relay.other(sender), (relay.receiver, Some("laptop".to_string())));
+    }
+
+    #[test]
+    fn chunks_are_counted_against_the_declared_size() {
+        let mut relay = accepted_relay(MAX_CHUNK_LEN as u64 + 10);
+        let sender = relay.sender;
+
+        assert_eq!(relay.send_chunk(sender, "phone", MAX_CHUNK_LEN + 1), Err(RelayError::ChunkTooLarge));
+        assert_eq!(relay.send_chunk(sender, "laptop", 1), Err(RelayError::NotAllowed));
+        assert_eq!(relay.send_chunk(relay.receiver, "laptop", 1), Err(RelayError::NotAllowed));
+
+        assert_eq!(relay.send_chunk(sender, "phone", MAX_CHUNK_LEN), Ok(()));
+        assert_eq!(relay.send_chunk(sender, "phone", 11), Err(RelayError::SizeExceeded));
+        assert_eq!(relay.send_chunk(sender, "phone", 10), Ok(()));
+        assert_eq!(relay.send_chunk(sender, "phone", 1), Err(RelayError::SizeExceeded));
+    }
+
+    #[test]
+    fn chunks_are_measured_in_bytes() {
+        assert_eq!(chunk_len(""), Ok(0));
+        assert_eq!(chunk_len("AAEC"), Ok(3));
+        assert_eq!(chunk_len("AAE="), Ok(2));
+        assert_eq!(chunk_len(&STANDARD.encode(vec![0; MAX_CHUNK_LEN])), Ok(MAX_CHUNK_LEN));
+        assert_eq!(chunk_len(&STANDARD.encode(vec![0; MAX_CHUNK_LEN + 3])), Err(RelayError::ChunkTooLarge));
+
+        // Multibyte characters are not base64, whatever they would count as
+        assert_eq!(chunk_len("ééé"), Err(RelayError::InvalidChunk));
+        assert_eq!(chunk_len(&"€".repeat(MAX_CHUNK_LEN)), Err(RelayError::ChunkTooLarge));
+    }
+
+    #[test]
+    fn the_window_is_freed_by_the_acks() {
+        let mut relay = accepted_relay(1000);
+        let (sender, receiver) = (relay.sender, relay.receiver);
+
+        for _ in 0..RELAY_WINDOW {
+            assert_eq!(relay.send_chunk(sender, "phone", 1), Ok(()));
+        }
+        assert_eq!(relay.send_chunk(sender, "phone", 1), Err(RelayError::WindowFull));
+
+        // Only the device which accepted the transfer can ack
+        assert_eq!(relay.ack(receiver, "tablet"), Err(RelayError::NotAllowed));
+        assert_eq!(relay.ack(sender, "phone"), Err(RelayError::NotAllowed));
+
+        assert_eq!(relay.ack(receiver, "laptop"), Ok(()));
+        assert_eq!(relay.send_chunk(sender, "phone", 1), Ok(()));
+        assert_eq!(relay.send_chunk(sender, "phone", 1), Err(RelayError::WindowFull));
+
+        // Extra acks do not open the window further
+        for _ in 0..RELAY_WINDOW + 5 {
+            relay.ack(receiver, "laptop").unwrap();
+        }
+        for _ in 0..RELAY_WINDOW {
+            assert_eq!(relay.send_chunk(sender, "phone", 1), Ok(()));
+        }
+        assert_eq!(relay.send_chunk(sender, "phone", 1), Err(RelayError::WindowFull));
+    }
+
+    #[test]
+    fn relays_are_removed_with_their_parties() {
+        let mut relays = Relays::default();
+        let first = accepted_relay(10);
+        let second = accepted_relay(10);
+        let (first_id, second_id) = (first.id.clone(), second.id.clone());
+        let (sender, receiver) = (first.sender, first.receiver);
+        let second_receiver = second.receiver;
+        relays.insert(first);
+        relays.insert(second);
+
+        assert!(relays.remove_of_device(receiver, "tablet").is_empty());
+        assert!(relays.remove_between(receiver, ObjectId::new()).is_empty());
+
+        let removed = relays.remove_between(receiver, sender);
+        assert_eq!(removed.iter().map(|relay| &relay.id).collect::<Vec<_>>(), vec![&first_id]);
+
+        let removed = relays.remove_of_device(second_receiver, "laptop");
+        assert_eq!(removed.len(), 1);
+        assert!(relays.get_mut(&second_id).is_none());
+    }
+}
@@ -0,0 +1,311 @@
+use std::{collections::HashMap, fmt};
+use mongodb::bson::oid::ObjectId;
+use serde::{Deserialize, Serialize};
+
+use crate::utils;
+
+pub const MAX_ROOM_SIZE: usize = 6;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Member {
+    pub user: ObjectId,
+    // Every member takes part from a single device
+    pub device: String,
+}
+
+/// A group call. The members connect to each other directly (mesh), the
+/// server only relays their signals and keeps the roster.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Room {
+    pub id: String,
+    pub host: ObjectId,
+    pub members: Vec<Member>,
+    pub invited: Vec<ObjectId>,
+    pub locked: bool,
+}
+
+impl Room {
+    pub fn is_member(&self, user: &ObjectId) -> bool {
+        self.members.iter().any(|member| &member.user == user)
+    }
+
+    pub fn member(&self, user: &ObjectId) -> Option<&Member> {
+        self.members.iter().find(|member| &member.user == user)
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum RoomError {
+    NotFound,
+    NotHost,
+    NotMember,
+    NotInvited,
+    NotFriend,
+    Blocked,
+    AlreadyInRoom,
+    Locked,
+    Full,
+}
+
+impl fmt::Display for RoomError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let message = match self {
+            RoomError::NotFound => "No such room",
+            RoomError::NotHost => "Only the host can do this",
+            RoomError::NotMember => "Not a member of the room",
+            RoomError::NotInvited => "Not invited to the room",
+            RoomError::NotFriend => "Only friends of the host can be invited",
+            RoomError::Blocked => "The member can not be signaled",
+            RoomError::AlreadyInRoom => "Already in a room",
+            RoomError::Locked => "The room is locked",
+            RoomError::Full => "The room is full",
+        };
+
+        f.write_str(message)
+    }
+}
+
+/// The rooms in progress. Like the calls, every instance keeps the whole
+/// table and the changes are shared through the cluster.
+#[derive(Default)]
+pub struct Rooms {
+    rooms: HashMap<String, Room>,
+    by_user: HashMap<ObjectId, String>,
+}
+
+impl Rooms {
+    pub fn get(&self, id: &str) -> Result<&Room, RoomError> {
+        self.rooms.get(id).ok_or(RoomError::NotFound)
+    }
+
+    pub fn of_user(&self, user: &ObjectId) -> Option<&Room> {
+        self.by_user.get(user).and_then(|id| self.rooms.get(id))
+    }
+
+    /// Stores the new state of the room, empty rooms are removed.
+    pub fn update(&mut self, room: Room) {
+        if let Some(old) = self.rooms.remove(&room.id) {
+            for member in old.members {
+                self.by_user.remove(&member.user);
+            }
+        }
+
+        if room.members.is_empty() {
+            return;
+        }
+
+        for member in &room.members {
+            self.by_user.insert(member.user, room.id.clone());
+        }
+        self.rooms.insert(room.id.clone(), room);
+    }
+
+    // The methods below return the changed room without storing it
+
+    pub fn create(&self, host: ObjectId, device: String) -> Result<Room, RoomError> {
+        if self.by_user.contains_key(&host) {
+            return Err(RoomError::AlreadyInRoom);
+        }
+
+        Ok(Room {
+            id: utils::generate_random_string(16),
+            host,
+            members: vec![Member { user: host, device }],
+            invited: vec![],
+            locked: false,
+        })
+    }
+
+    /// Invites the user, who has to be a friend of the host (checked by the caller).
+    pub fn invite(&self, id: &str, host: ObjectId, user: ObjectId) -> Result<Room, RoomError> {
+        let mut room = self.hosted_room(id, host)?;
+
+        if !room.invited.contains(&user) {
+            room.invited.push(user);
+        }
+
+        Ok(room)
+    }
+
+    pub fn join(&self, id: &str, user: ObjectId, device: String) -> Result<Room, RoomError> {
+        let mut room = self.get(id)?.clone();
+
+        if self.by_user.contains_key(&user) {
+            return Err(RoomError::AlreadyInRoom);
+        }
+        if !room.invited.contains(&user) {
+            return Err(RoomError::NotInvited);
+        }
+        if room.locked {
+            return Err(RoomError::Locked);
+        }
+        if room.members.len() >= MAX_ROOM_SIZE {
+            return Err(RoomError::Full);
+        }
+
+        room.invited.retain(|invited| invited != &user);
+        room.members.push(Member { user, device });
+
+        Ok(room)
+    }
+
+    /// Removes the user from their room. If the host leaves, the member who joined the
+    /// earliest becomes the host.
+    pub fn leave(&self, user: ObjectId) -> Option<Room> {
+        let mut room = self.of_user(&user)?.clone();
+
+        room.members.retain(|member| member.user != user);
+        if room.host == user {
+            if let Some(member) = room.members.first() {
+                room.host = member.user;
+            }
+        }
+
+        Some(room)
+    }
+
+    pub fn kick(&self, id: &str, host: ObjectId, user: ObjectId) -> Result<Room, RoomError> {
+        let mut room = self.hosted_room(id, host)?;
+
+        if user == host || !room.is_member(&user) {
+            return Err(RoomError::NotMember);
+        }
+
+        room.members.retain(|member| member.user != user);
+        room.invited.retain(|invited| invited != &user);
+
+        Ok(room)
+    }
+
+    pub fn lock(&self, id: &str, host: ObjectId, locked: bool) -> Result<Room, RoomError> {
+        let mut room = self.hosted_room(id, host)?;
+        room.locked = locked;
+
+        Ok(room)
+    }
+
+    /// Returns the device of the remote member, if both users are members of the room.
+    pub fn signal_target(&self, id: &str, user: ObjectId, remote: ObjectId) -> Result<&Member, RoomError> {
+        let room = self.get(id)?;
+
+        if !room.is_member(&user) {
+            return Err(RoomError::NotMember);
+        }
+
+        room.member(&remote).ok_or(RoomError::NotMember)
+    }
+
+    fn hosted_room(&self, id: &str, host: ObjectId) -> Result<Room, RoomError> {
+        let room = self.get(id)?;
+
+        if room.host != host {
+            return Err(RoomError::NotHost);
+        }
+
+        Ok(room.clone())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn room_with_guest(rooms: &mut Rooms, host: ObjectId, guest: ObjectId) -> Room {
+        let room = rooms.create(host, "host-device".to_string()).unwrap();
+        rooms.update(room.clone());
+        rooms.update(rooms.invite(&room.id, host, guest).unwrap());
+
+        let room = rooms.join(&room.id, guest, "guest-device".to_string()).unwrap();
+        rooms.update(room.clone());
+        room
+    }
+
+    #[test]
+    fn invited_users_can_join() {
+        let (host, guest, stranger) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut rooms = Rooms::default();
+
+        let room = room_with_guest(&mut rooms, host, guest);
+
+        assert!(room.is_member(&guest));
+        assert!(room.invited.is_empty());
+        assert_eq!(rooms.of_user(&guest).map(|room| &room.id), Some(&room.id));
+        assert_eq!(rooms.join(&room.id, stranger, "device".to_string()).unwrap_err(), RoomError::NotInvited);
+        assert_eq!(rooms.join("unknown", guest, "device".to_string()).unwrap_err(), RoomError::NotFound);
+    }
+
+    #[test]
+    fn users_can_be_in_one_room_only() {
+        let (host, guest) = (ObjectId::new(), ObjectId::new());
+        let mut rooms = Rooms::default();
+        room_with_guest(&mut rooms, host, guest);
+
+        assert_eq!(rooms.create(guest, "device".to_string()).unwrap_err(), RoomError::AlreadyInRoom);
+    }
+
+    #[test]
+    fn only_the_host_can_manage_the_room() {
+        let (host, guest, other) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut rooms = Rooms::default();
+        let room = room_with_guest(&mut rooms, host, guest);
+
+        assert_eq!(rooms.invite(&room.id, guest, other).unwrap_err(), RoomError::NotHost);
+        assert_eq!(rooms.kick(&room.id, guest, host).unwrap_err(), RoomError::NotHost);
+        assert_eq!(rooms.lock(&room.id, guest, true).unwrap_err(), RoomError::NotHost);
+        assert_eq!(rooms.kick(&room.id, host, host).unwrap_err(), RoomError::NotMember);
+
+        let room = rooms.kick(&room.id, host, guest).unwrap();
+        assert!(!room.is_member(&guest));
+    }
+
+    #[test]
+    fn locked_and_full_rooms_can_not_be_joined() {
+        let host = ObjectId::new();
+        let mut rooms = Rooms::default();
+        let room = rooms.create(host, "device".to_string()).unwrap();
+        rooms.update(room.clone());
+
+        let late = ObjectId::new();
+        rooms.update(rooms.invite(&room.id, host, late).unwrap());
+        rooms.update(rooms.lock(&room.id, host, true).unwrap());
+        assert_eq!(rooms.join(&room.id, late, "device".to_string()).unwrap_err(), RoomError::Locked);
+        rooms.update(rooms.lock(&room.id, host, false).unwrap());
+
+        for _ in 1..MAX_ROOM_SIZE {
+            let user = ObjectId::new();
+            rooms.update(rooms.invite(&room.id, host, user).unwrap());
+            rooms.update(rooms.join(&room.id, user, "device".to_string()).unwrap());
+        }
+        assert_eq!(rooms.join(&room.id, late, "device".to_string()).unwrap_err(), RoomError::Full);
+    }
+
+    #[test]
+    fn the_earliest_member_takes_over_from_the_host() {
+        let (host, guest) = (ObjectId::new(), ObjectId::new());
+        let mut rooms = Rooms::default();
+        let room = room_with_guest(&mut rooms, host, guest);
+
+        let left = rooms.leave(host).unwrap();
+        assert_eq!(left.host, guest);
+        rooms.update(left);
+        assert!(rooms.of_user(&host).is_none());
+
+        // The room is removed once everyone left
+        rooms.update(rooms.leave(guest).unwrap());
+        assert_eq!(rooms.get(&room.id).unwrap_err(), RoomError::NotFound);
+        assert!(rooms.of_user(&guest).is_none());
+    }
+
+    #[test]
+    fn only_members_can_signal_each_other() {
+        let (host, guest, stranger) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let mut rooms = Rooms::default();
+        let room = room_with_guest(&mut rooms, host, guest);
+
+        let target = rooms.signal_target(&room.id, host, guest).unwrap();
+        assert_eq!(target.device, "guest-device");
+
+        assert_eq!(rooms.signal_target(&room.id, stranger, host).unwrap_err(), RoomError::NotMember);
+        assert_eq!(rooms.signal_target(&room.id, host, stranger).unwrap_err(), RoomError::NotMember);
+    }
+}
@@ -1,16 +1,110 @@
 use actix::Addr;
-use actix_web::{web::{self, Data}, Error, HttpRequest, HttpResponse};
+use actix_session::Session;
+use actix_web::{error::{ErrorInternalServerError, ErrorUnauthorized}, web::{self, Data, Json, Path, Query}, Error, HttpRequest, HttpResponse};
 use actix_web_actors::ws;
-use crate::schemas::User;
-use super::{Server, Connection};
+use jsonwebtoken::{decode, errors::ErrorKind, DecodingKey};
+use mongodb::{bson::{doc, oid::ObjectId}, Collection};
+use serde::Deserialize;
+use unicode_segmentation::UnicodeSegmentation;
+use crate::{ice::{self, IceServer}, schemas::{GuestClaims, User}, sessions::SESSION_ID_KEY, utils::MapAndLog, EnvVars};
+use super::{protocol::{self, ProtocolSchema}, Server, Connection};
+
+const GUEST_NAME_LENGTH: usize = 32;
+
+#[derive(Deserialize)]
+pub struct GuestQuery {
+  name: Option<String>,
+}
 
 pub async fn ws_route(
   req: HttpRequest,
   stream: web::Payload,
   server: Data<Addr<Server>>,
+  session: Session,
   user: User
 ) -> Result<HttpResponse, Error> {
-  let connection = Connection::new(user, server.get_ref().clone());
+  let session_id = session.get::<String>(SESSION_ID_KEY).ok().flatten();
+  let connection = Connection::new(user, session_id, server.get_ref().clone());
 
   ws::start(connection, &req, stream)
-}
\ No newline at end of file
+}
+
+/// Connects someone without an account with the token of a guest link.
+pub async fn guest_ws_route(
+  req: HttpRequest,
+  stream: web::Payload,
+  params: Path<String>,
+  query: Query<GuestQuery>,
+  server: Data<Addr<Server>>,
+  users_coll: Data<Collection<User>>,
+  env_vars: Data<EnvVars>,
+) -> Result<HttpResponse, Error> {
+  let (_, owner_id) = guest_from_token(&params, &users_coll, &env_vars).await?;
+  let connection = Connection::guest(guest_user(query.name.as_deref()), owner_id, server.get_ref().clone());
+
+  ws::start(connection, &req, stream)
+}
+
+/// Everyone opening a link is another guest, so a shared link does not mix up their connections.
+fn guest_user(name: Option<&str>) -> User {
+  let username = name
+    .map(|name| name.trim().graphemes(true).take(GUEST_NAME_LENGTH).collect::<String>())
+    .filter(|name| !name.is_empty())
+    .unwrap_or_else(|| "Guest".to_string());
+
+  User { _id: ObjectId::new(), username, ..Default::default() }
+}
+
+/// The ICE servers for the guest, who has no session to get them with.
+pub async fn guest_ice_servers_route(
+  params: Path<String>,
+  users_coll: Data<Collection<User>>,
+  env_vars: Data<EnvVars>,
+) -> Result<Json<Vec<IceServer>>, Error> {
+  let (link_id, _) = guest_from_token(&params, &users_coll, &env_vars).await?;
+
+  Ok(Json(ice::ice_servers(&env_vars, link_id)))
+}
+
+/// Returns the id of the link and of the owner of the link.
+async fn guest_from_token(token: &str, users_coll: &Collection<User>, env_vars: &EnvVars) -> Result<(ObjectId, ObjectId), Error> {
+  let claims = decode::<GuestClaims>(token, &DecodingKey::from_secret(env_vars.confirm_secret.as_ref()), &GuestClaims::validation())
+    .map_err(|err| match err.kind() {
+      ErrorKind::ExpiredSignature => ErrorUnauthorized("Expired link"),
+      _ => ErrorUnauthorized("Invalid link"),
+    })?
+    .claims;
+
+  let link_id = ObjectId::parse_str(&claims.sub).map_err(|_| ErrorUnauthorized("Invalid link"))?;
+  let owner_id = ObjectId::parse_str(&claims.owner).map_err(|_| ErrorUnauthorized("Invalid link"))?;
+
+  // The links stop working with the account of the owner
+  let filter = doc!{"_id": owner_id, "deleted": false};
+  users_coll.find_one(filter, None).await
+    .log_and_map(ErrorInternalServerError(""))?
+    .ok_or_else(|| ErrorUnauthorized("Invalid link"))?;
+
+  Ok((link_id, owner_id))
+}
+
+pub async fn schema_route() -> Json<ProtocolSchema> {
+  Json(protocol::schema())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn every_guest_gets_their_own_id() {
+    assert_ne!(guest_user(Some("Anna"))._id, guest_user(Some("Anna"))._id);
+  }
+
+  #[test]
+  fn guest_names_are_trimmed() {
+    assert_eq!(guest_user(Some("  Anna ")).username, "Anna");
+    assert_eq!(guest_user(Some(" ")).username, "Guest");
+    assert_eq!(guest_user(None).username, "Guest");
+    assert_eq!(guest_user(Some(&"a".repeat(40))).username.len(), GUEST_NAME_LENGTH);
+  }
+}
@@ -1,68 +1,357 @@
-use crate::schemas::User;
-use super::{Send, Dispatch, Connect, Terminate, Disconnect, Connection, Subscribe, Unsubscribe, Signal, ConnectedIds};
-use actix::{prelude::{Actor, Context, Handler}, Addr, WrapFuture, ContextFutureSpawner};
-use mongodb::{bson::{oid::ObjectId, doc}, Collection};
+use crate::{rate_limit::RateLimiter, schemas::{GuestLogin, Presence, User, UserPresence}, utils::{self, MapAndLog}};
+use super::{Broadcast, Call, CallError, CallEvent, CallState, Calls, ErrorCode, Friends, Relay, RelayAction, chunk_len, RelayError, RelayEvent, RelayRequest, Relays, Room, RoomAction, RoomError, RoomEvent, RoomMember, RoomRequest, Rooms, SignalAction, ServerMessage, ServerSignal, Cluster, Instances, Registry, INSTANCE_TTL_SECS, Send, Dispatch, Connect, Kick, Disconnect, FriendsChanged, SetPresence, Subscribe, Unsubscribe, Signal, Unfriend, ConnectedIds, ConnectedSessions};
+use actix::{prelude::{Actor, Context, Handler}, ActorFutureExt, AsyncContext, ResponseFuture, StreamHandler, WrapFuture, ContextFutureSpawner};
+use log::error;
+use mongodb::{bson::{oid::ObjectId, doc, DateTime}, Collection};
 use serde::Serialize;
-use serde_json::json;
-use std::{collections::{HashMap}, rc::Rc};
+use std::{collections::{HashMap, HashSet}, time::{Duration, Instant}};
+
+const REFRESH_INTERVAL: Duration = Duration::from_secs(INSTANCE_TTL_SECS as u64 / 3);
+const INSTANCE_TTL: Duration = Duration::from_secs(INSTANCE_TTL_SECS as u64);
+// How often the last-seen time of the connected users is updated
+const LAST_SEEN_INTERVAL: Duration = Duration::from_secs(60);
+// The wait before resubscribing to Redis, doubled after every failed attempt
+const RESUBSCRIBE_BACKOFF: Duration = Duration::from_millis(500);
+const MAX_RESUBSCRIBE_BACKOFF: Duration = Duration::from_secs(30);
+// A call which is not accepted within this time is missed
+const RING_TIMEOUT: Duration = Duration::from_secs(30);
+// An accepted call, the peers of which do not start connecting within this time, is ended
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(30);
+// A caller can cause this many missed call notifications of the same callee per window
+const MISSED_CALL_LIMIT: u64 = 3;
+const MISSED_CALL_WINDOW_SECS: i64 = 5 * 60;
+// Relays without chunks or acks within this time are closed
+const RELAY_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+// The relay quota of the users is renewed daily
+const RELAY_QUOTA_WINDOW_SECS: i64 = 60 * 60 * 24;
+
+// Whether two members of a room can signal each other, it is checked once for every pair
+enum RoomPeer {
+    // The signals waiting for the check, in the order they arrived
+    Checking(Vec<RoomSignal>),
+    Checked(bool),
+}
+
+struct RoomSignal {
+    user: ObjectId,
+    device: String,
+    room_id: String,
+    remote_id: ObjectId,
+    peer_data: String,
+}
 
-#[derive(Debug)]
 pub struct Server {
-    connections: Rc<HashMap<ObjectId, Addr<Connection>>>,
-    events: Rc<HashMap<String, HashMap<ObjectId, Addr<Connection>>>>,
-    users_coll: Rc<Collection<User>>,
+    registry: Registry,
+    friends: Friends,
+    calls: Calls,
+    // Since when a device in each call has not been connected to any instance
+    orphaned_calls: HashMap<String, Instant>,
+    rooms: Rooms,
+    room_peers: HashMap<(ObjectId, ObjectId), RoomPeer>,
+    // The owner of the link of each guest, on every instance
+    guests: HashMap<ObjectId, ObjectId>,
+    relays: Relays,
+    instances: Instances,
+    // The bytes a user can relay per window, the relay is disabled if zero
+    relay_quota: u64,
+    rate_limiter: RateLimiter,
+    users_coll: Collection<User>,
+    cluster: Cluster,
 }
 
 impl Actor for Server {
     type Context = Context<Self>;
+
+    fn started(&mut self, ctx: &mut Self::Context) {
+        self.heartbeat();
+        ctx.run_interval(REFRESH_INTERVAL, |act, _| act.heartbeat());
+
+        ctx.run_interval(LAST_SEEN_INTERVAL, |act, ctx| {
+            act.record_last_seen(act.registry.users(), ctx);
+        });
+
+        ctx.run_interval(RELAY_IDLE_TIMEOUT / 4, |act, _| {
+            for relay in act.relays.remove_idle(RELAY_IDLE_TIMEOUT) {
+                act.send_relay_closed(&relay, relay.sender);
+                act.send_relay_closed(&relay, relay.receiver);
+            }
+        });
+    }
 }
 
 impl Server {
-    pub fn new(users_coll: Collection<User>) -> Server {
+    pub fn new(users_coll: Collection<User>, cluster: Cluster, rate_limiter: RateLimiter, relay_quota: u64) -> Server {
         Server {
-            connections: Rc::new(HashMap::new()),
-            events: Rc::new(HashMap::new()),
-            users_coll: Rc::new(users_coll),
+            registry: Registry::default(),
+            friends: Friends::default(),
+            calls: Calls::default(),
+            orphaned_calls: HashMap::new(),
+            rooms: Rooms::default(),
+            room_peers: HashMap::new(),
+            guests: HashMap::new(),
+            relays: Relays::default(),
+            instances: Instances::default(),
+            relay_quota,
+            rate_limiter,
+            users_coll,
+            cluster,
+        }
+    }
+
+    /// Emits the event to the subscribed users on every instance.
+    fn emit_event<T: Serialize>(&self, event: &str, data: T, ids: Vec<ObjectId>) {
+        let data = match serde_json::to_value(data) {
+            Ok(data) => data,
+            Err(err) => return error!("{err}"),
+        };
+
+        self.emit_local_event(event, &data, &ids);
+        self.cluster.publish(Broadcast::Event { event: event.to_string(), data, ids });
+    }
+
+    /// Sends the message to a single device of the user, or to all of them, on every instance.
+    /// A message for a single device only goes to the instance of the device, if it is known.
+    fn deliver(&self, id: ObjectId, device: Option<String>, message: String) {
+        if self.deliver_local(id, device.as_deref(), &message) {
+            return;
+        }
+
+        let instance = device.as_deref().and_then(|device| self.instances.locate(id, device));
+        let broadcast = Broadcast::Deliver { user: id, device, message };
+
+        match instance {
+            Some(instance) => self.cluster.publish_to(instance, broadcast),
+            None => self.cluster.publish(broadcast),
+        }
+    }
+
+    /// Marks this instance alive and tells the others which devices are connected to it.
+    fn heartbeat(&mut self) {
+        self.cluster.refresh(self.registry.users());
+        self.cluster.publish(Broadcast::Heartbeat { devices: self.registry.all_devices() });
+
+        self.expire_instances();
+        self.expire_calls();
+    }
+
+    /// Cleans up after the instances, which stopped sending heartbeats. Every instance
+    /// does this for itself, so only the users connected to this one are notified.
+    fn expire_instances(&mut self) {
+        let devices = self.instances.remove_dead(INSTANCE_TTL);
+
+        for (user, device) in &devices {
+            let joined = self.rooms.of_user(user)
+                .and_then(|room| room.member(user))
+                .is_some_and(|member| &member.device == device);
+
+            if let Some(room) = self.rooms.leave(*user).filter(|_| joined) {
+                let roster = roster_message(&room);
+                for member in &room.members {
+                    self.deliver_local(member.user, Some(&member.device), &roster);
+                }
+                self.store_room(room);
+            }
+
+            self.close_relays_of_device(*user, device);
+
+            if let Some(call) = self.calls.of_user(user).filter(|call| call.has_device(*user, device)).cloned() {
+                self.end_call_local(call);
+            }
+        }
+
+        let users: HashSet<ObjectId> = devices.into_iter().map(|(user, _)| user).collect();
+        let gone = users.into_iter().filter(|user| !self.registry.is_online(user) && !self.instances.is_online(user));
+
+        for user in gone.collect::<Vec<_>>() {
+            if let Some(call) = self.calls.of_user(&user).cloned() {
+                self.end_call_local(call);
+            }
+
+            if let Some(owner) = self.guests.remove(&user) {
+                self.emit_local_event("guestLogout", &serde_json::Value::String(user.to_hex()), &[owner]);
+            }
         }
     }
 
-    fn emit_event<T: Serialize>(&self, event: &str, data: Box<T>, ids: &[ObjectId]) {
-        emit_event(self.events.clone(), event, data, ids).ok();
+    /// Ends the calls, a device of which has not been connected to any instance for a whole
+    /// heartbeat, like those of an instance which crashed before its heartbeat expired.
+    /// Every instance does this for itself, so only the users connected to this one are notified.
+    fn expire_calls(&mut self) {
+        let orphaned: Vec<Call> = self.calls.all()
+            .filter(|call| {
+                let callee_device = call.callee_device.as_deref().map(|device| (call.callee, device));
+                [(call.caller, call.caller_device.as_str())].into_iter().chain(callee_device)
+                    .any(|(user, device)| !self.registry.has_device(&user, device) && self.instances.locate(user, device).is_none())
+            })
+            .cloned()
+            .collect();
+
+        self.orphaned_calls.retain(|id, _| orphaned.iter().any(|call| &call.id == id));
+
+        let now = Instant::now();
+        for call in orphaned {
+            let since = *self.orphaned_calls.entry(call.id.clone()).or_insert(now);
+
+            if now.duration_since(since) >= INSTANCE_TTL {
+                self.orphaned_calls.remove(&call.id);
+                self.end_call_local(call);
+            }
+        }
+    }
+
+    /// Ends the call on this instance only, the users connected to it are notified.
+    fn end_call_local(&mut self, call: Call) {
+        self.calls.update(Call { state: CallState::Ended, ..call.clone() });
+
+        for user in [call.caller, call.callee] {
+            self.deliver_local(user, None, &call_message(user, CallEvent::Ended, &call));
+        }
+    }
+
+    /// Emits the login or logout, unless the user has a connection on another instance.
+    /// The friends are looked up, if they are not cached. The status of the user is
+    /// sent along, if `presence` is set.
+    fn emit_presence(&self, event: &'static str, user: ObjectId, friends: Option<Vec<ObjectId>>, presence: Option<Presence>, ctx: &mut Context<Self>) {
+        let users_coll = self.users_coll.clone();
+        let online_elsewhere = self.cluster.online_elsewhere(user);
+
+        let future = async move {
+            if online_elsewhere.await.unwrap_or_default() {
+                return None;
+            }
+
+            match friends {
+                Some(friends) => Some(friends),
+                None => fetch_friends(&users_coll, user).await,
+            }
+        };
+
+        future.into_actor(self)
+            .map(move |friends, act, _| {
+                if let Some(friends) = friends {
+                    act.emit_event(event, user.to_hex(), friends.clone());
+
+                    if let Some(presence) = presence {
+                        act.emit_event("presence", UserPresence { _id: user, presence }, friends);
+                    }
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// (Re)loads the friends of a connected user into the cache and emits the
+    /// login of the user with their status once they are known, if `login` is set.
+    fn load_friends(&mut self, user: ObjectId, login: Option<Presence>, ctx: &mut Context<Self>) {
+        let load = self.friends.start_load(user);
+
+        let users_coll = self.users_coll.clone();
+
+        async move { fetch_friends(&users_coll, user).await }
+            .into_actor(self)
+            .map(move |friends, act, ctx| {
+                // Signals fall back to the database, when the friends could not be loaded
+                act.friends.finish_load(user, load, friends.as_deref());
+
+                // The user might have left already
+                if let Some(presence) = login.filter(|_| act.registry.is_online(&user)) {
+                    act.emit_presence("login", user, friends, Some(presence), ctx);
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn reload_friends(&mut self, ids: &[ObjectId], ctx: &mut Context<Self>) {
+        // Blocking changes the friends, the members of the rooms are checked again
+        self.room_peers.retain(|(user, remote), peer| {
+            matches!(peer, RoomPeer::Checking(_)) || !(ids.contains(user) || ids.contains(remote))
+        });
+
+        for id in ids {
+            if self.friends.is_kept(id) {
+                self.load_friends(*id, None, ctx);
+            }
+        }
+    }
+
+    fn record_last_seen(&self, ids: Vec<ObjectId>, ctx: &mut Context<Self>) {
+        if ids.is_empty() { return }
+
+        let users_coll = self.users_coll.clone();
+        let filter = doc!{"_id": {"$in": ids}};
+        let update = doc!{"$set": {"last_seen": DateTime::now()}};
+
+        let future = async move {
+            users_coll.update_many(filter, update, None).await
+                .log_and_map(())
+                .ok();
+        };
+
+        future.into_actor(self).spawn(ctx);
+    }
+
+    fn emit_local_event(&self, event: &str, data: &serde_json::Value, ids: &[ObjectId]) {
+        let message = ServerMessage::Pusher { event: event.to_string(), data: data.clone() }.to_text();
+
+        self.registry.subscribers(event, ids)
+            .for_each(|addr| addr.do_send(Send(message.clone())));
+    }
+
+    /// Sends the message to the devices connected to this instance. Returns true if the
+    /// message surely reached every recipient, so other instances can be skipped.
+    fn deliver_local(&self, id: ObjectId, device: Option<&str>, message: &str) -> bool {
+        self.registry.devices(&id, device)
+            .for_each(|addr| addr.do_send(Send(message.to_string())));
+
+        device.is_some_and(|device| self.registry.has_device(&id, device))
+    }
+
+    fn kick_local(&self, id: ObjectId, session_id: Option<String>) {
+        self.registry.devices(&id, None)
+            .for_each(|addr| addr.do_send(Kick { _id: id, session_id: session_id.clone() }));
     }
 }
 
 impl Handler<Connect> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: Connect, _: &mut Context<Self>) {
-        self.emit_event("login", Box::new(msg.user._id.to_hex()), &msg.user.friends);
+    fn handle(&mut self, msg: Connect, ctx: &mut Context<Self>) {
+        let first = self.registry.connect(msg.user._id, msg.device, msg.addr, msg.session_id);
+
+        // Guests have no friends, only the owner of the link sees them
+        if let Some(owner) = msg.guest_of {
+            if first {
+                self.guests.insert(msg.user._id, owner);
+                self.cluster.publish(Broadcast::Guest { guest: msg.user._id, owner: Some(owner) });
+                self.cluster.set_online(msg.user._id, true);
 
-        if let Some(connections) = Rc::get_mut(&mut self.connections) {
-            if let Some(addr) = connections.get(&msg.user._id) {
-                addr.do_send(Terminate);
+                let login = GuestLogin { _id: msg.user._id.to_hex(), username: msg.user.username };
+                self.emit_event("guestLogin", login, vec![owner]);
             }
+            return;
+        }
 
-            connections.insert(msg.user._id, msg.addr);
+        // The friends only see the user coming online with the first device
+        if first {
+            self.cluster.set_online(msg.user._id, true);
+            self.load_friends(msg.user._id, Some(msg.user.presence), ctx);
         }
     }
 }
 
-impl Handler<Subscribe> for Server {
+impl Handler<Kick> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
-        let used_fields = (
-            Rc::get_mut(&mut self.events),
-            self.connections.get(&msg._id)
-        );
+    fn handle(&mut self, msg: Kick, _: &mut Context<Self>) {
+        self.kick_local(msg._id, msg.session_id.clone());
+        self.cluster.publish(Broadcast::Kick { user: msg._id, session_id: msg.session_id });
+    }
+}
 
-        if let (Some(events), Some(addr)) = used_fields {
-            if !events.contains_key(&msg.event) {
-                events.insert(msg.event.clone(), HashMap::new());
-            }
+impl Handler<Subscribe> for Server {
+    type Result = ();
 
-            events.get_mut(&msg.event).unwrap().insert(msg._id, addr.clone());
-        }
+    fn handle(&mut self, msg: Subscribe, _: &mut Context<Self>) {
+        self.registry.subscribe(msg._id, msg.device, msg.event);
     }
 }
 
@@ -70,9 +359,7 @@ impl Handler<Unsubscribe> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: Unsubscribe, _: &mut Context<Self>) {
-        Rc::get_mut(&mut self.events)
-            .and_then(|events| events.get_mut(&msg.event))
-            .and_then(|subscribed| subscribed.remove(&msg._id));
+        self.registry.unsubscribe(msg._id, &msg.device, &msg.event);
     }
 }
 
@@ -80,38 +367,195 @@ impl Handler<Signal> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: Signal, ctx: &mut Context<Self>) {
-        let connections = self.connections.clone();
+        if let Some(allowed) = self.guest_signal_allowed(msg._id, msg.remote_id) {
+            return self.relay_signal(msg, allowed, ctx);
+        }
+
+        if let Some(allowed) = self.friends.is_friend(&msg._id, &msg.remote_id) {
+            return self.relay_signal(msg, allowed, ctx);
+        }
+
         let users_coll = self.users_coll.clone();
+        let (user_id, remote_id) = (msg._id, msg.remote_id);
 
-        let send_msg = move |id: ObjectId, msg: serde_json::Value| {
-            if let Some(addr) = connections.get(&id) {
-                addr.do_send(Send(msg.to_string()));
-            }
+        let future = async move {
+            let user = users_coll.find_one(doc!{"_id": &user_id}, None).await.ok()??;
+
+            let filter = doc!{"_id": &remote_id, "blocked": {"$ne": &user_id}};
+            let blocked = user.blocked.contains(&remote_id)
+                || !matches!(users_coll.find_one(filter, None).await, Ok(Some(_)));
+
+            Some(user.friends.contains(&remote_id) && !blocked)
         };
 
-        let future = async move {
-            if let Ok(Some(user)) = users_coll.find_one(doc!{"_id": &msg._id}, None).await {
-                if user.friends.contains(&msg.remote_id) {
-                    let payload = json!({
-                        "action": "signal",
-                        "peerData": msg.peer_data,
-                        "remoteId": msg._id.to_hex(),
-                        "type": msg.r#type,
-                        "data": msg.data,
-                        "msgType": "signal"
-                    });
+        future.into_actor(self)
+            .map(move |allowed, act, ctx| {
+                if let Some(allowed) = allowed {
+                    act.relay_signal(msg, allowed, ctx);
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Server {
+    /// Guests and the owners of their links can only signal each other.
+    /// Returns `None` if neither user is a guest.
+    fn guest_signal_allowed(&self, user: ObjectId, remote: ObjectId) -> Option<bool> {
+        match (self.guests.get(&user), self.guests.get(&remote)) {
+            (None, None) => None,
+            (owner, remote_owner) => Some(owner == Some(&remote) || remote_owner == Some(&user)),
+        }
+    }
+
+    fn relay_signal(&mut self, msg: Signal, allowed: bool, ctx: &mut Context<Self>) {
+        if allowed {
+            let call_signal = msg.r#type == "call" || msg.action != SignalAction::Signal;
 
-                    send_msg(msg.remote_id, payload)
+            if call_signal {
+                match self.calls.transition(msg._id, &msg.device, msg.remote_id, msg.action) {
+                    Ok(Some(call)) => {
+                        if matches!(msg.action, SignalAction::Accept | SignalAction::Decline) {
+                            self.send_call_event(msg._id, CallEvent::Answered, &call);
+                        }
+                        let id = call.id.clone();
+                        self.update_call(call, ctx);
+
+                        // Only a new call is checked, ringing again does not notify the callee again
+                        if msg.action == SignalAction::Ring && !self.registry.is_online(&msg.remote_id) {
+                            self.check_callee_offline(id, msg.device.clone(), ctx);
+                        }
+                    },
+                    Ok(None) => {},
+                    Err(CallError::Busy) => {
+                        let reply = ServerMessage::Call { event: CallEvent::Busy, call_id: None, remote_id: msg.remote_id.to_hex() };
+                        return self.deliver(msg._id, Some(msg.device), reply.to_text());
+                    },
+                    Err(CallError::NoCall) => {
+                        let reply = ServerMessage::error(ErrorCode::NoCall, "There is no call with the remote user");
+                        return self.deliver(msg._id, Some(msg.device), reply.to_text());
+                    },
                 }
-                else {
-                    let payload = json!({
-                        "error": "Not friend",
-                        "remoteId": msg.remote_id.to_hex(),
-                        "msgType": "signal"
-                    });
+            }
+
+            let payload = ServerMessage::Signal(ServerSignal::Data {
+                action: msg.action,
+                peer_data: msg.peer_data,
+                remote_id: msg._id.to_hex(),
+                remote_device: msg.device,
+                r#type: msg.r#type,
+                data: msg.data,
+            });
+
+            self.deliver(msg.remote_id, msg.remote_device, payload.to_text());
+        }
+        else {
+            self.deliver(msg._id, Some(msg.device), not_friend(msg.remote_id));
+        }
+    }
+}
+
+impl Server {
+    /// Stores the new state of the call on every instance. The instance
+    /// starting the ringing or accepting the call is the one timing it out.
+    fn update_call(&mut self, call: Call, ctx: &mut Context<Self>) {
+        let previous = self.calls.get(&call.id).map(|call| call.state);
+
+        if call.state == CallState::Ringing && previous.is_none() {
+            let id = call.id.clone();
+            ctx.run_later(RING_TIMEOUT, move |act, ctx| act.ring_timeout(&id, ctx));
+        }
+        if call.state == CallState::Accepted && previous != Some(CallState::Accepted) {
+            let id = call.id.clone();
+            ctx.run_later(CONNECT_TIMEOUT, move |act, ctx| act.connect_timeout(&id, ctx));
+        }
+
+        self.calls.update(call.clone());
+        self.cluster.publish(Broadcast::Call { call });
+    }
+
+    fn ring_timeout(&mut self, id: &str, ctx: &mut Context<Self>) {
+        let Some(call) = self.calls.get(id).filter(|call| call.state == CallState::Ringing).cloned() else { return };
+
+        self.update_call(Call { state: CallState::Ended, ..call.clone() }, ctx);
+        self.send_call_event(call.caller, CallEvent::Timeout, &call);
+        self.send_call_event(call.callee, CallEvent::Missed, &call);
+
+        self.notify_missed_call(&call, ctx);
+    }
 
-                    send_msg(msg._id, payload)
+    fn connect_timeout(&mut self, id: &str, ctx: &mut Context<Self>) {
+        let Some(call) = self.calls.get(id).filter(|call| call.state == CallState::Accepted).cloned() else { return };
+
+        self.update_call(Call { state: CallState::Ended, ..call.clone() }, ctx);
+        self.send_call_event(call.caller, CallEvent::Ended, &call);
+        self.send_call_event(call.callee, CallEvent::Ended, &call);
+    }
+
+    /// Ends the call of the user, if they have one, because they left. With a `device`,
+    /// the call only ends if that device is the one in the call.
+    fn end_call(&mut self, user: ObjectId, device: Option<&str>, ctx: &mut Context<Self>) {
+        let call = self.calls.of_user(&user)
+            .filter(|call| device.is_none_or(|device| call.has_device(user, device)))
+            .cloned();
+        let Some(call) = call else { return };
+
+        self.update_call(Call { state: CallState::Ended, ..call.clone() }, ctx);
+        self.send_call_event(call.other(user), CallEvent::Ended, &call);
+    }
+
+    fn send_call_event(&self, user: ObjectId, event: CallEvent, call: &Call) {
+        self.deliver(user, None, call_message(user, event, call));
+    }
+
+    /// Ends the new call and notifies the callee about it, if they are not connected to any
+    /// instance. The ring is still relayed meanwhile, in case they are connected to another one.
+    fn check_callee_offline(&self, id: String, device: String, ctx: &mut Context<Self>) {
+        let Some(callee) = self.calls.get(&id).map(|call| call.callee) else { return };
+
+        self.cluster.online_elsewhere(callee)
+            .into_actor(self)
+            .map(move |online, act, ctx| {
+                if online.unwrap_or(true) { return }
+
+                // Both this and the ring timeout only act on ringing calls, so the callee is notified once per call
+                let Some(call) = act.calls.get(&id).filter(|call| call.state == CallState::Ringing).cloned() else { return };
+                act.update_call(Call { state: CallState::Ended, ..call.clone() }, ctx);
+
+                let reply = ServerMessage::Call {
+                    event: CallEvent::Offline,
+                    call_id: Some(call.id.clone()),
+                    remote_id: callee.to_hex(),
                 };
+                act.deliver(call.caller, Some(device), reply.to_text());
+
+                act.notify_missed_call(&call, ctx);
+            })
+            .spawn(ctx);
+    }
+
+    /// Sends the push notification of the missed call, unless the caller has caused too many
+    /// of them to the callee lately.
+    fn notify_missed_call(&self, call: &Call, ctx: &mut Context<Self>) {
+        let users_coll = self.users_coll.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let (caller, callee) = (call.caller, call.callee);
+
+        let future = async move {
+            let bucket = format!("missed:account:{}:{}", caller.to_hex(), callee.to_hex());
+            if rate_limiter.hit(&bucket, MISSED_CALL_LIMIT, MISSED_CALL_WINDOW_SECS).await.is_some() {
+                return;
+            }
+
+            let caller = users_coll.find_one(doc!{"_id": caller}, None).await;
+            let callee = users_coll.find_one(doc!{"_id": callee}, None).await;
+
+            if let (Ok(Some(caller)), Ok(Some(callee))) = (caller, callee) {
+                // Opening the notification calls back the caller
+                let title = "Missed call".to_string();
+                let body = format!("Missed call from '{}'.", caller.username);
+                let url = format!("/?call={}", caller._id.to_hex());
+                utils::send_push_notifications_with_url(&users_coll, callee._id, callee.devices, title, body, Some(url)).await;
             }
         };
 
@@ -119,37 +563,389 @@ impl Handler<Signal> for Server {
     }
 }
 
-impl Handler<Disconnect> for Server {
+impl Handler<RoomAction> for Server {
     type Result = ();
 
-    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
-        Rc::get_mut(&mut self.connections)
-            .and_then(|connections| connections.remove(&msg._id));
+    fn handle(&mut self, msg: RoomAction, ctx: &mut Context<Self>) {
+        let RoomAction { _id: user, device, request } = msg;
+
+        let result = match request {
+            RoomRequest::Create => self.rooms.create(user, device.clone()),
+            RoomRequest::Invite { room_id, user_id } => return self.invite_to_room(user, device, room_id, user_id, ctx),
+            RoomRequest::Join { room_id } => self.rooms.join(&room_id, user, device.clone()),
+            RoomRequest::Leave { room_id } => {
+                self.rooms.of_user(&user)
+                    .filter(|room| room.id == room_id)
+                    .and_then(|_| self.rooms.leave(user))
+                    .ok_or(RoomError::NotMember)
+            },
+            RoomRequest::Kick { room_id, user_id } => match self.rooms.kick(&room_id, user, user_id) {
+                Ok(room) => {
+                    let message = ServerMessage::Room(RoomEvent::Kicked { room_id: room_id.clone() });
+                    self.deliver(user_id, None, message.to_text());
+                    Ok(room)
+                },
+                Err(err) => Err(err),
+            },
+            RoomRequest::Lock { room_id, locked } => self.rooms.lock(&room_id, user, locked),
+            RoomRequest::Signal { room_id, remote_id, peer_data } => {
+                return self.relay_room_signal(RoomSignal { user, device, room_id, remote_id, peer_data }, ctx);
+            },
+        };
+
+        match result {
+            Ok(room) => self.update_room(room),
+            Err(err) => self.send_room_error(user, device, err),
+        }
+    }
+}
+
+impl Server {
+    /// Stores the new state of the room on every instance and sends the roster to the members.
+    fn update_room(&mut self, room: Room) {
+        let roster = roster_message(&room);
+
+        for member in &room.members {
+            self.deliver(member.user, Some(member.device.clone()), roster.clone());
+        }
+
+        self.store_room(room.clone());
+        self.cluster.publish(Broadcast::Room { room });
+    }
 
+    /// Invites the user to the room, if they are a friend of the host.
+    fn invite_to_room(&mut self, host: ObjectId, device: String, room_id: String, user: ObjectId, ctx: &mut Context<Self>) {
+        if let Err(err) = self.rooms.invite(&room_id, host, user) {
+            return self.send_room_error(host, device, err);
+        }
+
+        let cached = self.friends.is_friend(&host, &user);
         let users_coll = self.users_coll.clone();
-        let events = self.events.clone();
-        let msg_id = msg._id;
 
         let future = async move {
-            if let Ok(Some(user)) = users_coll.find_one(doc!{"_id": &msg_id}, None).await {
-                emit_event(events.clone(), "logout", Box::new(user._id.to_hex()), &user.friends).ok();
+            match cached {
+                Some(friend) => Some(friend),
+                None => fetch_friends(&users_coll, host).await.map(|friends| friends.contains(&user)),
             }
         };
 
-        future.into_actor(self).spawn(ctx);
+        future.into_actor(self)
+            .map(move |friend, act, _| {
+                // The room might have changed meanwhile
+                let result = match friend {
+                    Some(true) => act.rooms.invite(&room_id, host, user),
+                    _ => Err(RoomError::NotFriend),
+                };
+
+                match result {
+                    Ok(room) => {
+                        let invite = ServerMessage::Room(RoomEvent::Invite { room_id, host_id: host.to_hex() });
+                        act.deliver(user, None, invite.to_text());
+                        act.update_room(room);
+                    },
+                    Err(err) => act.send_room_error(host, device, err),
+                }
+            })
+            .spawn(ctx);
+    }
+
+    /// Relays the signal to the member, unless one of them blocked the other. The members
+    /// do not have to be friends, only the host has to be a friend of everyone.
+    fn relay_room_signal(&mut self, signal: RoomSignal, ctx: &mut Context<Self>) {
+        if let Err(err) = self.rooms.signal_target(&signal.room_id, signal.user, signal.remote_id) {
+            return self.send_room_error(signal.user, signal.device, err);
+        }
+
+        let pair = room_pair(signal.user, signal.remote_id);
+        match self.room_peers.get_mut(&pair) {
+            Some(RoomPeer::Checked(allowed)) => {
+                let allowed = *allowed;
+                return self.deliver_room_signal(signal, allowed);
+            },
+            // Queued, so the signals are delivered in order once the check is done
+            Some(RoomPeer::Checking(queue)) => return queue.push(signal),
+            None => {},
+        }
+
+        // Friends are not blocked in either direction
+        let cached = (self.friends.is_friend(&signal.user, &signal.remote_id) == Some(true)).then_some(false);
+        let users_coll = self.users_coll.clone();
+        let (user, remote_id) = pair;
+
+        let future = async move {
+            if let Some(blocked) = cached { return Some(blocked) }
+
+            let filter = doc!{"$or": [
+                {"_id": &user, "blocked": &remote_id},
+                {"_id": &remote_id, "blocked": &user},
+            ]};
+            users_coll.count_documents(filter, None).await.ok().map(|count| count > 0)
+        };
+
+        self.room_peers.insert(pair, RoomPeer::Checking(vec![signal]));
+
+        future.into_actor(self)
+            .map(move |blocked, act, _| {
+                // Checked again with the next signal, if the database could not be reached
+                let queue = match blocked {
+                    Some(blocked) => act.room_peers.insert(pair, RoomPeer::Checked(!blocked)),
+                    None => act.room_peers.remove(&pair),
+                };
+                let Some(RoomPeer::Checking(queue)) = queue else { return };
+
+                for signal in queue {
+                    act.deliver_room_signal(signal, blocked == Some(false));
+                }
+            })
+            .spawn(ctx);
+    }
+
+    fn deliver_room_signal(&self, signal: RoomSignal, allowed: bool) {
+        let RoomSignal { user, device, room_id, remote_id, peer_data } = signal;
+
+        if !allowed {
+            return self.send_room_error(user, device, RoomError::Blocked);
+        }
+
+        // The room might have changed meanwhile
+        let remote_device = match self.rooms.signal_target(&room_id, user, remote_id) {
+            Ok(member) => member.device.clone(),
+            Err(err) => return self.send_room_error(user, device, err),
+        };
+
+        let message = ServerMessage::Room(RoomEvent::Signal {
+            room_id,
+            remote_id: user.to_hex(),
+            remote_device: device,
+            peer_data,
+        });
+        self.deliver(remote_id, Some(remote_device), message.to_text());
+    }
+
+    /// Stores the room and forgets the checks of the users who are not in a room anymore.
+    fn store_room(&mut self, room: Room) {
+        self.rooms.update(room);
+
+        let rooms = &self.rooms;
+        self.room_peers.retain(|(user, remote), _| rooms.of_user(user).is_some() && rooms.of_user(remote).is_some());
+    }
+
+    /// Removes the user from their room, if they joined it from the device.
+    fn leave_room(&mut self, user: ObjectId, device: &str) {
+        let joined = self.rooms.of_user(&user)
+            .and_then(|room| room.member(&user))
+            .is_some_and(|member| member.device == device);
+
+        if let Some(room) = self.rooms.leave(user).filter(|_| joined) {
+            self.update_room(room);
+        }
+    }
+
+    fn send_room_error(&self, user: ObjectId, device: String, err: RoomError) {
+        let message = ServerMessage::error(ErrorCode::Room, err.to_string());
+        self.deliver(user, Some(device), message.to_text());
+    }
+}
+
+impl Handler<RelayAction> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: RelayAction, ctx: &mut Context<Self>) {
+        self.handle_relay(msg._id, msg.device, msg.request, false, ctx);
+    }
+}
+
+impl Server {
+    /// Applies the relay action, if the relay is kept by this instance. Otherwise it is
+    /// passed on to the other instances, unless it came from one of them.
+    fn handle_relay(&mut self, user: ObjectId, device: String, request: RelayRequest, forwarded: bool, ctx: &mut Context<Self>) {
+        if let RelayRequest::Open { remote_id, size } = request {
+            return self.open_relay(user, device, remote_id, size, ctx);
+        }
+
+        let Some(relay_id) = request.relay_id().map(str::to_string) else { return };
+        let Some(relay) = self.relays.get_mut(&relay_id) else {
+            if let Some(instance) = Relay::instance_of(&relay_id).filter(|_| !forwarded) {
+                self.cluster.publish_to(instance, Broadcast::Relay { user, device, request });
+            }
+            return;
+        };
+
+        let result = match request {
+            RelayRequest::Accept { .. } => relay.accept(user, &device)
+                .map(|_| (relay.sender, Some(relay.sender_device.clone()), RelayEvent::Accepted { relay_id })),
+            RelayRequest::Chunk { data, .. } => chunk_len(&data)
+                .and_then(|len| relay.send_chunk(user, &device, len))
+                .map(|_| (relay.receiver, relay.receiver_device.clone(), RelayEvent::Chunk { relay_id, data })),
+            RelayRequest::Ack { .. } => relay.ack(user, &device)
+                .map(|_| (relay.sender, Some(relay.sender_device.clone()), RelayEvent::Ack { relay_id })),
+            RelayRequest::Close { .. } if relay.is_party(user, &device) || (user == relay.receiver && relay.receiver_device.is_none()) => {
+                if let Some(relay) = self.relays.remove(&relay_id) {
+                    self.send_relay_closed(&relay, relay.other(user).0);
+                }
+                return;
+            },
+            _ => Err(RelayError::NotAllowed),
+        };
+
+        match result {
+            Ok((recipient, recipient_device, event)) => self.deliver(recipient, recipient_device, ServerMessage::Relay(event).to_text()),
+            Err(err) => self.send_relay_error(user, device, err),
+        }
+    }
+
+    /// Offers the transfer to the friend, once its size is taken from the quota of the sender.
+    fn open_relay(&mut self, user: ObjectId, device: String, remote_id: ObjectId, size: u64, ctx: &mut Context<Self>) {
+        if self.relay_quota == 0 {
+            return self.send_relay_error(user, device, RelayError::Disabled);
+        }
+        if size > self.relay_quota {
+            return self.send_relay_error(user, device, RelayError::TooLarge);
+        }
+
+        let cached = self.friends.is_friend(&user, &remote_id);
+        let users_coll = self.users_coll.clone();
+        let rate_limiter = self.rate_limiter.clone();
+        let quota = self.relay_quota;
+
+        let future = async move {
+            let friend = match cached {
+                Some(friend) => friend,
+                None => fetch_friends(&users_coll, user).await.is_some_and(|friends| friends.contains(&remote_id)),
+            };
+            if !friend {
+                return Err(RelayError::NotFriend);
+            }
 
-        Rc::get_mut(&mut self.events)
-            .map( |events| events.iter_mut() )
-            .map( |events| events.for_each(|(_, map)| {map.remove(&msg._id);}) );    
+            let bucket = format!("relay:{}", user.to_hex());
+            match rate_limiter.consume(&bucket, size, quota, RELAY_QUOTA_WINDOW_SECS).await.log_and_map(RelayError::Unavailable)? {
+                Some(retry_after) => Err(RelayError::QuotaExceeded(retry_after)),
+                None => Ok(()),
+            }
+        };
+
+        future.into_actor(self)
+            .map(move |result, act, _| {
+                if let Err(err) = result {
+                    return act.send_relay_error(user, device, err);
+                }
+
+                let relay = Relay::new(act.cluster.instance(), user, device.clone(), remote_id, size);
+                let relay_id = relay.id.clone();
+                act.relays.insert(relay);
+
+                let opened = RelayEvent::Opened { relay_id: relay_id.clone(), remote_id: remote_id.to_hex() };
+                act.deliver(user, Some(device), ServerMessage::Relay(opened).to_text());
+
+                let request = RelayEvent::Request { relay_id, remote_id: user.to_hex(), size };
+                act.deliver(remote_id, None, ServerMessage::Relay(request).to_text());
+            })
+            .spawn(ctx);
+    }
+
+    /// Closes the relays the device takes part in, the other parties are notified.
+    fn close_relays_of_device(&mut self, user: ObjectId, device: &str) {
+        for relay in self.relays.remove_of_device(user, device) {
+            self.send_relay_closed(&relay, relay.other(user).0);
+        }
+    }
+
+    fn close_relays_between(&mut self, user: ObjectId, remote: ObjectId) {
+        for relay in self.relays.remove_between(user, remote) {
+            self.send_relay_closed(&relay, relay.sender);
+            self.send_relay_closed(&relay, relay.receiver);
+        }
+    }
+
+    fn send_relay_closed(&self, relay: &Relay, user: ObjectId) {
+        let device = if user == relay.sender { Some(relay.sender_device.clone()) } else { relay.receiver_device.clone() };
+        let message = ServerMessage::Relay(RelayEvent::Closed { relay_id: relay.id.clone() });
+
+        self.deliver(user, device, message.to_text());
+    }
+
+    fn send_relay_error(&self, user: ObjectId, device: String, err: RelayError) {
+        let message = ServerMessage::error(ErrorCode::Relay, err.to_string());
+        self.deliver(user, Some(device), message.to_text());
+    }
+}
+
+impl Handler<Unfriend> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Unfriend, _: &mut Context<Self>) {
+        // Rejecting the signaling which might be in progress between the two users
+        for (id, remote_id) in [(msg._id, msg.remote_id), (msg.remote_id, msg._id)] {
+            self.deliver(id, None, not_friend(remote_id));
+        }
+
+        // The relays are kept by the instances of the senders
+        self.close_relays_between(msg._id, msg.remote_id);
+        self.cluster.publish(Broadcast::Unfriend { user: msg._id, remote: msg.remote_id });
+    }
+}
+
+impl Handler<Disconnect> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: Disconnect, ctx: &mut Context<Self>) {
+        // The device reconnected meanwhile, the newer connection stays
+        if !self.registry.is_connection(&msg._id, &msg.device, &msg.addr) {
+            return;
+        }
+
+        self.record_last_seen(vec![msg._id], ctx);
+        self.leave_room(msg._id, &msg.device);
+        self.end_call(msg._id, Some(&msg.device), ctx);
+
+        self.close_relays_of_device(msg._id, &msg.device);
+        if self.relay_quota > 0 {
+            self.cluster.publish(Broadcast::Disconnected { user: msg._id, device: msg.device.clone() });
+        }
+
+        // The user is still online on another device
+        if !self.registry.disconnect(msg._id, &msg.device) {
+            return;
+        }
+
+        // A call still ringing on the devices left
+        self.end_call(msg._id, None, ctx);
+
+        if let Some(owner) = self.guests.remove(&msg._id) {
+            self.cluster.publish(Broadcast::Guest { guest: msg._id, owner: None });
+            self.cluster.set_online(msg._id, false);
+            return self.emit_event("guestLogout", msg._id.to_hex(), vec![owner]);
+        }
+
+        let friends = self.friends.remove(&msg._id).map(|friends| friends.into_iter().collect());
+
+        self.cluster.set_online(msg._id, false);
+        self.emit_presence("logout", msg._id, friends, None, ctx);
     }
 }
 
 impl Handler<ConnectedIds> for Server {
-    type Result = Option<Vec<ObjectId>>;
+    type Result = ResponseFuture<Option<Vec<ObjectId>>>;
 
     fn handle(&mut self, _: ConnectedIds, _: &mut Context<Self>) -> Self::Result {
-        let res = self.connections.keys().copied().collect();
-        Some(res)
+        let local = self.registry.users();
+        let online_users = self.cluster.online_users();
+
+        Box::pin(async move {
+            // Answering for this instance only is better than failing, when Redis is down
+            let mut ids = online_users.await.unwrap_or_default();
+            ids.extend(local.into_iter().filter(|id| !ids.contains(id)).collect::<Vec<_>>());
+
+            Some(ids)
+        })
+    }
+}
+
+impl Handler<ConnectedSessions> for Server {
+    type Result = Vec<String>;
+
+    fn handle(&mut self, msg: ConnectedSessions, _: &mut Context<Self>) -> Self::Result {
+        self.registry.session_ids(&msg._id)
     }
 }
 
@@ -157,25 +953,495 @@ impl Handler<Dispatch> for Server {
     type Result = ();
 
     fn handle(&mut self, msg: Dispatch, _: &mut Context<Self>) {
-        self.emit_event(&msg.event, Box::new(msg.payload), &msg.filter);
+        self.emit_event(&msg.event, msg.payload, msg.filter);
     }
 }
 
-fn emit_event<T: Serialize>(events: Rc<HashMap<String, HashMap<ObjectId, Addr<Connection>>>>, event: &str, data: Box<T>, ids: &[ObjectId]) -> Result<(), ()> {
-    let subscribed = events.get(event)
-        .ok_or_else(|| ())?;
+impl Handler<SetPresence> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: SetPresence, ctx: &mut Context<Self>) {
+        let users_coll = self.users_coll.clone();
+        let friends = self.friends.get(&msg._id).map(|friends| friends.iter().copied().collect());
+        let presence = msg.presence.clone();
+
+        let future = async move {
+            let update = doc!{"$set": {"presence": presence}};
+            users_coll.update_one(doc!{"_id": msg._id}, update, None).await
+                .log_and_map(())
+                .ok()?;
+
+            match friends {
+                Some(friends) => Some(friends),
+                None => fetch_friends(&users_coll, msg._id).await,
+            }
+        };
+
+        future.into_actor(self)
+            .map(move |friends, act, _| {
+                if let Some(friends) = friends {
+                    act.emit_event("presence", UserPresence { _id: msg._id, presence: msg.presence }, friends);
+                }
+            })
+            .spawn(ctx);
+    }
+}
+
+impl Handler<FriendsChanged> for Server {
+    type Result = ();
+
+    fn handle(&mut self, msg: FriendsChanged, ctx: &mut Context<Self>) {
+        self.reload_friends(&msg.ids, ctx);
+        self.cluster.publish(Broadcast::FriendsChanged { ids: msg.ids });
+    }
+}
+
+impl StreamHandler<redis::Msg> for Server {
+    fn handle(&mut self, msg: redis::Msg, ctx: &mut Context<Self>) {
+        let Some((instance, broadcast)) = self.cluster.receive(msg) else { return };
+
+        match broadcast {
+            Broadcast::Deliver { user, device, message } => {
+                self.deliver_local(user, device.as_deref(), &message);
+            },
+            Broadcast::Event { event, data, ids } => self.emit_local_event(&event, &data, &ids),
+            Broadcast::Kick { user, session_id } => self.kick_local(user, session_id),
+            Broadcast::FriendsChanged { ids } => self.reload_friends(&ids, ctx),
+            Broadcast::Call { call } => self.calls.update(call),
+            Broadcast::Room { room } => self.store_room(room),
+            Broadcast::Guest { guest, owner: Some(owner) } => { self.guests.insert(guest, owner); },
+            Broadcast::Guest { guest, owner: None } => { self.guests.remove(&guest); },
+            Broadcast::Relay { user, device, request } => self.handle_relay(user, device, request, true, ctx),
+            Broadcast::Disconnected { user, device } => self.close_relays_of_device(user, &device),
+            Broadcast::Unfriend { user, remote } => self.close_relays_between(user, remote),
+            Broadcast::Heartbeat { devices } => self.instances.heartbeat(instance, devices),
+        }
+    }
+
+    fn finished(&mut self, ctx: &mut Context<Self>) {
+        error!("The Redis pub/sub connection was closed, resubscribing");
+        self.resubscribe(RESUBSCRIBE_BACKOFF, ctx);
+    }
+}
+
+impl Server {
+    /// Reconnects to the channels of the cluster, waiting longer after every failed attempt.
+    /// The heartbeat is sent right away, so the others know about this instance again.
+    fn resubscribe(&mut self, backoff: Duration, ctx: &mut Context<Self>) {
+        self.cluster.subscribe()
+            .into_actor(self)
+            .map(move |result, act, ctx| match result {
+                Ok(messages) => {
+                    ctx.add_stream(messages);
+                    act.heartbeat();
+                },
+                Err(err) => {
+                    error!("Resubscribing to Redis failed, retrying in {backoff:?}: {err}");
+                    ctx.run_later(backoff, move |act, ctx| {
+                        act.resubscribe((backoff * 2).min(MAX_RESUBSCRIBE_BACKOFF), ctx);
+                    });
+                },
+            })
+            .spawn(ctx);
+    }
+}
+
+async fn fetch_friends(users_coll: &Collection<User>, id: ObjectId) -> Option<Vec<ObjectId>> {
+    let user = users_coll.find_one(doc!{"_id": id}, None).await.ok()??;
+    utils::visible_friends(users_coll, &user).await.ok()
+}
+
+// Blocking works in both directions, so the pairs are stored in the same order
+fn room_pair(user: ObjectId, remote: ObjectId) -> (ObjectId, ObjectId) {
+    if user < remote { (user, remote) } else { (remote, user) }
+}
+
+fn roster_message(room: &Room) -> String {
+    ServerMessage::Room(RoomEvent::Roster {
+        room_id: room.id.clone(),
+        host_id: room.host.to_hex(),
+        members: room.members.iter()
+            .map(|member| RoomMember { user_id: member.user.to_hex(), device: member.device.clone() })
+            .collect(),
+        locked: room.locked,
+    }).to_text()
+}
+
+fn call_message(user: ObjectId, event: CallEvent, call: &Call) -> String {
+    ServerMessage::Call {
+        event,
+        call_id: Some(call.id.clone()),
+        remote_id: call.other(user).to_hex(),
+    }.to_text()
+}
+
+fn not_friend(remote_id: ObjectId) -> String {
+    ServerMessage::Signal(ServerSignal::Error {
+        error: "Not friend".to_string(),
+        remote_id: remote_id.to_hex(),
+    }).to_text()
+}
+
+#[cfg(test)]
+mod tests {
+    use actix::{Actor, Addr};
+    use actix_web::{error::PayloadError, web::Bytes};
+    use actix_web_actors::ws::WebsocketContext;
+    use futures::{stream::{self, LocalBoxStream}, FutureExt, StreamExt};
+    use mongodb::{options::ClientOptions, Client};
+    use crate::ws::{Connection, PROTOCOL_VERSION};
+    use super::*;
+
+    async fn start_server() -> Addr<Server> {
+        start_server_with(RateLimiter::detached(), 0).await
+    }
+
+    async fn start_server_with(rate_limiter: RateLimiter, relay_quota: u64) -> Addr<Server> {
+        // Nothing listens there, so the lookups of the server fail
+        let options = ClientOptions::parse("mongodb://127.0.0.1:9").await.unwrap();
+        let users_coll = Client::with_options(options).unwrap().database("speer").collection("users");
+
+        Server::new(users_coll, Cluster::detached(), rate_limiter, relay_quota).start()
+    }
+
+    /// Stands in for the friends, which can not be loaded without the database.
+    #[derive(actix::Message)]
+    #[rtype(result = "()")]
+    struct SetFriends(ObjectId, Vec<ObjectId>);
+
+    impl Handler<SetFriends> for Server {
+        type Result = ();
+
+        fn handle(&mut self, msg: SetFriends, _: &mut Context<Self>) {
+            self.friends.set(msg.0, msg.1);
+        }
+    }
+
+    /// A connection without a client, the frames it would send are collected instead.
+    struct TestConnection {
+        addr: Addr<Connection>,
+        device: String,
+        // Polling the output runs the actor of the connection
+        output: LocalBoxStream<'static, Result<Bytes, actix_web::Error>>,
+        texts: Vec<String>,
+    }
+
+    impl TestConnection {
+        fn start(connection: Connection) -> TestConnection {
+            TestConnection::receiving(connection, vec![])
+        }
+
+        /// Starts the connection with the frames sent by the client.
+        fn receiving(connection: Connection, frames: Vec<Bytes>) -> TestConnection {
+            let device = connection.device().to_string();
+            let input = stream::iter(frames.into_iter().map(Ok::<_, PayloadError>)).chain(stream::pending());
+            let (addr, output) = WebsocketContext::create_with_addr(connection, input);
+
+            TestConnection { addr, device, output: output.boxed_local(), texts: vec![] }
+        }
+
+        /// Runs the connection until it is idle.
+        fn poll(&mut self) {
+            while let Some(Some(Ok(bytes))) = self.output.next().now_or_never() {
+                self.texts.extend(text_frames(&bytes));
+            }
+        }
+
+        /// The peer data of the signals received so far.
+        fn signals(&self) -> Vec<String> {
+            self.messages("signal", "peerData")
+        }
+
+        /// The call events received so far.
+        fn call_events(&self) -> Vec<String> {
+            self.messages("call", "event")
+        }
+
+        fn errors(&self) -> Vec<String> {
+            self.messages("error", "message")
+        }
+
+        fn relay_events(&self) -> Vec<serde_json::Value> {
+            self.texts.iter()
+                .filter_map(|text| serde_json::from_str::<serde_json::Value>(text).ok())
+                .filter(|message| message["msgType"] == "relay")
+                .collect()
+        }
 
-    ids.iter().map(|id| subscribed.get(&id))
-        .filter(|addr| addr.is_some())
-        .for_each(|addr| {
-            let message = json!({
-                "event": event,
-                "data": data,
-                "msgType": "pusher"
-            }).to_string();
+        fn messages(&self, msg_type: &str, field: &str) -> Vec<String> {
+            self.texts.iter()
+                .filter_map(|text| serde_json::from_str::<serde_json::Value>(text).ok())
+                .filter(|message| message["msgType"] == msg_type)
+                .filter_map(|message| message[field].as_str().map(str::to_string))
+                .collect()
+        }
+    }
+
+    async fn settle(connections: &mut [&mut TestConnection]) {
+        for _ in 0..3 {
+            connections.iter_mut().for_each(|connection| connection.poll());
+            actix::clock::sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    // The frames of the clients have to be masked, a zero mask leaves the payload as it is
+    fn client_frame(opcode: u8, payload: &str) -> Bytes {
+        assert!(payload.len() < 126);
+
+        let mut frame = vec![0x80 | opcode, 0x80 | payload.len() as u8, 0, 0, 0, 0];
+        frame.extend_from_slice(payload.as_bytes());
+        Bytes::from(frame)
+    }
+
+    fn hello(version: u32) -> Bytes {
+        client_frame(1, &format!(r#"{{"msgType":"hello","version":{version}}}"#))
+    }
+
+    // The frames of the server are not masked
+    fn text_frames(mut bytes: &[u8]) -> Vec<String> {
+        let mut texts = vec![];
+
+        while bytes.len() >= 2 {
+            let (len, start) = match bytes[1] & 0x7f {
+                126 => (u16::from_be_bytes([bytes[2], bytes[3]]) as usize, 4),
+                127 => (u64::from_be_bytes(bytes[2..10].try_into().unwrap()) as usize, 10),
+                len => (len as usize, 2),
+            };
+
+            if bytes[0] & 0x0f == 1 {
+                texts.push(String::from_utf8_lossy(&bytes[start..start + len]).to_string());
+            }
+            bytes = &bytes[start + len..];
+        }
+
+        texts
+    }
 
-            addr.unwrap().do_send(Send(message));
+    #[actix_web::test]
+    async fn a_stale_disconnect_leaves_the_reconnected_device_alone() {
+        let server = start_server().await;
+        let owner = ObjectId::new();
+        let guest = User { username: "guest".to_string(), ..User::default() };
+
+        let mut old = TestConnection::start(Connection::guest(guest.clone(), owner, server.clone()));
+        let mut new = TestConnection::start(Connection::guest(guest.clone(), owner, server.clone()));
+        settle(&mut [&mut old, &mut new]).await;
+
+        let connect = |connection: &TestConnection| Connect {
+            addr: connection.addr.clone(),
+            user: guest.clone(),
+            device: "phone".to_string(),
+            session_id: None,
+            guest_of: Some(owner),
+        };
+        let signal = |peer_data: &str| Signal {
+            _id: owner,
+            device: "laptop".to_string(),
+            action: SignalAction::Signal,
+            peer_data: peer_data.to_string(),
+            remote_id: guest._id,
+            remote_device: Some("phone".to_string()),
+            r#type: "data".to_string(),
+            data: None,
+        };
+
+        // The device reconnects, while the disconnect of its old connection is still on the way
+        server.do_send(connect(&old));
+        server.do_send(signal("first"));
+        server.do_send(connect(&new));
+        server.do_send(signal("second"));
+        server.do_send(Disconnect { addr: old.addr.clone(), _id: guest._id, device: "phone".to_string() });
+        server.do_send(signal("third"));
+        settle(&mut [&mut old, &mut new]).await;
+
+        assert_eq!(old.signals(), vec!["first".to_string()]);
+        assert_eq!(new.signals(), vec!["second".to_string(), "third".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn changed_friends_are_not_signaled_from_the_cache() {
+        let server = start_server().await;
+        let (user, friend) = (User::default(), User::default());
+
+        let mut phone = TestConnection::start(Connection::new(user.clone(), None, server.clone()));
+        let mut laptop = TestConnection::start(Connection::new(friend.clone(), None, server.clone()));
+        settle(&mut [&mut phone, &mut laptop]).await;
+
+        let signal = |peer_data: &str| Signal {
+            _id: user._id,
+            device: phone.device.clone(),
+            action: SignalAction::Signal,
+            peer_data: peer_data.to_string(),
+            remote_id: friend._id,
+            remote_device: None,
+            r#type: "data".to_string(),
+            data: None,
+        };
+
+        server.do_send(SetFriends(user._id, vec![friend._id]));
+        server.do_send(signal("before"));
+        // Unfriending or blocking reloads the friends, meanwhile the database has to be asked
+        server.do_send(FriendsChanged { ids: vec![user._id, friend._id] });
+        server.do_send(signal("after"));
+        settle(&mut [&mut phone, &mut laptop]).await;
+
+        assert_eq!(laptop.signals(), vec!["before".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn the_hello_is_answered_with_the_device() {
+        let server = start_server().await;
+        let mut connection = TestConnection::receiving(Connection::new(User::default(), None, server), vec![hello(PROTOCOL_VERSION)]);
+        settle(&mut [&mut connection]).await;
+
+        assert_eq!(connection.messages("welcome", "device"), vec![connection.device.clone()]);
+        assert!(connection.errors().is_empty());
+    }
+
+    #[actix_web::test]
+    async fn another_version_is_refused() {
+        let server = start_server().await;
+        let frames = vec![hello(PROTOCOL_VERSION + 1), client_frame(1, r#"{"msgType":"presence","status":"away"}"#)];
+        let mut connection = TestConnection::receiving(Connection::new(User::default(), None, server), frames);
+        settle(&mut [&mut connection]).await;
+
+        assert!(connection.messages("welcome", "device").is_empty());
+        // The connection is closed, the message after the hello is not read anymore
+        assert_eq!(connection.messages("error", "code"), vec!["unsupportedVersion".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn messages_before_the_hello_are_refused() {
+        let server = start_server().await;
+        let frames = vec![client_frame(1, r#"{"msgType":"presence","status":"away"}"#)];
+        let mut connection = TestConnection::receiving(Connection::new(User::default(), None, server), frames);
+        settle(&mut [&mut connection]).await;
+
+        assert_eq!(connection.messages("error", "code"), vec!["handshakeRequired".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn binary_frames_are_refused() {
+        let server = start_server().await;
+        let frames = vec![hello(PROTOCOL_VERSION), client_frame(2, "binary")];
+        let mut connection = TestConnection::receiving(Connection::new(User::default(), None, server), frames);
+        settle(&mut [&mut connection]).await;
+
+        assert_eq!(connection.messages("error", "code"), vec!["protocol".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn the_server_keeps_running_while_resubscribing_to_redis() {
+        let options = ClientOptions::parse("mongodb://127.0.0.1:9").await.unwrap();
+        let users_coll = Client::with_options(options).unwrap().database("speer").collection("users");
+
+        // The pub/sub connection ends at once, and resubscribing fails without Redis
+        let server = Server::create(|ctx| {
+            ctx.add_stream(stream::empty::<redis::Msg>());
+            Server::new(users_coll, Cluster::detached(), RateLimiter::detached(), 0)
         });
 
-    Ok(())
-}
\ No newline at end of file
+        let user = User::default();
+        let mut connection = TestConnection::start(Connection::new(user.clone(), None, server.clone()));
+        settle(&mut [&mut connection]).await;
+
+        assert_eq!(server.send(ConnectedIds).await.unwrap(), Some(vec![user._id]));
+    }
+
+    #[actix_web::test]
+    async fn the_call_ends_with_its_device_although_another_one_stays() {
+        let server = start_server().await;
+        let owner = User { username: "owner".to_string(), ..User::default() };
+        let guest = User { username: "guest".to_string(), ..User::default() };
+
+        let mut desk = TestConnection::start(Connection::new(owner.clone(), None, server.clone()));
+        let mut phone = TestConnection::start(Connection::guest(guest.clone(), owner._id, server.clone()));
+        let mut laptop = TestConnection::start(Connection::guest(guest.clone(), owner._id, server.clone()));
+        settle(&mut [&mut desk, &mut phone, &mut laptop]).await;
+
+        let signal = |user: &User, connection: &TestConnection, remote: &User, action: SignalAction, peer_data: &str| Signal {
+            _id: user._id,
+            device: connection.device.clone(),
+            action,
+            peer_data: peer_data.to_string(),
+            remote_id: remote._id,
+            remote_device: None,
+            r#type: "call".to_string(),
+            data: None,
+        };
+
+        // The call is accepted and connected on the phone, the laptop of the guest stays online
+        server.do_send(signal(&owner, &desk, &guest, SignalAction::Ring, "ring"));
+        server.do_send(signal(&guest, &phone, &owner, SignalAction::Accept, "accept"));
+        server.do_send(signal(&guest, &phone, &owner, SignalAction::Signal, "answer"));
+        server.do_send(Disconnect { addr: phone.addr.clone(), _id: guest._id, device: phone.device.clone() });
+        settle(&mut [&mut desk, &mut phone, &mut laptop]).await;
+
+        assert_eq!(laptop.signals(), vec!["ring".to_string()]);
+        assert_eq!(laptop.call_events(), vec!["answered".to_string()]);
+        assert_eq!(desk.call_events(), vec!["ended".to_string()]);
+
+        // The guest is no longer busy
+        server.do_send(signal(&owner, &desk, &guest, SignalAction::Ring, "ring again"));
+        settle(&mut [&mut desk, &mut laptop]).await;
+
+        assert_eq!(laptop.signals(), vec!["ring".to_string(), "ring again".to_string()]);
+        assert_eq!(desk.call_events(), vec!["ended".to_string()]);
+    }
+
+    #[actix_web::test]
+    async fn files_are_relayed_to_the_device_which_accepted() {
+        let server = start_server_with(RateLimiter::unlimited(), 1024).await;
+        let sender = User { username: "sender".to_string(), ..User::default() };
+        let receiver = User { username: "receiver".to_string(), ..User::default() };
+
+        let mut desk = TestConnection::start(Connection::new(sender.clone(), None, server.clone()));
+        let mut phone = TestConnection::start(Connection::new(receiver.clone(), None, server.clone()));
+        let mut laptop = TestConnection::start(Connection::new(receiver.clone(), None, server.clone()));
+        settle(&mut [&mut desk, &mut phone, &mut laptop]).await;
+
+        server.do_send(SetFriends(sender._id, vec![receiver._id]));
+        server.do_send(SetFriends(receiver._id, vec![sender._id]));
+
+        let relay = |user: &User, connection: &TestConnection, request: RelayRequest| RelayAction {
+            _id: user._id,
+            device: connection.device.clone(),
+            request,
+        };
+
+        server.do_send(relay(&sender, &desk, RelayRequest::Open { remote_id: receiver._id, size: 5 }));
+        settle(&mut [&mut desk, &mut phone, &mut laptop]).await;
+
+        let opened = desk.relay_events();
+        assert_eq!(opened.len(), 1);
+        assert_eq!(opened[0]["event"], "opened");
+        let relay_id = opened[0]["relayId"].as_str().unwrap().to_string();
+
+        // Every device of the receiver is asked, the first one accepting gets the chunks
+        for connection in [&phone, &laptop] {
+            let request = &connection.relay_events()[0];
+            assert_eq!(request["event"], "request");
+            assert_eq!(request["relayId"], relay_id.as_str());
+            assert_eq!(request["size"], 5);
+        }
+
+        server.do_send(relay(&receiver, &phone, RelayRequest::Accept { relay_id: relay_id.clone() }));
+        server.do_send(relay(&sender, &desk, RelayRequest::Chunk { relay_id: relay_id.clone(), data: "AAEC".to_string() }));
+        // Neither characters nor more than the declared bytes get through
+        server.do_send(relay(&sender, &desk, RelayRequest::Chunk { relay_id: relay_id.clone(), data: "ééé".to_string() }));
+        server.do_send(relay(&sender, &desk, RelayRequest::Chunk { relay_id: relay_id.clone(), data: "AAEC".to_string() }));
+        server.do_send(relay(&receiver, &phone, RelayRequest::Ack { relay_id: relay_id.clone() }));
+        server.do_send(relay(&sender, &desk, RelayRequest::Chunk { relay_id: relay_id.clone(), data: "AAE=".to_string() }));
+        server.do_send(relay(&sender, &desk, RelayRequest::Close { relay_id: relay_id.clone() }));
+        settle(&mut [&mut desk, &mut phone, &mut laptop]).await;
+
+        let events = |connection: &TestConnection| connection.relay_events().iter()
+            .map(|event| format!("{} {}", event["event"].as_str().unwrap(), event["data"].as_str().unwrap_or_default()).trim().to_string())
+            .collect::<Vec<_>>();
+
+        assert_eq!(events(&desk), vec!["opened", "accepted", "ack"]);
+        assert_eq!(desk.errors(), vec![RelayError::InvalidChunk.to_string(), RelayError::SizeExceeded.to_string()]);
+        assert_eq!(events(&phone), vec!["request", "chunk AAEC", "chunk AAE=", "closed"]);
+        assert_eq!(events(&laptop), vec!["request"]);
+    }
+}
@@ -1,5 +1,10 @@
 import Pusher from '../plugins/pusher'
 import PeerClient from '../plugins/peerclient'
+import RoomClient from '../plugins/roomclient'
+import RelayClient from '../plugins/relayclient'
+
+// The version of the WebSocket protocol spoken with the backend
+const PROTOCOL_VERSION = 1
 
 export default ({$axios, redirect, store, $config}) => {
   return new Promise( (resolve, reject) => {
@@ -10,17 +15,25 @@ export default ({$axios, redirect, store, $config}) => {
     let socket = new WebSocket(wsAddress.href)
     let pusher = new Pusher()
     let peerClient = new PeerClient()
+    let roomClient = new RoomClient()
+    let relayClient = new RelayClient()
 
     Promise.all([
       $axios.$get('/me'),
       $axios.$get('/friends'),
-      initSocket(socket, pusher, peerClient),
+      $axios.$get('/iceServers'),
+      initSocket(socket, pusher, peerClient, roomClient, relayClient),
     ])
-    .then( ([user, friends]) => {
+    .then( ([user, friends, iceServers]) => {
+      peerClient.setIceServers(iceServers)
+      roomClient.setIceServers(iceServers)
+      roomClient.setUserId(user._id)
       store.dispatch('setUser', user)
       store.dispatch('setFriends', friends)
       store.dispatch('setPusher', pusher)
       store.dispatch('setPeerClient', peerClient)
+      store.dispatch('room/setClient', roomClient)
+      store.dispatch('setRelayClient', relayClient)
 
       resolve()
     })
@@ -31,11 +44,15 @@ export default ({$axios, redirect, store, $config}) => {
   })
 }
 
-function initSocket(socket, pusher, peerClient) {
+function initSocket(socket, pusher, peerClient, roomClient, relayClient) {
   return new Promise((resolve, reject) => {
     socket.addEventListener('open', () => {
+      socket.send(JSON.stringify({msgType: 'hello', version: PROTOCOL_VERSION}))
+
       pusher.init(socket)
       peerClient.init(socket)
+      roomClient.init(socket)
+      relayClient.init(socket)
 
       resolve()
     })
@@ -2,14 +2,17 @@ const Peer = require('simple-peer')
 
 // A class that handles the signaling works to get connected with remote peers.
 export default class PeerClient {
-  constructor({onClose = null, maxRetryCount = 10, retryFrequency = 10000} = {}) {
+  constructor({onClose = null, maxRetryCount = 10, retryFrequency = 10000, connectTimeout = 20000} = {}) {
     this._signaling    = {}
+    this._calls        = {}
     this._maxRetryCount   = maxRetryCount
     this._retryFrequency  = retryFrequency
+    this._connectTimeout  = connectTimeout
 
     this._subscriptions   = {}
     this._open            = false
     this._retryCount      = 0
+    this._iceServers      = null
 
     if(onClose) this._onClose = onClose
   }
@@ -26,35 +29,58 @@ export default class PeerClient {
     // Handling message from signal server
     this._socket.addEventListener('message', event => {
       let data = JSON.parse(event.data)
-      if(data.msgType != 'signal') return
+      if(data.msgType == 'call') return this._handleCallEvent(data)
+      // The signals of the relayed transfers are handled by the relay client
+      if(data.msgType != 'signal' || data.type == 'relay') return
 
       if(data.error == 'Not friend') {
-        if(this._signaling[data.remoteId]) {
-          this._signaling[data.remoteId].reject(data.error)
-          delete this._signaling[data.remoteId]
+        for(let key of this._keysOf(this._signaling, data.remoteId)) {
+          this._signaling[key].reject(data.error)
+          delete this._signaling[key]
         }
         
         return
       }
 
-      if(!this._signaling[data.remoteId]) this._handleNewSignalConnection(data)
-      else this._handleReceivedRequestedSignalData(data)
+      // The call actions only drive the state of the call on the server, except the ring
+      if(data.action && data.action != 'signal') {
+        let call = this._calls[this._key(data.remoteId, data.remoteDevice)]
+        if(data.action == 'ring' && call) call._handleRing()
+        return
+      }
+
+      let key = this._key(data.remoteId, data.remoteDevice)
+      if(!this._signaling[key]) this._claimSignaling(data)
+      // A new offer replaces the signaling which never connected, like that of a device whose answer was dropped
+      else if(JSON.parse(data.peerData).type == 'offer') this._dropSignaling(key)
+
+      if(this._signaling[key]) this._handleReceivedRequestedSignalData(data)
+      // Only offers start a connection, the answers of the other devices of the remote user are dropped
+      else if(JSON.parse(data.peerData).type == 'offer') this._handleNewSignalConnection(data)
     })
   }
 
+  // The STUN and TURN servers from the backend, the defaults of Simple-Peer are used until they are set
+  setIceServers(iceServers) {
+    this._iceServers = iceServers
+  }
+
   // Creating connection with a remote peer
   async createConnection(remoteId) {
-    return new Connection(await this._signal({remoteId, initiator: true, type: 'basic'}))
+    let {peer} = await this._signal({remoteId, initiator: true, type: 'basic'})
+    return new Connection(peer)
   }
 
   // Creating file connection with a remote peer
   async createFileConnection(remoteId) {
-    return new FileConnection(await this._signal({remoteId, initiator: true, type: 'binary'}))
+    let {peer} = await this._signal({remoteId, initiator: true, type: 'binary'})
+    return new FileConnection(peer)
   }
 
   // Creating call connection with a remote peer
   async createCallConnection(remoteId) {
-    return new CallConnection(await this._signal({remoteId, initiator: true, type: 'call'}))
+    let {peer, remoteDevice} = await this._signal({remoteId, initiator: true, type: 'call'})
+    return this._createCallConnection(remoteId, remoteDevice, peer)
   }
 
   destroy() {
@@ -63,47 +89,100 @@ export default class PeerClient {
 
   // Handling a connection request from a remote peer
   async _handleNewSignalConnection(data) {
-    let peer = await this._signal({remoteId: data.remoteId, type: data.type, peerData: data.peerData})
+    let signaled = await this._signal({remoteId: data.remoteId, remoteDevice: data.remoteDevice, type: data.type, peerData: data.peerData})
+      .catch(error => console.warn('[PeerClient] - Could not connect to the remote peer:', error))
+    if(!signaled) return
+
+    let {peer} = signaled
 
     switch(data.type) {
       case 'binary': this.onFileConnection({connection: new FileConnection(peer), remoteId: data.remoteId}); break;
-      case 'call': this.onCallConnection({connection: new CallConnection(peer), remoteId: data.remoteId}); break;
+      case 'call': this.onCallConnection({connection: this._createCallConnection(data.remoteId, data.remoteDevice, peer), remoteId: data.remoteId}); break;
       default: this.onConnection({connection: new Connection(peer), remoteId: data.remoteId})
     }
   }
 
+  // The server tracks the state of the calls, so the call actions are sent to it as well
+  _createCallConnection(remoteId, remoteDevice, peer) {
+    let connection = new CallConnection(peer, action => this._send({action, remoteId, remoteDevice, peerData: '', type: 'call'}))
+    this._calls[this._key(remoteId, remoteDevice)] = connection
+
+    return connection
+  }
+
+  // Handling the events of the server about a call: busy, offline, timeout, missed, ended, answered
+  _handleCallEvent(data) {
+    for(let key of this._keysOf(this._calls, data.remoteId)) this._calls[key]._handleCallEvent(data.event)
+  }
+
   // Feeding the remote peer's requested data to Simple-Peer
   async _handleReceivedRequestedSignalData(data) {
-    this._signaling[data.remoteId].peer.signal(JSON.parse(data.peerData))
+    this._signaling[this._key(data.remoteId, data.remoteDevice)].peer.signal(JSON.parse(data.peerData))
+  }
+
+  // An offer goes to every device of the remote user, the device answering first is the one connected to
+  _claimSignaling(data) {
+    let pending = this._signaling[this._key(data.remoteId)]
+    if(!pending || JSON.parse(data.peerData).type != 'answer') return
+
+    delete this._signaling[this._key(data.remoteId)]
+    pending.remoteDevice = data.remoteDevice
+    this._signaling[this._key(data.remoteId, data.remoteDevice)] = pending
   }
 
+  _dropSignaling(key) {
+    let signaling = this._signaling[key]
+    delete this._signaling[key]
+
+    signaling.peer.destroy()
+    signaling.reject('Replaced')
+  }
+
+  // The signaling state is kept per device of the remote user, the key of an offer sent to all of them has no device
+  _key(remoteId, remoteDevice = null) {
+    return `${remoteId}/${remoteDevice || ''}`
+  }
+
+  _keysOf(table, remoteId) {
+    return Object.keys(table).filter(key => key.startsWith(`${remoteId}/`))
+  }
 
   // Sending connection request to remote peer / feeding new peerData to Simple-Peer
-  _signal({remoteId, type, initiator = false, peerData = null}) {
+  _signal({remoteId, remoteDevice = null, type, initiator = false, peerData = null}) {
     return new Promise( (resolve, reject) => {
-      this._signaling[remoteId] = {resolve, reject} // this needs to exist before the peer is created
-      this._signaling[remoteId].peer = this._createPeerForSignaling(remoteId, type, initiator)
+      let signaling = {remoteDevice, resolve, reject}
+      this._signaling[this._key(remoteId, remoteDevice)] = signaling // this needs to exist before the peer is created
+      signaling.peer = this._createPeerForSignaling(remoteId, signaling, type, initiator)
       
-      if(peerData) this._signaling[remoteId].peer.signal(JSON.parse(peerData))
+      if(peerData) signaling.peer.signal(JSON.parse(peerData))
     })
   }
 
-  _createPeerForSignaling(remoteId, type, initiator) {
-    let peer = new Peer({initiator, trickle: true})
+  _createPeerForSignaling(remoteId, signaling, type, initiator) {
+    let config = this._iceServers ? {iceServers: this._iceServers} : undefined
+    let peer = new Peer({initiator, trickle: true, config})
     
+    // The device is not known until the first answer, the signals before it go to every device
     peer.on('signal', peerData => {
       this._send({
         action: 'signal',
         remoteId: remoteId,
+        remoteDevice: signaling.remoteDevice,
         peerData: JSON.stringify(peerData),
         type,
       })
     })
     
+    // The peers might never connect, e.g. if both of them are behind strict NATs
+    let timeout = setTimeout(() => this._failSignaling(remoteId, signaling, 'Timeout'), this._connectTimeout)
+    peer.on('error', error => this._failSignaling(remoteId, signaling, error))
+
     peer.on('connect', () => {
+      clearTimeout(timeout)
+
       setTimeout(() => {
-        this._signaling[remoteId].resolve(peer)
-        delete this._signaling[remoteId]
+        signaling.resolve({peer, remoteDevice: signaling.remoteDevice})
+        delete this._signaling[this._key(remoteId, signaling.remoteDevice)]
       }, 100)
       
       peer.removeAllListeners('signal')
@@ -112,6 +191,16 @@ export default class PeerClient {
     return peer
   }
 
+  // Only the signaling still in progress fails, the errors of connected peers are handled by their connections
+  _failSignaling(remoteId, signaling, error) {
+    let key = this._key(remoteId, signaling.remoteDevice)
+    if(this._signaling[key] != signaling) return
+
+    delete this._signaling[key]
+    signaling.peer.destroy()
+    signaling.reject(error)
+  }
+
   _onCloseHandler(event) {
     this._open = false
     this._onClose(event)
@@ -136,7 +225,7 @@ export default class PeerClient {
   
   // Sending message to signal server
   _send(data) {
-    this._socket.send(JSON.stringify(data))
+    this._socket.send(JSON.stringify({msgType: 'signal', ...data}))
   }
 
   // TO IMPLEMENT BY DEVELOPER
@@ -436,7 +525,7 @@ class FileConnection {
   onClose() { Logger.log('You have to implement the !<onClose>! function yourself!') }
 }
 
-class FileSendRequest {
+export class FileSendRequest {
   constructor(fileData) {
     this.file = fileData
   }
@@ -455,8 +544,14 @@ class FileSendRequest {
 }
 
 class CallConnection {
-  constructor(peer) {
+  constructor(peer, sendAction) {
     this.isInCall = false
+    this._sendAction = sendAction
+
+    // The request is only shown once the server rang, it does not ring busy users
+    this._request = null
+    this._rung = false
+    this._ringing = false
 
     this.peer = peer
     this.configConnection = new Connection(peer)
@@ -480,6 +575,7 @@ class CallConnection {
       this.stream = stream
       this.callResolve = resolve
 
+      this._sendAction('ring')
       this.configConnection.send({
         type: 'request',
         data,
@@ -492,6 +588,7 @@ class CallConnection {
 
   end() {
     this._resetCall(true)
+    this._sendAction('hangup')
     this.configConnection.send({type: 'end'})
   }
 
@@ -505,15 +602,68 @@ class CallConnection {
     switch(data.type) {
       case 'request': this._handleRequest(data); break;
       case 'answer': this._handleRequestAnswer(data); break;
-      case 'end': this._resetCall(); break;
+      case 'end': this._handleEnd(); break;
     }
   }
 
   _handleRequest(data) {
-    const request = new CallRequest(data.data)
+    this._request = data
+    this._showRequest()
+  }
+
+  _handleRing() {
+    this._rung = true
+    this._showRequest()
+  }
+
+  _handleEnd() {
+    if(this.isInCall || this._ringing) return this._resetCall()
+
+    // The call was cancelled before it rang
+    this._request = null
+    this._rung = false
+  }
+
+  _handleCallEvent(event) {
+    switch(event) {
+      case 'busy':
+      case 'offline':
+      case 'timeout':
+        if(!this.isInCall) return
+
+        this.isInCall = false
+        this.configConnection.send({type: 'end'})
+        this.onUnanswered(event)
+        break
+      case 'missed':
+      case 'ended':
+        if(this.isInCall || this._ringing) this._resetCall()
+        break
+      // Another device of ours answered, this one stops ringing
+      case 'answered':
+        if(!this._ringing) return
+
+        this._ringing = false
+        this.onAnswered()
+        break
+    }
+  }
+
+  _showRequest() {
+    if(!this._request || !this._rung) return
+
+    const request = new CallRequest(this._request.data)
+    this._request = null
+    this._rung = false
+    this._ringing = true
+
     request._answered()
       .then( accepted => {
+        if(!this._ringing) return
+        this._ringing = false
+
         if(!accepted) {
+          this._sendAction('decline')
           return this.configConnection.send({
             type: 'answer',
             data: 'decline',
@@ -521,6 +671,7 @@ class CallConnection {
         }
         
         this._waitForCall(accepted)
+        this._sendAction('accept')
         this.configConnection.send({
           type: 'answer',
           data: 'accept',
@@ -563,6 +714,7 @@ class CallConnection {
 
     if(!initiator) this.onEnd()
     this.isInCall = false
+    this._ringing = false
   }
 
   _handleChannelError(error) {
@@ -574,10 +726,12 @@ class CallConnection {
 
   // TO IMPLEMENT BY DEVELOPER
   onDecline() { Logger.log('You have to implement the !<onDecline>! function yourself!') }
+  onUnanswered() { Logger.log('You have to implement the !<onUnanswered>! function yourself!') }
   onRequest() { Logger.log('You have to implement the !<onRequest>! function yourself!') }
   onStream() { Logger.log('You have to implement the !<onStream>! function yourself!')}
   onTrack() { Logger.log('You have to implement the !<onTrack>! function yourself!')}
   onEnd() { Logger.log('You have to implement the !<onEnd>! function yourself!')}
+  onAnswered() { Logger.log('You have to implement the !<onAnswered>! function yourself!')}
   onClose() { Logger.log('You have to implement the !<onClose>! function yourself!') }
 }
 
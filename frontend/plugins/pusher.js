@@ -84,6 +84,6 @@ export default class Pusher {
   _onClose() { console.warn('[Pusher] - Connection with the real-time server closed.') }
 
   _send(data) {
-    this._socket.send(JSON.stringify(data))
+    this._socket.send(JSON.stringify({msgType: 'pusher', ...data}))
   }
 }
\ No newline at end of file
@@ -0,0 +1,454 @@
+import {FileSendRequest} from './peerclient'
+
+// The bytes of the file in a chunk, the IV and the tag of AES-GCM are added to them
+const CHUNK_SIZE = 16 * 1024
+const IV_LENGTH = 12
+const CHUNK_OVERHEAD = IV_LENGTH + 16
+// The chunks which can be unacknowledged, the server does not allow more
+const WINDOW = 16
+
+const ECDH = {name: 'ECDH', namedCurve: 'P-256'}
+const AES_GCM = {name: 'AES-GCM', length: 256}
+
+// A class that sends files through the server, when two friends can not connect directly.
+// The server only passes the chunks on, they are encrypted end-to-end:
+// - the sender opens a relay and signals its ECDH public key to every device of the receiver
+// - each device answers with its own public key, the two derive a shared AES-GCM key
+// - the sender sends the key of the file and its details to each device, encrypted with the shared key
+// - the device accepting the transfer gets the chunks, encrypted with the key of the file
+export default class RelayClient {
+  constructor() {
+    this._connections = {}
+    this._transfers   = {}
+    // The outgoing transfers waiting for the server to open their relays, in order
+    this._opening     = []
+    // The signals of the senders, which arrived before the request of their relays
+    this._early       = {}
+  }
+
+  init(socket) {
+    this._socket = socket
+
+    this._socket.addEventListener('message', event => {
+      let data = JSON.parse(event.data)
+
+      if(data.msgType == 'relay') this._handleEvent(data)
+      else if(data.msgType == 'signal' && data.type == 'relay' && data.peerData) this._handleSignal(data)
+      else if(data.msgType == 'error' && data.code == 'relay') this._handleError(data.message)
+    })
+  }
+
+  // The connection works like the file connections of the peer client
+  createFileConnection(remoteId) {
+    if(!this._connections[remoteId]) this._connections[remoteId] = new RelayConnection(this, remoteId)
+
+    return Promise.resolve(this._connections[remoteId])
+  }
+
+  // The connection of an incoming transfer, the app is told about it like about the peer connections
+  _connection(remoteId) {
+    if(!this._connections[remoteId]) {
+      this._connections[remoteId] = new RelayConnection(this, remoteId)
+      this.onFileConnection({remoteId, connection: this._connections[remoteId]})
+    }
+
+    return this._connections[remoteId]
+  }
+
+  _handleEvent(data) {
+    switch(data.event) {
+      case 'opened': {
+        let transfer = this._opening.shift()
+        if(transfer) transfer._opened(data.relayId)
+        break
+      }
+      case 'request': {
+        let transfer = new IncomingTransfer(this, this._connection(data.remoteId), data.relayId, data.size)
+        this._transfers[data.relayId] = transfer
+
+        for(let signal of this._early[data.relayId] || []) transfer._handleSignal(signal)
+        delete this._early[data.relayId]
+        break
+      }
+      default: {
+        let transfer = this._transfers[data.relayId]
+        if(transfer) transfer._handleEvent(data)
+      }
+    }
+  }
+
+  _handleSignal(data) {
+    let payload = JSON.parse(data.peerData)
+    let signal = {remoteDevice: data.remoteDevice, ...payload}
+
+    if(this._transfers[payload.relayId]) return this._transfers[payload.relayId]._handleSignal(signal)
+
+    // The signal might overtake the request, which is sent by another instance
+    this._early[payload.relayId] = [...(this._early[payload.relayId] || []), signal]
+    setTimeout(() => delete this._early[payload.relayId], 60 * 1000)
+  }
+
+  // The errors of the relays have no id, the opening of a relay is the one waiting for an answer
+  _handleError(message) {
+    let transfer = this._opening.shift()
+
+    if(transfer) transfer._fail(message)
+    else this.onError(message)
+  }
+
+  _open(transfer, remoteId, size) {
+    this._opening.push(transfer)
+    this._send({action: 'open', remoteId, size})
+  }
+
+  _remove(relayId) {
+    delete this._transfers[relayId]
+  }
+
+  _signal(remoteId, remoteDevice, payload) {
+    this._socket.send(JSON.stringify({
+      msgType: 'signal',
+      action: 'signal',
+      type: 'relay',
+      remoteId,
+      remoteDevice,
+      peerData: JSON.stringify(payload),
+    }))
+  }
+
+  _send(data) {
+    this._socket.send(JSON.stringify({msgType: 'relay', ...data}))
+  }
+
+  // TO IMPLEMENT BY DEVELOPER
+  onFileConnection() { console.warn('You have to implement the "onFileConnection" function yourself!') }
+  onError() { console.warn('You have to implement the "onError" function yourself!') }
+}
+
+// The relayed transfers with a friend, it has the interface of the file connections of the peer client
+class RelayConnection {
+  constructor(client, remoteId) {
+    this._client = client
+    this._transfers = new Set()
+
+    this.remoteId = remoteId
+    this.isSending = false
+    this.isReceiving = false
+    this.recvProperties = {mode: 'emit'}
+  }
+
+  async send(file, callback) {
+    if(this.isSending) throw 'ERROR: Sending in progress'
+    this.isSending = true
+
+    try {
+      await new OutgoingTransfer(this._client, this, file, callback).start()
+    }
+    finally {
+      this.isSending = false
+    }
+  }
+
+  close() {
+    for(let transfer of this._transfers) transfer._close()
+    delete this._client._connections[this.remoteId]
+  }
+
+  // TO IMPLEMENT BY DEVELOPER
+  onDecline() { console.warn('You have to implement the "onDecline" function yourself!') }
+  onRequest() { console.warn('You have to implement the "onRequest" function yourself!') }
+  onReceive() { console.warn('You have to implement the "onReceive" function yourself!') }
+  onClose() { console.warn('You have to implement the "onClose" function yourself!') }
+}
+
+class OutgoingTransfer {
+  constructor(client, connection, file, percentCallback) {
+    this._client = client
+    this._connection = connection
+    this._file = file
+    this._percentCallback = percentCallback
+
+    this.relayId = null
+    this._chunkCount = Math.ceil(file.size / CHUNK_SIZE)
+    this._chunkIndex = 0
+    this._acked = 0
+    this._inFlight = 0
+    this._accepted = false
+    // The chunks are encrypted one after the other, so they are sent in order
+    this._sending = Promise.resolve()
+  }
+
+  async start() {
+    this._keyPair = await crypto.subtle.generateKey(ECDH, false, ['deriveKey'])
+    this._fileKey = await crypto.subtle.generateKey(AES_GCM, true, ['encrypt'])
+
+    return new Promise((resolve, reject) => {
+      this._resolve = resolve
+      this._reject = reject
+
+      this._connection._transfers.add(this)
+      this._client._open(this, this._connection.remoteId, this._file.size + this._chunkCount * CHUNK_OVERHEAD)
+    })
+  }
+
+  async _opened(relayId) {
+    this.relayId = relayId
+    this._client._transfers[relayId] = this
+
+    let publicKey = await exportPublicKey(this._keyPair)
+    this._client._signal(this._connection.remoteId, null, {relayId, publicKey})
+  }
+
+  // Every device of the receiver answers with its public key, and gets the details of the file
+  async _handleSignal({remoteDevice, publicKey}) {
+    if(!publicKey) return
+
+    let sharedKey = await deriveSharedKey(this._keyPair, publicKey)
+    let details = JSON.stringify({
+      fileKey: toBase64(await crypto.subtle.exportKey('raw', this._fileKey)),
+      name: this._file.name,
+      size: this._file.size,
+      type: this._file.type,
+      chunkCount: this._chunkCount,
+    })
+
+    details = await encrypt(sharedKey, new TextEncoder().encode(details), this._context('details'))
+    this._client._signal(this._connection.remoteId, remoteDevice, {relayId: this.relayId, details})
+  }
+
+  _handleEvent(data) {
+    switch(data.event) {
+      case 'accepted': {
+        this._accepted = true
+        this._queueChunks()
+        break
+      }
+      case 'ack': {
+        this._acked++
+        this._inFlight--
+        this._percentCallback(this._acked / this._chunkCount)
+        this._queueChunks()
+        break
+      }
+      case 'closed': {
+        this._finish()
+
+        if(this._accepted) this._reject('The transfer was closed')
+        else {
+          this._connection.onDecline()
+          this._resolve()
+        }
+        break
+      }
+    }
+  }
+
+  _queueChunks() {
+    this._sending = this._sending
+      .then(() => this._sendChunks())
+      .catch(error => {
+        this._close()
+        this._reject(error)
+      })
+  }
+
+  async _sendChunks() {
+    if(this._acked == this._chunkCount) {
+      this._close()
+      this._percentCallback(1)
+      return this._resolve()
+    }
+
+    while(this._inFlight < WINDOW && this._chunkIndex < this._chunkCount) {
+      let index = this._chunkIndex++
+      this._inFlight++
+
+      let start = index * CHUNK_SIZE
+      let chunk = await this._file.slice(start, start + CHUNK_SIZE).arrayBuffer()
+      let data = await encrypt(this._fileKey, chunk, this._context(index))
+
+      this._client._send({action: 'chunk', relayId: this.relayId, data})
+    }
+  }
+
+  // Binds the encrypted data to the relay and its place in it
+  _context(part) {
+    return new TextEncoder().encode(`${this.relayId}:${part}`)
+  }
+
+  _fail(error) {
+    this._finish()
+    this._reject(error)
+  }
+
+  _close() {
+    if(this.relayId) this._client._send({action: 'close', relayId: this.relayId})
+    this._finish()
+  }
+
+  _finish() {
+    this._client._remove(this.relayId)
+    this._connection._transfers.delete(this)
+  }
+}
+
+class IncomingTransfer {
+  constructor(client, connection, relayId, size) {
+    this._client = client
+    this._connection = connection
+
+    this.relayId = relayId
+    this._size = size
+    this._senderDevice = null
+    this._details = null
+    this._accepted = false
+    this._chunkIndex = 0
+    this._buffer = []
+    // The chunks are decrypted one after the other, the order is part of their encryption
+    this._receiving = Promise.resolve()
+
+    this._connection._transfers.add(this)
+  }
+
+  async _handleSignal({remoteDevice, publicKey, details}) {
+    if(publicKey && !this._senderDevice) {
+      this._senderDevice = remoteDevice
+      this._keyPair = await crypto.subtle.generateKey(ECDH, false, ['deriveKey'])
+      this._sharedKey = await deriveSharedKey(this._keyPair, publicKey)
+
+      let ownKey = await exportPublicKey(this._keyPair)
+      this._client._signal(this._connection.remoteId, remoteDevice, {relayId: this.relayId, publicKey: ownKey})
+    }
+    else if(details && this._sharedKey && !this._details && remoteDevice == this._senderDevice) {
+      this._handleDetails(details).catch(error => {
+        console.error('[RelayClient] - Invalid details of the transfer', error)
+        this._close()
+      })
+    }
+  }
+
+  async _handleDetails(details) {
+    details = JSON.parse(new TextDecoder().decode(await decrypt(this._sharedKey, details, this._context('details'))))
+
+    // The quota was taken for the declared size, which has to match the file shown to the user
+    if(details.size + details.chunkCount * CHUNK_OVERHEAD != this._size || details.chunkCount != Math.ceil(details.size / CHUNK_SIZE))
+      throw 'The size of the file does not match the relay'
+
+    this._details = details
+    this._fileKey = await crypto.subtle.importKey('raw', fromBase64(details.fileKey), AES_GCM, false, ['decrypt'])
+
+    const request = new FileSendRequest({name: details.name, size: details.size, type: details.type})
+    request._answered()
+      .then(({accepted, callback}) => {
+        if(!accepted) return this._close()
+
+        this._accepted = true
+        this._percentCallback = callback
+        this._mode = this._connection.recvProperties.mode
+        this._connection.isReceiving = true
+        this._client._send({action: 'accept', relayId: this.relayId})
+
+        if(this._details.chunkCount == 0) this._complete()
+      })
+
+    this._connection.onRequest(request)
+  }
+
+  _handleEvent(data) {
+    switch(data.event) {
+      case 'chunk': {
+        this._receiving = this._receiving
+          .then(() => this._handleChunk(data.data))
+          .catch(error => {
+            console.error('[RelayClient] - Invalid chunk', error)
+            this._close()
+          })
+        break
+      }
+      case 'closed': {
+        if(this._accepted && this._chunkIndex < this._details.chunkCount) console.warn('[RelayClient] - The transfer was closed before it finished')
+        this._finish()
+        break
+      }
+    }
+  }
+
+  async _handleChunk(data) {
+    if(!this._accepted) return
+
+    let chunk = new Uint8Array(await decrypt(this._fileKey, data, this._context(this._chunkIndex)))
+    this._client._send({action: 'ack', relayId: this.relayId})
+
+    ++this._chunkIndex
+    if(this._chunkIndex % 10 == 0) this._percentCallback(this._chunkIndex / this._details.chunkCount)
+
+    if(this._mode == 'accumulate') this._buffer.push(chunk)
+    else this._connection.onReceive(chunk)
+
+    if(this._chunkIndex == this._details.chunkCount) this._complete()
+  }
+
+  _complete() {
+    if(this._mode == 'accumulate') this._connection.onReceive(new File(this._buffer, this._details.name, {type: this._details.type}))
+    else this._connection.onReceive(new Uint8Array())
+
+    this._percentCallback(1)
+    this._finish()
+  }
+
+  _context(part) {
+    return new TextEncoder().encode(`${this.relayId}:${part}`)
+  }
+
+  _close() {
+    this._client._send({action: 'close', relayId: this.relayId})
+    this._finish()
+  }
+
+  _finish() {
+    this._client._remove(this.relayId)
+    this._connection._transfers.delete(this)
+    this._connection.isReceiving = false
+    this._buffer = []
+  }
+}
+
+async function exportPublicKey(keyPair) {
+  return toBase64(await crypto.subtle.exportKey('raw', keyPair.publicKey))
+}
+
+async function deriveSharedKey(keyPair, publicKey) {
+  let remoteKey = await crypto.subtle.importKey('raw', fromBase64(publicKey), ECDH, false, [])
+  return crypto.subtle.deriveKey({name: 'ECDH', public: remoteKey}, keyPair.privateKey, AES_GCM, false, ['encrypt', 'decrypt'])
+}
+
+// The IV is sent in front of the encrypted data
+async function encrypt(key, data, additionalData) {
+  let iv = crypto.getRandomValues(new Uint8Array(IV_LENGTH))
+  let encrypted = new Uint8Array(await crypto.subtle.encrypt({name: 'AES-GCM', iv, additionalData}, key, data))
+
+  let message = new Uint8Array(IV_LENGTH + encrypted.length)
+  message.set(iv)
+  message.set(encrypted, IV_LENGTH)
+
+  return toBase64(message)
+}
+
+function decrypt(key, data, additionalData) {
+  let message = fromBase64(data)
+  return crypto.subtle.decrypt({name: 'AES-GCM', iv: message.subarray(0, IV_LENGTH), additionalData}, key, message.subarray(IV_LENGTH))
+}
+
+function toBase64(buffer) {
+  let bytes = new Uint8Array(buffer)
+  let binary = ''
+
+  for(let start = 0; start < bytes.length; start += 0x8000)
+    binary += String.fromCharCode(...bytes.subarray(start, start + 0x8000))
+
+  return btoa(binary)
+}
+
+function fromBase64(text) {
+  return Uint8Array.from(atob(text), char => char.charCodeAt(0))
+}
@@ -0,0 +1,150 @@
+const Peer = require('simple-peer')
+
+// A class that handles the group calls (rooms). The members are connected to each other
+// directly, the server only relays their signals and sends the roster of the room.
+export default class RoomClient {
+  constructor() {
+    this._peers       = {}
+    this._iceServers  = null
+    this._device      = null
+
+    this.userId       = null
+    this.room         = null
+    this.stream       = null
+  }
+
+  init(socket) {
+    this._socket = socket
+
+    this._socket.addEventListener('message', event => {
+      let data = JSON.parse(event.data)
+
+      if(data.msgType == 'welcome') this._device = data.device
+      else if(data.msgType == 'room') this._handleEvent(data)
+      else if(data.msgType == 'error' && data.code == 'room') this.onError(data.message)
+    })
+  }
+
+  // The members of the roster are matched against the logged in user
+  setUserId(userId) {
+    this.userId = userId
+  }
+
+  // The STUN and TURN servers from the backend, the defaults of Simple-Peer are used until they are set
+  setIceServers(iceServers) {
+    this._iceServers = iceServers
+  }
+
+  create(stream) {
+    this.stream = stream
+    this._send({action: 'create'})
+  }
+
+  join(roomId, stream) {
+    this.stream = stream
+    this._send({action: 'join', roomId})
+  }
+
+  invite(userId) {
+    this._send({action: 'invite', roomId: this.room.id, userId})
+  }
+
+  kick(userId) {
+    this._send({action: 'kick', roomId: this.room.id, userId})
+  }
+
+  lock(locked) {
+    this._send({action: 'lock', roomId: this.room.id, locked})
+  }
+
+  leave() {
+    if(this.room) this._send({action: 'leave', roomId: this.room.id})
+    this._reset()
+  }
+
+  _handleEvent(data) {
+    switch(data.event) {
+      case 'invite': this.onInvite({roomId: data.roomId, hostId: data.hostId}); break;
+      case 'roster': this._handleRoster(data); break;
+      case 'signal': this._handleSignal(data); break;
+      case 'kicked': {
+        this._reset()
+        this.onKicked()
+        break
+      }
+    }
+  }
+
+  // A newly joined member connects to each of the members listed before them
+  _handleRoster(data) {
+    let ownIndex = data.members.findIndex(member => member.userId == this.userId && member.device == this._device)
+    if(ownIndex == -1) return
+
+    this.room = {id: data.roomId, hostId: data.hostId, members: data.members, locked: data.locked}
+
+    for(let userId in this._peers) {
+      if(!data.members.some(member => member.userId == userId)) this._removePeer(userId)
+    }
+
+    data.members.slice(0, ownIndex)
+      .filter(member => !this._peers[member.userId])
+      .forEach(member => this._createPeer(member.userId, true))
+
+    this.onRoster(this.room)
+  }
+
+  _handleSignal(data) {
+    if(!this.room || data.roomId != this.room.id) return
+
+    if(!this._peers[data.remoteId]) this._createPeer(data.remoteId, false)
+    this._peers[data.remoteId].signal(JSON.parse(data.peerData))
+  }
+
+  _createPeer(remoteId, initiator) {
+    let config = this._iceServers ? {iceServers: this._iceServers} : undefined
+    let peer = new Peer({initiator, trickle: true, config, stream: this.stream})
+
+    peer.on('signal', peerData => {
+      this._send({
+        action: 'signal',
+        roomId: this.room.id,
+        remoteId,
+        peerData: JSON.stringify(peerData),
+      })
+    })
+    peer.on('stream', stream => this.onStream(remoteId, stream))
+    peer.on('error', error => console.error("SimplePeer ERROR", error))
+    peer.on('close', () => {
+      if(this._peers[remoteId] == peer) this._removePeer(remoteId)
+    })
+
+    this._peers[remoteId] = peer
+  }
+
+  _removePeer(remoteId) {
+    let peer = this._peers[remoteId]
+    delete this._peers[remoteId]
+
+    peer.destroy()
+    this.onStreamEnd(remoteId)
+  }
+
+  _reset() {
+    for(let remoteId in this._peers) this._removePeer(remoteId)
+
+    this.room = null
+    this.stream = null
+  }
+
+  _send(data) {
+    this._socket.send(JSON.stringify({msgType: 'room', ...data}))
+  }
+
+  // TO IMPLEMENT BY DEVELOPER
+  onInvite() { console.warn('You have to implement the "onInvite" function yourself!') }
+  onRoster() { console.warn('You have to implement the "onRoster" function yourself!') }
+  onStream() { console.warn('You have to implement the "onStream" function yourself!') }
+  onStreamEnd() { console.warn('You have to implement the "onStreamEnd" function yourself!') }
+  onKicked() { console.warn('You have to implement the "onKicked" function yourself!') }
+  onError() { console.warn('You have to implement the "onError" function yourself!') }
+}
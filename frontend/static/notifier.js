@@ -6,6 +6,7 @@ self.addEventListener('push', event => {
       icon: '/icon.png',
       vibrate: [200, 100, 200],
       body: data.body,
+      data: {url: data.url},
     })
   )
 })
@@ -14,6 +15,7 @@ self.addEventListener('notificationclick', event => {
   event.notification.close()
 
   const urlToOpen = new URL(self.location.origin).href
+  const path = event.notification.data && event.notification.data.url
 
   const promiseChain = clients.matchAll({
     type: 'window',
@@ -22,6 +24,14 @@ self.addEventListener('notificationclick', event => {
     .then( windowClients => {
       let matchingClient = windowClients.find( client => client.url == urlToOpen )
 
+      // Notifications with a path (eg. missed calls) open that part of the app
+      if(path) {
+        let target = new URL(path, urlToOpen).href
+
+        if(matchingClient) return matchingClient.navigate(target).then( client => client.focus() )
+        else return clients.openWindow(target)
+      }
+
       if(matchingClient) return matchingClient.focus()
       else return clients.openWindow(urlToOpen)
     })
@@ -90,6 +90,18 @@ export const actions = {
       ctx.dispatch('reset')
       alertBox('Declined call!', `${ctx.rootState.friends[remoteId].username} declined your call!`)
     }
+    connection.onUnanswered = reason => {
+      ctx.dispatch('resetCall', {remoteId}, {root: true})
+      ctx.dispatch('reset')
+
+      let username = ctx.rootState.friends[remoteId].username
+      switch(reason) {
+        case 'busy': alertBox('Busy!', `${username} is in another call`); break;
+        case 'offline': alertBox('Offline!', `${username} is not online, they will see that you called`); break;
+        default: alertBox('No answer!', `${username} did not answer your call`)
+      }
+    }
+    connection.onAnswered = () => ctx.commit('popUp/set', {popUp: 'call', value: null}, {root: true})
     connection.onTrack = (track, stream) => ctx.commit('setRemoteStream', {remoteId, stream}, {root: true})
     connection.onEnd = () => {
       if(ctx.rootState.popUp.call) {
@@ -12,6 +12,7 @@ export const state = () => ({
 
   user: null, // logged-in user's data
   client: null, // PeerClient instance (plugins/peerclient.js)
+  relayClient: null, // RelayClient instance (plugins/relayclient.js)
   pusher: null, // Pusher instance (plugins/pusher.js)
   partnerId: null, // id of the currently open partner
   partners: {}, // friends who we are/were connected to in the session, holds data neccecary for communication (see the 'checkPartnerState' mutation)
@@ -65,6 +66,9 @@ export const mutations = {
   setClient(state, client) {
     state.client = client
   },
+  setRelayClient(state, relayClient) {
+    state.relayClient = relayClient
+  },
   setPusher(state, pusher) {
     state.pusher = pusher
   },
@@ -171,6 +175,7 @@ export const mutations = {
   reset(state) {
     state.user = null
     state.client = null
+    state.relayClient = null
     state.pusher = null
     state.partnerId = null
     state.partners = {}
@@ -204,12 +209,16 @@ export const mutations = {
   },
   setOnlines(state, onlines) {
     for(let id in state.friends) {
-      if(onlines.includes(id))
-        state.friends[id].online = true
-      else
-        state.friends[id].online = false
+      let presence = onlines.find(online => online._id == id)
+
+      state.friends[id].online = !!presence
+      if(presence) state.friends[id].presence = {status: presence.status, text: presence.text}
     }
   },
+  setPresence(state, {_id, status, text}) {
+    if(state.friends[_id])
+      state.friends[_id].presence = {status, text}
+  },
   setOnline(state, {remoteId, online}) {
     state.friends[remoteId].online = online
   },
@@ -222,16 +231,28 @@ export const mutations = {
   removeRequest(state, index = 0) {
     state.requests.splice(index, 1)
   },
+  // The sender withdrew the request
+  cancelRequest(state, remoteId) {
+    state.requests = state.requests.filter(request => request._id != remoteId)
+  },
   setFriends(state, friends) {
     let friendsObj = {}
 
     for(let friend of friends)
-      friendsObj[friend._id] = {...friend, online: false}
+      friendsObj[friend._id] = {...friend, online: false, presence: null}
 
     state.friends = friendsObj
   },
   addFriend(state, friend) {
-    this._vm.$set(state.friends, friend._id, {...friend, online: false})
+    this._vm.$set(state.friends, friend._id, {...friend, online: false, presence: null})
+  },
+  // Guests are shown like friends, while they are connected through our guest link
+  addGuest(state, guest) {
+    this._vm.$set(state.friends, guest._id, {...guest, avatar: 'avatar.jpg', guest: true, online: true, presence: null})
+  },
+  removeFriend(state, remoteId) {
+    this._vm.$delete(state.friends, remoteId)
+    this._vm.$delete(state.partners, remoteId)
   },
   setPartnerId(state, partnerId) {
     state.partnerId = partnerId
@@ -380,10 +401,23 @@ export const actions = {
       ctx.dispatch('call/setCallConnectionListeners', {remoteId, connection})
     }
   },
+  setRelayClient(ctx, relayClient) {
+    ctx.commit('setRelayClient', relayClient)
+
+    relayClient.onFileConnection = ({remoteId, connection}) => {
+      setFileConnectionListeners(ctx, remoteId, connection)
+
+      ctx.commit('checkPartnerState', remoteId)
+      ctx.commit('addConnection', {remoteId, type: 'file', connection})
+    }
+
+    relayClient.onError = message => errorBox('Error!', message)
+  },
   setPusher(ctx, pusher) {
     ctx.commit('setPusher', pusher)
 
     pusher.subscribe( 'request', request => ctx.commit('addRequest', request) )
+    pusher.subscribe( 'cancelRequest', ({_id}) => ctx.commit('cancelRequest', _id) )
     pusher.subscribe( 'login', remoteId => ctx.commit('setOnline', {remoteId, online: true}) )
     pusher.subscribe( 'logout', remoteId => {
       if(ctx.state.partners[remoteId])
@@ -394,6 +428,10 @@ export const actions = {
 
       ctx.commit('setOnline', {remoteId, online: false})
     })
+    pusher.subscribe( 'presence', presence => ctx.commit('setPresence', presence) )
+    pusher.subscribe( 'guestLogin', guest => ctx.commit('addGuest', guest) )
+    pusher.subscribe( 'guestLogout', guestId => ctx.dispatch('removeFriend', guestId) )
+    pusher.subscribe( 'unfriend', ({_id}) => ctx.dispatch('removeFriend', _id) )
     pusher.subscribe( 'friend', async friend => {
       ctx.commit('addFriend', friend)
 
@@ -448,7 +486,12 @@ export const actions = {
       }
 
       ctx.commit('setConnecting', {type: 'file', value: remoteId})
+      // The files go through the server, when the peers can not connect directly
       ctx.state.client.createFileConnection(remoteId)
+        .catch( err => {
+          console.error(err)
+          return ctx.state.relayClient.createFileConnection(remoteId)
+        })
         .then( connection => {
           setFileConnectionListeners(ctx, remoteId, connection)
           ctx.commit('addConnection', {remoteId, type: 'file', connection})
@@ -488,6 +531,20 @@ export const actions = {
     ctx.commit('closeConnection', {remoteId, type: 'file'})
     ctx.dispatch('resetCall', {remoteId, full: true})
   },
+  removeFriend(ctx, remoteId) {
+    if(!ctx.state.friends[remoteId]) return
+
+    if(ctx.state.partners[remoteId])
+      ctx.dispatch('resetPartner', remoteId)
+
+    if(ctx.state.popUp.call && ctx.state.popUp.call.caller._id == remoteId)
+      ctx.dispatch('popUp/set', {popUp: 'call', value: null})
+
+    if(ctx.state.partnerId == remoteId)
+      ctx.commit('setPartnerId', null)
+
+    ctx.commit('removeFriend', remoteId)
+  },
   resetCall(ctx, {remoteId, full = false}) {
     ctx.dispatch('call/disableVideo', remoteId)
     ctx.dispatch('call/stopScreenCapture', remoteId)
@@ -681,13 +738,19 @@ export const actions = {
       ctx.state.pusher.unsubscribe('login')
       ctx.state.pusher.unsubscribe('friend')
       ctx.state.pusher.unsubscribe('logout')
+      ctx.state.pusher.unsubscribe('presence')
       ctx.state.pusher.unsubscribe('request')
+      ctx.state.pusher.unsubscribe('cancelRequest')
+      ctx.state.pusher.unsubscribe('guestLogin')
+      ctx.state.pusher.unsubscribe('guestLogout')
+      ctx.state.pusher.unsubscribe('unfriend')
 
       ctx.state.pusher.destroy()
     }
 
     ctx.dispatch('reset')
     ctx.commit('call/reset')
+    ctx.dispatch('room/reset')
     ctx.commit('popUp/reset')
 
     this.$axios.post(`/logout`, null, {withCredentials: true})
@@ -6,6 +6,8 @@ export const state = () => ({
   imageCropper: false,
   call: false,
   callSettings: false,
+  room: false,
+  roomInvite: false,
   ping: false,
   file: false,
   notification: false,
@@ -23,6 +25,8 @@ export const mutations = {
     state.imageCropper = false
     state.call = false
     state.callSettings = false
+    state.room = false
+    state.roomInvite = false
     state.ping = false
     state.file = false
     state.notification = false
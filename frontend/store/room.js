@@ -0,0 +1,127 @@
+export const strict = false
+
+export const state = () => ({
+  client: null, // RoomClient instance (plugins/roomclient.js)
+  room: null, // the group call we are in: {id, hostId, members, locked}
+  streams: {}, // the audio of the other members by their ids
+  localStream: null,
+  shownErrors: [],
+})
+
+export const getters = {
+  isHost(state, getters, rootState) {
+    return !!state.room && state.room.hostId == rootState.user._id
+  },
+}
+
+export const mutations = {
+  reset(state) {
+    state.room = null
+    state.streams = {}
+    state.localStream = null
+    state.shownErrors = []
+  },
+  setClient(state, client) {
+    state.client = client
+  },
+  setRoom(state, room) {
+    state.room = room
+  },
+  setLocalStream(state, stream) {
+    state.localStream = stream
+  },
+  addStream(state, {userId, stream}) {
+    this._vm.$set(state.streams, userId, stream)
+  },
+  removeStream(state, userId) {
+    this._vm.$delete(state.streams, userId)
+  },
+  addShownError(state, message) {
+    state.shownErrors.push(message)
+  },
+}
+
+export const actions = {
+  setClient(ctx, client) {
+    ctx.commit('setClient', client)
+
+    client.onInvite = ({roomId, hostId}) => {
+      let host = ctx.rootState.friends[hostId]
+      if(!host) return
+
+      ctx.commit('popUp/set', {popUp: 'roomInvite', value: {roomId, host}}, {root: true})
+    }
+    client.onRoster = room => ctx.commit('setRoom', {...room})
+    client.onStream = (userId, stream) => ctx.commit('addStream', {userId, stream})
+    client.onStreamEnd = userId => ctx.commit('removeStream', userId)
+    client.onKicked = () => {
+      ctx.dispatch('reset')
+      alertBox('Removed from the group call!', 'The host removed you from the call')
+    }
+    client.onError = message => {
+      // Creating or joining the room failed
+      if(!ctx.state.room) {
+        ctx.dispatch('reset')
+        return errorBox('Group call failed!', message)
+      }
+
+      // Every signal sent to a blocked member fails, the error is only shown once
+      if(ctx.state.shownErrors.includes(message)) return
+      ctx.commit('addShownError', message)
+      errorBox('Group call', message)
+    }
+  },
+  create(ctx) {
+    if(ctx.state.room || ctx.rootGetters.call.isInCall) return
+
+    navigator.mediaDevices.getUserMedia({audio: true, video: false})
+      .then( stream => {
+        ctx.commit('setLocalStream', stream)
+        ctx.state.client.create(stream)
+        ctx.commit('popUp/set', {popUp: 'room', value: true}, {root: true})
+      })
+      .catch( err => {
+        console.error(err)
+        errorBox('Uh-oh!', 'The microphone can not be used')
+      })
+  },
+  join(ctx) {
+    let {roomId} = ctx.rootState.popUp.roomInvite
+    ctx.commit('popUp/set', {popUp: 'roomInvite', value: null}, {root: true})
+
+    if(ctx.state.room) ctx.dispatch('leave')
+
+    navigator.mediaDevices.getUserMedia({audio: true, video: false})
+      .then( stream => {
+        ctx.commit('setLocalStream', stream)
+        ctx.state.client.join(roomId, stream)
+      })
+      .catch( err => {
+        console.error(err)
+        errorBox('Uh-oh!', 'The microphone can not be used')
+      })
+  },
+  declineInvite(ctx) {
+    ctx.commit('popUp/set', {popUp: 'roomInvite', value: null}, {root: true})
+  },
+  invite(ctx, userId) {
+    ctx.state.client.invite(userId)
+  },
+  kick(ctx, userId) {
+    ctx.state.client.kick(userId)
+  },
+  lock(ctx, locked) {
+    ctx.state.client.lock(locked)
+  },
+  leave(ctx) {
+    ctx.state.client.leave()
+    ctx.dispatch('reset')
+  },
+  reset(ctx) {
+    if(ctx.state.localStream)
+      ctx.state.localStream.getTracks().forEach(track => track.stop())
+
+    ctx.commit('reset')
+    ctx.commit('popUp/set', {popUp: 'room', value: false}, {root: true})
+  },
+}